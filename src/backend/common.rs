@@ -38,6 +38,15 @@ pub enum PciEnumerationError {
     NotFound,
     PermissionDenied,
     ParseInt(ParseIntError),
+    InvalidRom,
+    InvalidVpd,
+    InvalidSmbios,
+    InvalidExpression(String),
+    NoIommuGroup,
+    DriverBindFailed(String),
+    NoSlotPowerControl(String),
+    Toml(toml::de::Error),
+    Json(serde_json::Error),
 }
 
 // Convert IO errors to PCI enumeration errors.
@@ -58,8 +67,22 @@ impl From<ParseIntError> for PciEnumerationError {
     }
 }
 
+// Convert TOML parsing error into PCI enumeration error.
+impl From<toml::de::Error> for PciEnumerationError {
+    fn from(err: toml::de::Error) -> Self {
+        PciEnumerationError::Toml(err)
+    }
+}
+
+// Convert JSON parsing error into PCI enumeration error.
+impl From<serde_json::Error> for PciEnumerationError {
+    fn from(err: serde_json::Error) -> Self {
+        PciEnumerationError::Json(err)
+    }
+}
+
 // Define a PCI device as its component fields
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PciDevice {
     pub domain: u32,
     pub bus: u8,
@@ -76,33 +99,33 @@ pub struct PciDevice {
     pub revision_id: u8,
 }
 
+impl PciDevice {
+    // Format the device's address the way Linux and lspci do, e.g. 0000:00:1c.0
+    pub fn address(&self) -> String {
+        format!("{:04x}:{:02x}:{:02x}.{:x}", self.domain, self.bus, self.device, self.function)
+    }
+
+    // Class, subclass and programming interface packed into 24 bits, e.g. 0x030000 for VGA.
+    pub fn class_code(&self) -> u32 {
+        (self.class as u32) << 16 | (self.subclass as u32) << 8 | self.programming_interface as u32
+    }
+}
+
 
 
 // ############################## Begin hex helper functions ##############################
 pub(crate) fn ox_hex_string_to_u8(input_string: &str) -> Result<u8, ParseIntError> {
-    let input_string = if input_string.starts_with("0x") {
-        &input_string[2..]
-    } else {
-        input_string
-    }.trim();
+    let input_string = input_string.strip_prefix("0x").unwrap_or(input_string).trim();
     u8::from_str_radix(input_string, 16)
 }
 
 pub(crate) fn ox_hex_string_to_u16(input_string: &str) -> Result<u16, ParseIntError> {
-    let input_string = if input_string.starts_with("0x") {
-        &input_string[2..]
-    } else {
-        input_string
-    }.trim();
+    let input_string = input_string.strip_prefix("0x").unwrap_or(input_string).trim();
     u16::from_str_radix(input_string, 16)
 }
 
 pub(crate) fn ox_hex_string_to_u32(input_string: &str) -> Result<u32, ParseIntError> {
-    let input_string = if input_string.starts_with("0x") {
-        &input_string[2..]
-    } else {
-        input_string
-    }.trim();
+    let input_string = input_string.strip_prefix("0x").unwrap_or(input_string).trim();
     u32::from_str_radix(input_string, 16)
 }
 // ############################## End hex helper functions ##############################
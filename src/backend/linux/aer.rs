@@ -0,0 +1,357 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Advanced Error Reporting counters kept by Linux in sysfs.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::backend::common::PciEnumerationError;
+use crate::backend::linux::sysfs::{Sysfs, SysfsDevice};
+use crate::pci::health::{check_health, ErrorCounts, HealthReport, HealthThresholds};
+use crate::pci::topology::PciTopology;
+
+// The correctable error names used in aer_dev_correctable.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CorrectableError {
+    ReceiverError,
+    BadTlp,
+    BadDllp,
+    ReplayRollover,
+    ReplayTimeout,
+    AdvisoryNonFatal,
+    CorrectedInternal,
+    HeaderLogOverflow,
+    Other(String),
+}
+
+impl From<&str> for CorrectableError {
+    fn from(name: &str) -> Self {
+        match name {
+            "RxErr" => CorrectableError::ReceiverError,
+            "BadTLP" => CorrectableError::BadTlp,
+            "BadDLLP" => CorrectableError::BadDllp,
+            "Rollover" => CorrectableError::ReplayRollover,
+            "Timeout" => CorrectableError::ReplayTimeout,
+            "NonFatalErr" => CorrectableError::AdvisoryNonFatal,
+            "CorrIntErr" => CorrectableError::CorrectedInternal,
+            "HeaderOF" => CorrectableError::HeaderLogOverflow,
+            other => CorrectableError::Other(other.to_string()),
+        }
+    }
+}
+
+// The uncorrectable error names used in aer_dev_fatal and aer_dev_nonfatal.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum UncorrectableError {
+    Undefined,
+    DataLinkProtocol,
+    SurpriseDown,
+    PoisonedTlp,
+    FlowControlProtocol,
+    CompletionTimeout,
+    CompleterAbort,
+    UnexpectedCompletion,
+    ReceiverOverflow,
+    MalformedTlp,
+    Ecrc,
+    UnsupportedRequest,
+    AcsViolation,
+    UncorrectableInternal,
+    MulticastBlockedTlp,
+    AtomicOpBlocked,
+    TlpPrefixBlocked,
+    PoisonedTlpBlocked,
+    Other(String),
+}
+
+impl From<&str> for UncorrectableError {
+    fn from(name: &str) -> Self {
+        match name {
+            "Undefined" => UncorrectableError::Undefined,
+            "DLP" => UncorrectableError::DataLinkProtocol,
+            "SDES" => UncorrectableError::SurpriseDown,
+            "TLP" => UncorrectableError::PoisonedTlp,
+            "FCP" => UncorrectableError::FlowControlProtocol,
+            "CmpltTO" => UncorrectableError::CompletionTimeout,
+            "CmpltAbrt" => UncorrectableError::CompleterAbort,
+            "UnxCmplt" => UncorrectableError::UnexpectedCompletion,
+            "RxOF" => UncorrectableError::ReceiverOverflow,
+            "MalfTLP" => UncorrectableError::MalformedTlp,
+            "ECRC" => UncorrectableError::Ecrc,
+            "UnsupReq" => UncorrectableError::UnsupportedRequest,
+            "ACSViol" => UncorrectableError::AcsViolation,
+            "UncorrIntErr" => UncorrectableError::UncorrectableInternal,
+            "BlockedTLP" => UncorrectableError::MulticastBlockedTlp,
+            "AtomicOpBlocked" => UncorrectableError::AtomicOpBlocked,
+            "TLPBlockedErr" => UncorrectableError::TlpPrefixBlocked,
+            "PoisonTLPBlocked" => UncorrectableError::PoisonedTlpBlocked,
+            other => UncorrectableError::Other(other.to_string()),
+        }
+    }
+}
+
+// Counters only go down when the device is removed or the machine reboots, so those count as
+// starting again from zero.
+fn since(now: u64, before: u64) -> u64 {
+    now.checked_sub(before).unwrap_or(now)
+}
+
+// Counts per error type plus the kernel's running total, which also counts errors it
+// couldn't attribute to a type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AerCounts<T: Ord> {
+    pub counts: BTreeMap<T, u64>,
+    pub total: u64,
+}
+
+impl<T: Ord> Default for AerCounts<T> {
+    fn default() -> Self {
+        AerCounts {
+            counts: BTreeMap::new(),
+            total: 0,
+        }
+    }
+}
+
+impl<T: Ord + Clone + for<'a> From<&'a str>> AerCounts<T> {
+    // Parse lines of "Name count", with the total on a line starting TOTAL_ERR_.
+    pub fn parse(contents: &str) -> Result<AerCounts<T>, PciEnumerationError> {
+        let mut counts = AerCounts::default();
+        for line in contents.lines() {
+            let (name, count) = match line.split_once(' ') {
+                Some((name, count)) => (name, count.trim().parse()?),
+                None => continue,
+            };
+            if name.starts_with("TOTAL_ERR_") {
+                counts.total = count;
+            } else {
+                counts.counts.insert(T::from(name), count);
+            }
+        }
+        Ok(counts)
+    }
+
+    pub fn delta(&self, earlier: &AerCounts<T>) -> AerCounts<T> {
+        let counts = self
+            .counts
+            .iter()
+            .map(|(error, count)| {
+                let before = earlier.counts.get(error).copied().unwrap_or(0);
+                (error.clone(), since(*count, before))
+            })
+            .collect();
+        AerCounts {
+            counts,
+            total: since(self.total, earlier.total),
+        }
+    }
+
+    pub fn get(&self, error: &T) -> u64 {
+        self.counts.get(error).copied().unwrap_or(0)
+    }
+}
+
+// Errors reported to a root port from anywhere below it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RootPortTotals {
+    pub correctable: u64,
+    pub fatal: u64,
+    pub nonfatal: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AerCounters {
+    pub correctable: AerCounts<CorrectableError>,
+    pub fatal: AerCounts<UncorrectableError>,
+    pub nonfatal: AerCounts<UncorrectableError>,
+    pub root_port: Option<RootPortTotals>,
+}
+
+impl AerCounters {
+    pub fn error_counts(&self) -> ErrorCounts {
+        ErrorCounts {
+            correctable: self.correctable.total,
+            nonfatal: self.nonfatal.total,
+            fatal: self.fatal.total,
+        }
+    }
+
+    pub fn delta(&self, earlier: &AerCounters) -> AerCounters {
+        let root_port = self.root_port.map(|now| {
+            let before = earlier.root_port.unwrap_or_default();
+            RootPortTotals {
+                correctable: since(now.correctable, before.correctable),
+                fatal: since(now.fatal, before.fatal),
+                nonfatal: since(now.nonfatal, before.nonfatal),
+            }
+        });
+
+        AerCounters {
+            correctable: self.correctable.delta(&earlier.correctable),
+            fatal: self.fatal.delta(&earlier.fatal),
+            nonfatal: self.nonfatal.delta(&earlier.nonfatal),
+            root_port,
+        }
+    }
+}
+
+// Counters along with when they were read, so deltas can be turned into rates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AerSample {
+    pub counters: AerCounters,
+    pub taken: Instant,
+}
+
+impl AerSample {
+    pub fn delta(&self, earlier: &AerSample) -> AerDelta {
+        AerDelta {
+            counters: self.counters.delta(&earlier.counters),
+            elapsed: self.taken.saturating_duration_since(earlier.taken),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AerDelta {
+    pub counters: AerCounters,
+    pub elapsed: Duration,
+}
+
+impl AerDelta {
+    pub fn correctable_per_hour(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            seconds if seconds > 0.0 => self.counters.correctable.total as f64 * 3600.0 / seconds,
+            _ => 0.0,
+        }
+    }
+}
+
+impl SysfsDevice {
+    // The aer_dev_* attributes only exist when the kernel handles AER for the device.
+    pub fn aer_counters(&self) -> Result<AerCounters, PciEnumerationError> {
+        let mut root_port = None;
+        if self.has_attribute("aer_rootport_total_err_cor") {
+            root_port = Some(RootPortTotals {
+                correctable: self.read_attribute("aer_rootport_total_err_cor")?.parse()?,
+                fatal: self.read_attribute("aer_rootport_total_err_fatal")?.parse()?,
+                nonfatal: self.read_attribute("aer_rootport_total_err_nonfatal")?.parse()?,
+            });
+        }
+
+        Ok(AerCounters {
+            correctable: AerCounts::parse(&self.read_attribute("aer_dev_correctable")?)?,
+            fatal: AerCounts::parse(&self.read_attribute("aer_dev_fatal")?)?,
+            nonfatal: AerCounts::parse(&self.read_attribute("aer_dev_nonfatal")?)?,
+            root_port,
+        })
+    }
+
+    pub fn aer_sample(&self) -> Result<AerSample, PciEnumerationError> {
+        Ok(AerSample {
+            counters: self.aer_counters()?,
+            taken: Instant::now(),
+        })
+    }
+}
+
+impl Sysfs {
+    // The config space health checks, plus each device's AER totals since boot checked
+    // against the thresholds. Devices without AER counters are skipped.
+    pub fn health_check(&self, topology: &PciTopology, thresholds: &HealthThresholds) -> HealthReport {
+        let mut report = check_health(topology);
+        for (id, node) in topology.nodes().iter().enumerate() {
+            if let Ok(counters) = self.device(&node.device).aer_counters() {
+                report.check_errors(id, &counters.error_counts(), thresholds);
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::backend::linux::aer::{AerSample, CorrectableError, UncorrectableError};
+    use crate::backend::linux::fixture::SysfsFixture;
+    use crate::pci::health::{HealthThresholds, Severity};
+
+    fn write_counters(fixture: &SysfsFixture, receiver_errors: u64, bad_tlps: u64) {
+        let correctable = format!(
+            "RxErr {}\nBadTLP {}\nBadDLLP 0\nRollover 0\nTimeout 0\nNonFatalErr 0\nCorrIntErr 0\nHeaderOF 0\nTOTAL_ERR_COR {}\n",
+            receiver_errors,
+            bad_tlps,
+            receiver_errors + bad_tlps
+        );
+        fixture.write("bus/pci/devices/0000:00:1c.0/aer_dev_correctable", correctable);
+        fixture.write("bus/pci/devices/0000:00:1c.0/aer_dev_fatal", "Undefined 0\nDLP 0\nSDES 1\nTOTAL_ERR_FATAL 1\n");
+        fixture.write("bus/pci/devices/0000:00:1c.0/aer_dev_nonfatal", "Undefined 0\nCmpltTO 0\nTOTAL_ERR_NONFATAL 0\n");
+        fixture.write("bus/pci/devices/0000:00:1c.0/aer_rootport_total_err_cor", format!("{}\n", receiver_errors + bad_tlps));
+        fixture.write("bus/pci/devices/0000:00:1c.0/aer_rootport_total_err_fatal", "1\n");
+        fixture.write("bus/pci/devices/0000:00:1c.0/aer_rootport_total_err_nonfatal", "0\n");
+    }
+
+    #[test]
+    fn test_aer_delta() {
+        let fixture = SysfsFixture::new();
+        fixture.add_device("pci0000:00/0000:00:1c.0", 0x8086, 0xa338, 0x060400);
+        let device = fixture.sysfs().device_by_address("0000:00:1c.0");
+
+        write_counters(&fixture, 10, 2);
+        let before = device.aer_sample().unwrap();
+        assert_eq!(before.counters.correctable.get(&CorrectableError::ReceiverError), 10);
+        assert_eq!(before.counters.fatal.get(&UncorrectableError::SurpriseDown), 1);
+        assert_eq!(before.counters.root_port.unwrap().correctable, 12);
+
+        write_counters(&fixture, 25, 2);
+        let after = AerSample {
+            taken: before.taken + Duration::from_secs(1800),
+            ..device.aer_sample().unwrap()
+        };
+
+        let delta = after.delta(&before);
+        assert_eq!(delta.counters.correctable.get(&CorrectableError::ReceiverError), 15);
+        assert_eq!(delta.counters.correctable.get(&CorrectableError::BadTlp), 0);
+        assert_eq!(delta.counters.fatal.total, 0);
+        assert_eq!(delta.counters.root_port.unwrap().correctable, 15);
+        assert_eq!(delta.correctable_per_hour(), 30.0);
+    }
+
+    #[test]
+    fn test_health_check_counts_aer() {
+        let fixture = SysfsFixture::new();
+        fixture.add_device("pci0000:00/0000:00:1c.0", 0x8086, 0xa338, 0x060400);
+        write_counters(&fixture, 10, 2);
+
+        // A fatal error is critical, and a dozen correctable ones since boot are worth a warning.
+        let topology = fixture.sysfs().topology().unwrap();
+        let report = fixture.sysfs().health_check(&topology, &HealthThresholds::default());
+        assert_eq!(report.findings.len(), 2);
+        assert_eq!(report.worst(), Some(Severity::Critical));
+    }
+}
@@ -0,0 +1,239 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! A security audit of PCI devices: DMA exposure, isolation and trust.
+
+use core::fmt;
+use std::fmt::Display;
+use std::fs::{read_dir, read_to_string};
+
+use crate::backend::linux::sysfs::{Sysfs, SysfsDevice};
+use crate::pci::bandwidth::is_downstream_port;
+use crate::pci::config::{COMMAND_BUS_MASTER, ECAP_ID_ATS};
+use crate::pci::health::Severity;
+use crate::pci::topology::{NodeId, PciTopology};
+
+pub const ATS_CONTROL: usize = 0x06;
+pub const ATS_CONTROL_ENABLE: u16 = 0x8000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditIssue {
+    // No IOMMU is registered, so every device can DMA anywhere in memory.
+    IommuDisabled,
+    // The device's group uses identity mapping, so its DMA isn't translated.
+    IommuPassthrough { group: u32 },
+    // The device can issue DMA but no driver is managing it.
+    BusMasterWithoutDriver,
+    // A downstream port or multi-function device with no ACS capability, so peer to peer
+    // traffic can bypass the IOMMU.
+    MissingAcs,
+    // ACS is there but the controls the kernel needs for isolation are off.
+    AcsNotIsolating,
+    // ATS lets a device present already translated addresses, which an untrusted device could forge.
+    AtsOnUntrusted,
+    // A port leading outside the machine, e.g. Thunderbolt or USB4.
+    ExternalFacing,
+    // A device the kernel treats as untrusted, normally because it sits behind an external port.
+    Untrusted,
+}
+
+impl Display for AuditIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuditIssue::IommuDisabled => write!(f, "IOMMU disabled"),
+            AuditIssue::IommuPassthrough { group } => write!(f, "IOMMU group {} in passthrough mode", group),
+            AuditIssue::BusMasterWithoutDriver => write!(f, "bus mastering enabled without a bound driver"),
+            AuditIssue::MissingAcs => write!(f, "no ACS capability"),
+            AuditIssue::AcsNotIsolating => write!(f, "ACS present but not isolating"),
+            AuditIssue::AtsOnUntrusted => write!(f, "ATS enabled on an untrusted device"),
+            AuditIssue::ExternalFacing => write!(f, "external facing port"),
+            AuditIssue::Untrusted => write!(f, "untrusted device"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditFinding {
+    pub address: Option<String>, // None for system wide findings.
+    pub severity: Severity,
+    pub issue: AuditIssue,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditReport {
+    pub findings: Vec<AuditFinding>,
+}
+
+impl AuditReport {
+    fn push(&mut self, address: Option<String>, severity: Severity, issue: AuditIssue) {
+        self.findings.push(AuditFinding { address, severity, issue });
+    }
+
+    pub fn worst(&self) -> Option<Severity> {
+        self.findings.iter().map(|finding| finding.severity).max()
+    }
+
+    pub fn for_device<'a>(&'a self, address: &'a str) -> impl Iterator<Item = &'a AuditFinding> {
+        self.findings.iter().filter(move |finding| finding.address.as_deref() == Some(address))
+    }
+}
+
+fn flag(device: &SysfsDevice, attribute: &str) -> bool {
+    device.read_attribute(attribute).is_ok_and(|value| value == "1")
+}
+
+impl SysfsDevice {
+    pub fn is_external_facing(&self) -> bool {
+        flag(self, "external_facing")
+    }
+}
+
+impl Sysfs {
+    // Whether any IOMMU has registered with the kernel.
+    pub fn iommu_enabled(&self) -> bool {
+        read_dir(self.root().join("class/iommu")).is_ok_and(|mut entries| entries.next().is_some())
+    }
+
+    // The group's domain type, e.g. DMA, DMA-FQ or identity for passthrough.
+    pub fn iommu_group_type(&self, group: u32) -> Option<String> {
+        read_to_string(self.root().join(format!("kernel/iommu_groups/{}/type", group))).ok().map(|kind| kind.trim().to_string())
+    }
+
+    // Like the kernel, treat devices below an external facing port as untrusted, along with
+    // anything sysfs marks untrusted or removable.
+    pub fn is_untrusted(&self, topology: &PciTopology, id: NodeId) -> bool {
+        let device = self.device(&topology.node(id).device);
+        flag(&device, "untrusted")
+            || device.read_attribute("removable").is_ok_and(|removable| removable == "removable")
+            || topology.ancestors(id).into_iter().any(|ancestor| self.device(&topology.node(ancestor).device).is_external_facing())
+    }
+
+    pub fn security_audit(&self, topology: &PciTopology) -> AuditReport {
+        let mut report = AuditReport::default();
+        let iommu = self.iommu_enabled();
+        if !iommu {
+            report.push(None, Severity::Critical, AuditIssue::IommuDisabled);
+        }
+
+        for (id, node) in topology.nodes().iter().enumerate() {
+            let address = Some(node.address());
+            let device = self.device(&node.device);
+            let untrusted = self.is_untrusted(topology, id);
+
+            if device.is_external_facing() {
+                report.push(address.clone(), Severity::Info, AuditIssue::ExternalFacing);
+            }
+            if untrusted {
+                report.push(address.clone(), Severity::Info, AuditIssue::Untrusted);
+            }
+
+            if let Some(group) = device.iommu_group().filter(|_| iommu) {
+                if self.iommu_group_type(group).as_deref() == Some("identity") {
+                    let severity = if untrusted { Severity::Critical } else { Severity::Warning };
+                    report.push(address.clone(), severity, AuditIssue::IommuPassthrough { group });
+                }
+            }
+
+            let config = match &node.config {
+                Some(config) => config,
+                None => continue,
+            };
+            let bus_master = config.command().is_some_and(|command| command & COMMAND_BUS_MASTER != 0);
+            if bus_master && !node.is_bridge() && device.driver().is_none() {
+                report.push(address.clone(), Severity::Warning, AuditIssue::BusMasterWithoutDriver);
+            }
+
+            // Extended capabilities are only readable as root; without them ACS and ATS can't be judged.
+            if !config.is_extended() {
+                continue;
+            }
+            if is_downstream_port(node) || config.is_multi_function() {
+                match config.acs() {
+                    None => report.push(address.clone(), Severity::Warning, AuditIssue::MissingAcs),
+                    Some(acs) if !acs.isolates() => report.push(address.clone(), Severity::Warning, AuditIssue::AcsNotIsolating),
+                    Some(_) => {}
+                }
+            }
+            let ats_enabled = config
+                .find_extended_capability(ECAP_ID_ATS)
+                .and_then(|offset| config.read_u16(offset + ATS_CONTROL))
+                .is_some_and(|control| control & ATS_CONTROL_ENABLE != 0);
+            if ats_enabled && (untrusted || device.is_external_facing()) {
+                report.push(address, Severity::Critical, AuditIssue::AtsOnUntrusted);
+            }
+        }
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::linux::audit::AuditIssue;
+    use crate::backend::linux::fixture::SysfsFixture;
+    use crate::pci::health::Severity;
+
+    #[test]
+    fn test_security_audit() {
+        let fixture = SysfsFixture::new();
+        fixture.add_device("pci0000:00/0000:00:07.0", 0x8086, 0x9a25, 0x060400);
+        fixture.add_device("pci0000:00/0000:00:07.0/0000:05:00.0", 0x1b21, 0x2142, 0x0c0330);
+        fixture.write("bus/pci/devices/0000:00:07.0/external_facing", "1\n");
+        fixture.write("bus/pci/devices/0000:00:07.0/config", [0u8; 64]);
+
+        // The device behind the external port has bus mastering on, ATS enabled at 0x100
+        // and no driver.
+        let mut config = vec![0u8; 4096];
+        config[0x04] = 0x06;
+        config[0x100..0x104].copy_from_slice(&0x0001_000Fu32.to_le_bytes());
+        config[0x106..0x108].copy_from_slice(&0x8000u16.to_le_bytes());
+        fixture.write("bus/pci/devices/0000:05:00.0/config", &config);
+
+        fixture.link("devices/pci0000:00/0000:00:07.0/0000:05:00.0/iommu_group", "kernel/iommu_groups/12");
+        fixture.write("kernel/iommu_groups/12/type", "identity\n");
+        fixture.write("class/iommu/dmar0/name", "dmar0\n");
+
+        let sysfs = fixture.sysfs();
+        let topology = sysfs.topology().unwrap();
+        let report = sysfs.security_audit(&topology);
+
+        let port: Vec<&AuditIssue> = report.for_device("0000:00:07.0").map(|finding| &finding.issue).collect();
+        assert_eq!(port, vec![&AuditIssue::ExternalFacing]);
+
+        let device: Vec<(Severity, &AuditIssue)> = report.for_device("0000:05:00.0").map(|finding| (finding.severity, &finding.issue)).collect();
+        assert_eq!(
+            device,
+            vec![
+                (Severity::Info, &AuditIssue::Untrusted),
+                (Severity::Critical, &AuditIssue::IommuPassthrough { group: 12 }),
+                (Severity::Warning, &AuditIssue::BusMasterWithoutDriver),
+                (Severity::Critical, &AuditIssue::AtsOnUntrusted),
+            ]
+        );
+        assert!(!report.findings.iter().any(|finding| finding.issue == AuditIssue::IommuDisabled));
+    }
+}
@@ -0,0 +1,192 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Reading and writing configuration space through the sysfs config attribute.
+
+use crate::backend::common::PciEnumerationError;
+use crate::backend::linux::sysfs::{Sysfs, SysfsDevice};
+use crate::pci::config::ConfigSpace;
+use crate::pci::payload::PayloadChange;
+use crate::pci::setpci::SetpciExpression;
+use crate::pci::state::read_register;
+use crate::pci::topology::PciTopology;
+
+// A single register write, with what was there before so it can be put back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigWrite {
+    pub address: String,
+    pub offset: usize,
+    pub width: usize,
+    pub old: u32,
+    pub new: u32,
+}
+
+// Config space writes made so far, oldest first, so they can be undone in reverse.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigJournal {
+    entries: Vec<ConfigWrite>,
+}
+
+impl ConfigJournal {
+    pub fn new() -> Self {
+        ConfigJournal::default()
+    }
+
+    pub fn entries(&self) -> &[ConfigWrite] {
+        &self.entries
+    }
+
+    // Write a register, remembering its old value.
+    pub fn write(&mut self, device: &SysfsDevice, offset: usize, width: usize, value: u32) -> Result<ConfigWrite, PciEnumerationError> {
+        let old = device.read_config_register(offset, width)?;
+        device.write_config_register(offset, width, value)?;
+
+        let entry = ConfigWrite {
+            address: device.address().to_string(),
+            offset,
+            width,
+            old,
+            new: value,
+        };
+        self.entries.push(entry.clone());
+        Ok(entry)
+    }
+
+    // Run a parsed setpci expression. Reads return the register's value, writes return the
+    // values written after masking.
+    pub fn execute(&mut self, device: &SysfsDevice, expression: &SetpciExpression) -> Result<Vec<u32>, PciEnumerationError> {
+        let offset = expression.resolve(&device.config_space()?).ok_or(PciEnumerationError::NotFound)?;
+        if !expression.is_write() {
+            return Ok(vec![device.read_config_register(offset, expression.width)?]);
+        }
+
+        let mut written = Vec::new();
+        for (index, value) in expression.values.iter().enumerate() {
+            let offset = offset + index * expression.width;
+            let new = value.apply(device.read_config_register(offset, expression.width)?);
+            written.push(self.write(device, offset, expression.width, new)?.new);
+        }
+        Ok(written)
+    }
+
+    // Parse and run an expression such as CAP_EXP+10.w=0040:0040
+    pub fn run(&mut self, device: &SysfsDevice, expression: &str) -> Result<Vec<u32>, PciEnumerationError> {
+        self.execute(device, &SetpciExpression::parse(expression)?)
+    }
+
+    // Write the Device Control changes from plan_payload, in the order given.
+    pub fn apply_payload_plan(&mut self, sysfs: &Sysfs, topology: &PciTopology, plan: &[PayloadChange]) -> Result<(), PciEnumerationError> {
+        for change in plan {
+            let device = sysfs.device(&topology.node(change.device).device);
+            self.write(&device, change.control_offset, 2, change.new_control as u32)?;
+        }
+        Ok(())
+    }
+
+    // Put back the old value of the most recent write.
+    pub fn undo_last(&mut self, sysfs: &Sysfs) -> Result<Option<ConfigWrite>, PciEnumerationError> {
+        let entry = match self.entries.pop() {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        if let Err(err) = sysfs.device_by_address(&entry.address).write_config_register(entry.offset, entry.width, entry.old) {
+            self.entries.push(entry);
+            return Err(err);
+        }
+        Ok(Some(entry))
+    }
+
+    // Undo every write, newest first. Stops at the first failure, leaving the rest journaled.
+    pub fn undo(&mut self, sysfs: &Sysfs) -> Result<(), PciEnumerationError> {
+        while self.undo_last(sysfs)?.is_some() {}
+        Ok(())
+    }
+}
+
+impl SysfsDevice {
+    // Without root the kernel only returns the first 64 bytes, so capabilities will be missing.
+    pub fn config_space(&self) -> Result<ConfigSpace, PciEnumerationError> {
+        Ok(ConfigSpace::new(self.read_attribute_bytes("config")?))
+    }
+
+    pub fn read_config_register(&self, offset: usize, width: usize) -> Result<u32, PciEnumerationError> {
+        read_register(&self.config_space()?, offset, width).ok_or(PciEnumerationError::NotFound)
+    }
+
+    // Write raw bytes into config space. Needs root, and the kernel may refuse some ranges.
+    pub fn write_config(&self, offset: usize, data: &[u8]) -> Result<(), PciEnumerationError> {
+        self.write_attribute_bytes_at("config", offset as u64, data)
+    }
+
+    // Write a 1, 2 or 4 byte register in one go.
+    pub fn write_config_register(&self, offset: usize, width: usize, value: u32) -> Result<(), PciEnumerationError> {
+        self.write_config(offset, &value.to_le_bytes()[..width.min(4)])
+    }
+
+    pub fn serial_number(&self) -> Result<Option<u64>, PciEnumerationError> {
+        Ok(self.config_space()?.serial_number())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::read;
+
+    use crate::backend::linux::config::ConfigJournal;
+    use crate::backend::linux::fixture::SysfsFixture;
+
+    #[test]
+    fn test_journaled_writes() {
+        let fixture = SysfsFixture::new();
+        fixture.add_device("pci0000:00/0000:00:1c.0", 0x8086, 0xa338, 0x060400);
+
+        // A PCIe capability at 0x40 with link control at 0x50.
+        let mut config = vec![0u8; 256];
+        config[0x04] = 0x07;
+        config[0x06] = 0x10;
+        config[0x34] = 0x40;
+        config[0x40] = 0x10;
+        config[0x50] = 0x03;
+        let path = fixture.write("bus/pci/devices/0000:00:1c.0/config", &config);
+
+        let sysfs = fixture.sysfs();
+        let device = sysfs.device_by_address("0000:00:1c.0");
+        let mut journal = ConfigJournal::new();
+
+        assert_eq!(journal.run(&device, "COMMAND").unwrap(), vec![0x0007]);
+        assert_eq!(journal.run(&device, "CAP_EXP+10.w=0040:0040").unwrap(), vec![0x0043]);
+        assert_eq!(journal.run(&device, "COMMAND=0:4").unwrap(), vec![0x0003]);
+        assert_eq!(journal.entries().len(), 2);
+        assert_eq!(read(&path).unwrap()[0x50], 0x43);
+        assert!(journal.run(&device, "ECAP_AER+4.l=0").is_err());
+
+        journal.undo(&sysfs).unwrap();
+        assert!(journal.entries().is_empty());
+        assert_eq!(read(&path).unwrap(), config);
+    }
+}
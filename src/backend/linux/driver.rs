@@ -0,0 +1,182 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Inspecting and controlling which kernel driver is bound to a device.
+
+use std::fs::read_link;
+
+use crate::backend::common::{PciDevice, PciEnumerationError};
+use crate::backend::linux::sysfs::{Sysfs, SysfsDevice};
+use crate::pci::render::render_dot;
+use crate::pci::topology::PciTopology;
+
+// An ID added to a driver at runtime through its new_id file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DynamicId {
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub subsystem: Option<(u16, u16)>, // (subsystem vendor, subsystem device)
+}
+
+impl DynamicId {
+    pub fn new(vendor_id: u16, device_id: u16) -> Self {
+        DynamicId {
+            vendor_id,
+            device_id,
+            subsystem: None,
+        }
+    }
+
+    // The format new_id and remove_id expect: hex fields separated by spaces, no 0x prefix.
+    fn to_sysfs_string(self) -> String {
+        match self.subsystem {
+            Some((subsys_vendor_id, subsys_device_id)) => format!("{:04x} {:04x} {:04x} {:04x}", self.vendor_id, self.device_id, subsys_vendor_id, subsys_device_id),
+            None => format!("{:04x} {:04x}", self.vendor_id, self.device_id),
+        }
+    }
+}
+
+impl SysfsDevice {
+    // The driver symlink points at /sys/bus/pci/drivers/<name> while a driver is bound.
+    pub fn driver(&self) -> Option<String> {
+        let target = read_link(self.path().join("driver")).ok()?;
+        Some(target.file_name()?.to_string_lossy().to_string())
+    }
+
+    // The kernel module behind the bound driver. Drivers built into the kernel have none.
+    pub fn driver_module(&self) -> Option<String> {
+        let target = read_link(self.path().join("driver/module")).ok()?;
+        Some(target.file_name()?.to_string_lossy().to_string())
+    }
+
+    // The kernel reports an unset override as "(null)".
+    pub fn driver_override(&self) -> Result<Option<String>, PciEnumerationError> {
+        let value = self.read_attribute("driver_override")?;
+        if value.is_empty() || value == "(null)" {
+            Ok(None)
+        } else {
+            Ok(Some(value))
+        }
+    }
+
+    // Only the named driver will bind to the device from now on, however its IDs match.
+    pub fn set_driver_override(&self, driver: &str) -> Result<(), PciEnumerationError> {
+        self.write_attribute("driver_override", driver)
+    }
+
+    pub fn clear_driver_override(&self) -> Result<(), PciEnumerationError> {
+        self.write_attribute("driver_override", "\n")
+    }
+
+    // Does nothing if no driver is bound.
+    pub fn unbind(&self) -> Result<(), PciEnumerationError> {
+        if self.driver().is_none() {
+            return Ok(());
+        }
+        self.write_attribute("driver/unbind", self.address())
+    }
+}
+
+impl Sysfs {
+    pub fn bind(&self, device: &PciDevice, driver: &str) -> Result<(), PciEnumerationError> {
+        self.write(&format!("bus/pci/drivers/{}/bind", driver), &device.address())
+    }
+
+    // Ask the kernel to find a driver for an unbound device, honouring driver_override.
+    pub fn probe(&self, device: &PciDevice) -> Result<(), PciEnumerationError> {
+        self.write("bus/pci/drivers_probe", &device.address())
+    }
+
+    // Adding an ID also makes the driver probe any unbound device that matches it.
+    pub fn add_dynamic_id(&self, driver: &str, id: DynamicId) -> Result<(), PciEnumerationError> {
+        self.write(&format!("bus/pci/drivers/{}/new_id", driver), &id.to_sysfs_string())
+    }
+
+    pub fn remove_dynamic_id(&self, driver: &str, id: DynamicId) -> Result<(), PciEnumerationError> {
+        self.write(&format!("bus/pci/drivers/{}/remove_id", driver), &id.to_sysfs_string())
+    }
+
+    // Graphviz output with each node's bound driver filled in.
+    pub fn render_dot(&self, topology: &PciTopology) -> String {
+        render_dot(topology, |node| {
+            match self.device(&node.device).driver() {
+                Some(driver) => vec![("driver".to_string(), driver)],
+                None => Vec::new(),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::backend::linux::driver::DynamicId;
+    use crate::backend::linux::fixture::SysfsFixture;
+    use crate::backend::linux::sysfs::SysfsWrite;
+
+    fn recorded(path: &str, value: &str) -> SysfsWrite {
+        SysfsWrite {
+            path: PathBuf::from(path),
+            offset: None,
+            data: value.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_driver_binding() {
+        let fixture = SysfsFixture::new();
+        fixture.add_device("pci0000:00/0000:00:1f.6", 0x8086, 0x15bb, 0x020000);
+        fixture.add_driver("e1000e");
+        fixture.add_driver("vfio-pci");
+        fixture.link("bus/pci/drivers/e1000e/module", "module/e1000e");
+        fixture.link("bus/pci/devices/0000:00:1f.6/driver", "bus/pci/drivers/e1000e");
+        fixture.write("bus/pci/devices/0000:00:1f.6/driver_override", "(null)\n");
+
+        let sysfs = fixture.sysfs().with_write_log();
+        let nic = sysfs.pci_list().unwrap().remove(0);
+        let device = sysfs.device(&nic);
+        assert_eq!(device.driver().as_deref(), Some("e1000e"));
+        assert_eq!(device.driver_module().as_deref(), Some("e1000e"));
+        assert_eq!(device.driver_override().unwrap(), None);
+
+        device.set_driver_override("vfio-pci").unwrap();
+        assert_eq!(device.driver_override().unwrap().as_deref(), Some("vfio-pci"));
+        device.unbind().unwrap();
+        sysfs.add_dynamic_id("vfio-pci", DynamicId::new(0x8086, 0x15bb)).unwrap();
+        sysfs.bind(&nic, "vfio-pci").unwrap();
+        device.clear_driver_override().unwrap();
+
+        assert_eq!(sysfs.writes(), vec![
+            recorded("bus/pci/devices/0000:00:1f.6/driver_override", "vfio-pci"),
+            recorded("bus/pci/devices/0000:00:1f.6/driver/unbind", "0000:00:1f.6"),
+            recorded("bus/pci/drivers/vfio-pci/new_id", "8086 15bb"),
+            recorded("bus/pci/drivers/vfio-pci/bind", "0000:00:1f.6"),
+            recorded("bus/pci/devices/0000:00:1f.6/driver_override", "\n"),
+        ]);
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Throwaway sysfs trees for testing the Linux backend without real hardware.
+
+use std::cell::RefCell;
+use std::env::temp_dir;
+use std::fs::{create_dir_all, read_to_string, remove_dir_all, remove_file, write};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use crate::backend::linux::sysfs::{Sysfs, SysfsWrite};
+
+pub(crate) struct SysfsFixture {
+    root: PathBuf,
+    // Set when the driver core is emulated, with the (device, driver) pairs that fail to bind.
+    driver_core: RefCell<Option<Vec<(String, String)>>>,
+}
+
+impl SysfsFixture {
+    pub(crate) fn new() -> Self {
+        let root = temp_dir().join(format!("libpci-rs-fixture-{:016x}", fastrand::u64(..)));
+        create_dir_all(root.join("bus/pci/devices")).unwrap();
+        create_dir_all(root.join("devices")).unwrap();
+        SysfsFixture {
+            root,
+            driver_core: RefCell::new(None),
+        }
+    }
+
+    pub(crate) fn sysfs(&self) -> Sysfs {
+        let sysfs = Sysfs::with_root(&self.root);
+        match self.driver_core.borrow().clone() {
+            Some(failing) => {
+                let root = self.root.clone();
+                sysfs.with_write_hook(move |write| driver_core(&root, &failing, write))
+            }
+            None => sysfs,
+        }
+    }
+
+    // Create a device under devices/ at the given hierarchy, e.g. pci0000:00/0000:00:1c.0,
+    // and link it into bus/pci/devices like the kernel does. Returns the device directory.
+    pub(crate) fn add_device(&self, hierarchy: &str, vendor_id: u16, device_id: u16, class: u32) -> PathBuf {
+        let path = self.root.join("devices").join(hierarchy);
+        create_dir_all(&path).unwrap();
+
+        write(path.join("vendor"), format!("0x{:04x}\n", vendor_id)).unwrap();
+        write(path.join("device"), format!("0x{:04x}\n", device_id)).unwrap();
+        write(path.join("subsystem_vendor"), "0x0000\n").unwrap();
+        write(path.join("subsystem_device"), "0x0000\n").unwrap();
+        write(path.join("class"), format!("0x{:06x}\n", class)).unwrap();
+        write(path.join("revision"), "0x00\n").unwrap();
+
+        let address = path.file_name().unwrap();
+        symlink(&path, self.root.join("bus/pci/devices").join(address)).unwrap();
+        path
+    }
+
+    // Write a file relative to the fixture root, creating directories on the way.
+    pub(crate) fn write<C: AsRef<[u8]>>(&self, relative: &str, contents: C) -> PathBuf {
+        let path = self.root.join(relative);
+        create_dir_all(path.parent().unwrap()).unwrap();
+        write(&path, contents).unwrap();
+        path
+    }
+
+    // A driver directory with the files used to bind devices and add IDs.
+    pub(crate) fn add_driver(&self, driver: &str) -> PathBuf {
+        for file in ["bind", "unbind", "new_id", "remove_id"] {
+            self.write(&format!("bus/pci/drivers/{}/{}", driver, file), "");
+        }
+        self.root.join("bus/pci/drivers").join(driver)
+    }
+
+    // Create a symlink at a path relative to the fixture root, pointing at another one.
+    pub(crate) fn link(&self, relative: &str, target: &str) {
+        let path = self.root.join(relative);
+        create_dir_all(path.parent().unwrap()).unwrap();
+        create_dir_all(self.root.join(target)).unwrap();
+        symlink(self.root.join(target), path).unwrap();
+    }
+}
+
+impl SysfsFixture {
+    // React to writes to bind, unbind and drivers_probe the way the kernel would, by moving
+    // the device's driver link. Probing honours driver_override.
+    pub(crate) fn emulate_driver_core(&self) {
+        self.driver_core.borrow_mut().get_or_insert_with(Vec::new);
+    }
+
+    // Make binding a device to a driver fail, as if the driver's probe rejected it. Applies to
+    // Sysfs handles made after this call.
+    pub(crate) fn fail_binding(&self, address: &str, driver: &str) {
+        self.driver_core.borrow_mut().get_or_insert_with(Vec::new).push((address.to_string(), driver.to_string()));
+    }
+}
+
+// What the kernel does after a write to bind, unbind or drivers_probe.
+fn driver_core(root: &Path, failing: &[(String, String)], write: &SysfsWrite) {
+    let address = write.value().trim().to_string();
+    let device = root.join("bus/pci/devices").join(&address);
+
+    let driver = if write.path == Path::new("bus/pci/drivers_probe") {
+        match read_to_string(device.join("driver_override")) {
+            Ok(driver_override) if !matches!(driver_override.trim(), "" | "(null)") => driver_override.trim().to_string(),
+            _ => return,
+        }
+    } else if write.path.ends_with("unbind") {
+        let _ = remove_file(device.join("driver"));
+        return;
+    } else if write.path.ends_with("bind") {
+        write.path.parent().unwrap().file_name().unwrap().to_string_lossy().to_string()
+    } else {
+        return;
+    };
+
+    let driver_path = root.join("bus/pci/drivers").join(&driver);
+    if !failing.contains(&(address, driver)) && driver_path.exists() && !device.join("driver").exists() {
+        symlink(driver_path, device.join("driver")).unwrap();
+    }
+}
+
+impl Drop for SysfsFixture {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.root);
+    }
+}
@@ -0,0 +1,50 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Gathering stable device identities from sysfs.
+
+use crate::backend::common::PciDevice;
+use crate::backend::linux::sysfs::Sysfs;
+use crate::pci::identity::{DeviceIdentity, IdentityHints};
+
+impl Sysfs {
+    // Every source is optional: unreadable config space or missing VPD just means falling back
+    // to the next source.
+    pub fn identity_hints(&self, device: &PciDevice) -> IdentityHints {
+        let sysfs_device = self.device(device);
+
+        IdentityHints {
+            serial_number: sysfs_device.serial_number().ok().flatten(),
+            vpd_serial: sysfs_device.vpd().ok().and_then(|vpd| vpd.serial_number()),
+            physical_slot: self.physical_slot(device),
+        }
+    }
+
+    pub fn identity(&self, device: &PciDevice) -> DeviceIdentity {
+        DeviceIdentity::new(device, &self.identity_hints(device))
+    }
+}
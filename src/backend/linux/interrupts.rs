@@ -0,0 +1,301 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Interrupt lines and MSI vectors of devices, with per-CPU counts from /proc/interrupts.
+
+use std::collections::BTreeMap;
+use std::fs::{read_dir, read_to_string, write};
+use std::path::{Path, PathBuf};
+
+use crate::backend::common::{PciDevice, PciEnumerationError};
+use crate::backend::linux::numa::CpuSet;
+use crate::backend::linux::sysfs::{Sysfs, SysfsDevice};
+
+// Where procfs is mounted on a normal system.
+pub const PROC_ROOT: &str = "/proc";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MsiMode {
+    Msi,
+    MsiX,
+    Other(String),
+}
+
+impl From<&str> for MsiMode {
+    fn from(mode: &str) -> Self {
+        match mode {
+            "msi" => MsiMode::Msi,
+            "msix" => MsiMode::MsiX,
+            other => MsiMode::Other(other.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MsiVector {
+    pub irq: u32,
+    pub mode: MsiMode,
+}
+
+// One numbered line of /proc/interrupts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterruptLine {
+    pub irq: u32,
+    pub per_cpu: BTreeMap<usize, u64>,
+    pub description: String, // The chip, hardware IRQ and handler names, e.g. IR-PCI-MSIX-0000:81:00.0 1-edge mlx5_comp0
+}
+
+impl InterruptLine {
+    pub fn total(&self) -> u64 {
+        self.per_cpu.values().sum()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProcInterrupts {
+    pub lines: BTreeMap<u32, InterruptLine>,
+}
+
+impl ProcInterrupts {
+    // The header names the CPU of each column, which skips offline CPUs. Lines for NMIs,
+    // timers and other per-CPU sources aren't numbered, and are left out.
+    pub fn parse(contents: &str) -> ProcInterrupts {
+        let mut lines = contents.lines();
+        let cpus: Vec<usize> = match lines.next() {
+            Some(header) => header.split_whitespace().filter_map(|column| column.strip_prefix("CPU")?.parse().ok()).collect(),
+            None => return ProcInterrupts::default(),
+        };
+
+        let mut interrupts = ProcInterrupts::default();
+        for line in lines {
+            let mut fields = line.split_whitespace();
+            let irq = match fields.next().and_then(|irq| irq.strip_suffix(':')?.parse().ok()) {
+                Some(irq) => irq,
+                None => continue,
+            };
+
+            let mut per_cpu = BTreeMap::new();
+            for cpu in &cpus {
+                match fields.next().and_then(|count| count.parse().ok()) {
+                    Some(count) => per_cpu.insert(*cpu, count),
+                    None => break,
+                };
+            }
+
+            let description = fields.collect::<Vec<&str>>().join(" ");
+            interrupts.lines.insert(irq, InterruptLine { irq, per_cpu, description });
+        }
+        interrupts
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ProcInterrupts, PciEnumerationError> {
+        Ok(ProcInterrupts::parse(&read_to_string(path)?))
+    }
+
+    pub fn get(&self, irq: u32) -> Option<&InterruptLine> {
+        self.lines.get(&irq)
+    }
+}
+
+// The root of procfs. Tests point this at a fixture directory instead of /proc.
+#[derive(Debug, Clone)]
+pub struct Procfs {
+    root: PathBuf,
+}
+
+impl Default for Procfs {
+    fn default() -> Self {
+        Procfs::with_root(PROC_ROOT)
+    }
+}
+
+impl Procfs {
+    pub fn new() -> Self {
+        Procfs::default()
+    }
+
+    pub fn with_root<P: AsRef<Path>>(root: P) -> Self {
+        Procfs { root: root.as_ref().to_path_buf() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn interrupts(&self) -> Result<ProcInterrupts, PciEnumerationError> {
+        ProcInterrupts::load(self.root.join("interrupts"))
+    }
+
+    // The CPUs an interrupt may be delivered to.
+    pub fn irq_affinity(&self, irq: u32) -> Result<CpuSet, PciEnumerationError> {
+        let directory = self.root.join(format!("irq/{}", irq));
+        match read_to_string(directory.join("smp_affinity_list")) {
+            Ok(list) => CpuSet::parse_list(&list),
+            Err(_) => CpuSet::parse_mask(&read_to_string(directory.join("smp_affinity"))?),
+        }
+    }
+
+    pub fn set_irq_affinity(&self, irq: u32, cpus: &CpuSet) -> Result<(), PciEnumerationError> {
+        Ok(write(self.root.join(format!("irq/{}/smp_affinity_list", irq)), cpus.to_string())?)
+    }
+}
+
+// Everything known about one of a device's interrupts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VectorInterrupts {
+    pub irq: u32,
+    pub mode: Option<MsiMode>, // None for the legacy INTx line.
+    pub description: Option<String>,
+    pub per_cpu: BTreeMap<usize, u64>,
+    pub affinity: Option<CpuSet>,
+}
+
+impl VectorInterrupts {
+    pub fn total(&self) -> u64 {
+        self.per_cpu.values().sum()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceInterrupts {
+    pub vectors: Vec<VectorInterrupts>,
+}
+
+impl DeviceInterrupts {
+    pub fn total(&self) -> u64 {
+        self.vectors.iter().map(VectorInterrupts::total).sum()
+    }
+
+    // Interrupts handled by each CPU across every vector, to spot queues piled on one core.
+    pub fn per_cpu(&self) -> BTreeMap<usize, u64> {
+        let mut per_cpu = BTreeMap::new();
+        for vector in &self.vectors {
+            for (cpu, count) in &vector.per_cpu {
+                *per_cpu.entry(*cpu).or_insert(0) += count;
+            }
+        }
+        per_cpu
+    }
+}
+
+impl SysfsDevice {
+    // The legacy interrupt line. Zero means none is assigned.
+    pub fn irq(&self) -> Option<u32> {
+        self.read_attribute("irq").ok()?.parse().ok().filter(|irq| *irq != 0)
+    }
+
+    // msi_irqs holds a file per vector, named after its IRQ and containing msi or msix.
+    // It only exists while MSI is enabled.
+    pub fn msi_irqs(&self) -> Result<Vec<MsiVector>, PciEnumerationError> {
+        let entries = match read_dir(self.path().join("msi_irqs")) {
+            Ok(entries) => entries,
+            Err(_) => return Ok(Vec::new()),
+        };
+
+        let mut vectors = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let irq = match entry.file_name().to_str().and_then(|name| name.parse().ok()) {
+                Some(irq) => irq,
+                None => continue,
+            };
+            vectors.push(MsiVector {
+                irq,
+                mode: MsiMode::from(read_to_string(entry.path())?.trim()),
+            });
+        }
+        vectors.sort_by_key(|vector| vector.irq);
+        Ok(vectors)
+    }
+}
+
+impl Sysfs {
+    // A device's MSI vectors, or its legacy line when MSI is off, with counts and affinity.
+    pub fn interrupts(&self, procfs: &Procfs, device: &PciDevice) -> Result<DeviceInterrupts, PciEnumerationError> {
+        let sysfs_device = self.device(device);
+        let mut sources: Vec<(u32, Option<MsiMode>)> = sysfs_device.msi_irqs()?.into_iter().map(|vector| (vector.irq, Some(vector.mode))).collect();
+        if sources.is_empty() {
+            sources.extend(sysfs_device.irq().map(|irq| (irq, None)));
+        }
+
+        let counts = procfs.interrupts()?;
+        let vectors = sources
+            .into_iter()
+            .map(|(irq, mode)| {
+                let line = counts.get(irq);
+                VectorInterrupts {
+                    irq,
+                    mode,
+                    description: line.map(|line| line.description.clone()),
+                    per_cpu: line.map(|line| line.per_cpu.clone()).unwrap_or_default(),
+                    affinity: procfs.irq_affinity(irq).ok(),
+                }
+            })
+            .collect();
+
+        Ok(DeviceInterrupts { vectors })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::linux::fixture::SysfsFixture;
+    use crate::backend::linux::interrupts::{MsiMode, Procfs};
+
+    const PROC_INTERRUPTS: &str = "           CPU0       CPU1       CPU2       CPU3
+  0:         38          0          0          0   IO-APIC   2-edge      timer
+ 16:          0          0          0         12   IO-APIC  16-fasteoi   i801_smbus
+ 45:        100        250          0          0   IR-PCI-MSIX-0000:81:00.0    0-edge      mlx5_async0
+ 46:          0       4000       1000          0   IR-PCI-MSIX-0000:81:00.0    1-edge      mlx5_comp0
+NMI:          1          1          1          1   Non-maskable interrupts
+";
+
+    #[test]
+    fn test_device_interrupts() {
+        let fixture = SysfsFixture::new();
+        fixture.add_device("pci0000:80/0000:80:02.0/0000:81:00.0", 0x15b3, 0x101d, 0x020000);
+        fixture.write("bus/pci/devices/0000:81:00.0/irq", "16\n");
+        fixture.write("bus/pci/devices/0000:81:00.0/msi_irqs/45", "msix\n");
+        fixture.write("bus/pci/devices/0000:81:00.0/msi_irqs/46", "msix\n");
+        fixture.write("proc/interrupts", PROC_INTERRUPTS);
+        fixture.write("proc/irq/46/smp_affinity_list", "1-2\n");
+
+        let sysfs = fixture.sysfs();
+        let procfs = Procfs::with_root(sysfs.root().join("proc"));
+        let device = sysfs.pci_list().unwrap().remove(0);
+        assert_eq!(sysfs.device(&device).irq(), Some(16));
+
+        let interrupts = sysfs.interrupts(&procfs, &device).unwrap();
+        assert_eq!(interrupts.vectors.len(), 2);
+        assert_eq!(interrupts.vectors[0].mode, Some(MsiMode::MsiX));
+        assert_eq!(interrupts.vectors[1].description.as_deref(), Some("IR-PCI-MSIX-0000:81:00.0 1-edge mlx5_comp0"));
+        assert_eq!(interrupts.vectors[1].affinity.as_ref().map(|cpus| cpus.to_string()), Some("1-2".to_string()));
+        assert_eq!(interrupts.vectors[0].affinity, None);
+        assert_eq!(interrupts.total(), 5350);
+        assert_eq!(interrupts.per_cpu().get(&1), Some(&4250));
+    }
+}
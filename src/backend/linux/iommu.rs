@@ -0,0 +1,209 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! IOMMU groups and how ready devices are for VFIO passthrough.
+
+use std::fs::{read_dir, read_link};
+
+use crate::backend::common::{PciDevice, PciEnumerationError};
+use crate::backend::linux::sysfs::{Sysfs, SysfsDevice};
+use crate::pci::express::PciePortType;
+use crate::pci::topology::PciTopology;
+
+// Drivers that leave a device safe to hand to a guest.
+const PASSTHROUGH_DRIVERS: [&str; 2] = ["vfio-pci", "pci-stub"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IommuGroup {
+    pub id: u32,
+    pub devices: Vec<String>, // Addresses of every device in the group.
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PassthroughBlocker {
+    NoIommuGroup, // The IOMMU is disabled or does not cover this device.
+    HostDriverBound { address: String, driver: String }, // A group member is in use by the host.
+    MissingAcs { address: String }, // A port above the device does not isolate it from its neighbours.
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PassthroughReport {
+    pub address: String,
+    pub group: Option<u32>,
+    pub group_members: Vec<String>, // The other devices sharing the group.
+    pub assignable: bool, // Whether the whole group can go to a guest as things stand.
+    pub blockers: Vec<PassthroughBlocker>,
+}
+
+impl SysfsDevice {
+    // iommu_group links to /sys/kernel/iommu_groups/<id>.
+    pub fn iommu_group(&self) -> Option<u32> {
+        let target = read_link(self.path().join("iommu_group")).ok()?;
+        target.file_name()?.to_str()?.parse().ok()
+    }
+}
+
+impl Sysfs {
+    pub fn iommu_groups(&self) -> Result<Vec<IommuGroup>, PciEnumerationError> {
+        let mut groups = Vec::new();
+
+        for entry in read_dir(self.root().join("kernel/iommu_groups"))? {
+            let entry = entry?;
+            let id = match entry.file_name().to_str().and_then(|name| name.parse().ok()) {
+                Some(id) => id,
+                None => continue,
+            };
+            groups.push(self.iommu_group(id)?);
+        }
+
+        groups.sort_by_key(|group| group.id);
+        Ok(groups)
+    }
+
+    pub fn iommu_group(&self, id: u32) -> Result<IommuGroup, PciEnumerationError> {
+        let mut devices = Vec::new();
+        for entry in read_dir(self.root().join(format!("kernel/iommu_groups/{}/devices", id)))? {
+            devices.push(entry?.file_name().to_string_lossy().to_string());
+        }
+        devices.sort();
+
+        Ok(IommuGroup { id, devices })
+    }
+
+    // Check a device against what VFIO needs: an IOMMU group, nothing else in the group bound
+    // to a host driver (bridges excepted), and ACS isolation on every port above it.
+    pub fn passthrough_report(&self, topology: &PciTopology, device: &PciDevice) -> Result<PassthroughReport, PciEnumerationError> {
+        let address = device.address();
+        let mut blockers = Vec::new();
+
+        let group = self.device(device).iommu_group();
+        let members = match group {
+            Some(id) => self.iommu_group(id)?.devices,
+            None => {
+                blockers.push(PassthroughBlocker::NoIommuGroup);
+                vec![address.clone()]
+            }
+        };
+
+        for member in &members {
+            let is_bridge = topology.find(member).map(|id| topology.node(id).is_bridge()).unwrap_or(false);
+            if is_bridge {
+                continue;
+            }
+            if let Some(driver) = self.device_by_address(member).driver() {
+                if !PASSTHROUGH_DRIVERS.contains(&driver.as_str()) {
+                    blockers.push(PassthroughBlocker::HostDriverBound {
+                        address: member.clone(),
+                        driver,
+                    });
+                }
+            }
+        }
+
+        // Missing isolation only matters when it pulled other devices into the group.
+        let shares_group = members.iter().any(|member| {
+            *member != address && !topology.find(member).map(|id| topology.node(id).is_bridge()).unwrap_or(false)
+        });
+        if let (true, Some(id)) = (shares_group, topology.find(&address)) {
+            let node = topology.node(id);
+            let mut checked = Vec::new();
+            if node.config.as_ref().is_some_and(|config| config.is_multi_function()) {
+                checked.push(id);
+            }
+            checked.extend(topology.ancestors(id).into_iter().filter(|ancestor| {
+                matches!(topology.node(*ancestor).port_type(), Some(PciePortType::RootPort) | Some(PciePortType::DownstreamPort))
+            }));
+
+            for port in checked {
+                // Without readable config space there is nothing to say either way.
+                let config = match &topology.node(port).config {
+                    Some(config) => config,
+                    None => continue,
+                };
+                if !config.acs().is_some_and(|acs| acs.isolates()) {
+                    blockers.push(PassthroughBlocker::MissingAcs {
+                        address: topology.node(port).address(),
+                    });
+                }
+            }
+        }
+
+        let assignable = !blockers.iter().any(|blocker| !matches!(blocker, PassthroughBlocker::MissingAcs { .. }));
+
+        Ok(PassthroughReport {
+            group_members: members.into_iter().filter(|member| *member != address).collect(),
+            address,
+            group,
+            assignable,
+            blockers,
+        })
+    }
+
+    pub fn passthrough_reports(&self, topology: &PciTopology) -> Result<Vec<PassthroughReport>, PciEnumerationError> {
+        topology.nodes().iter()
+            .filter(|node| !node.is_bridge())
+            .map(|node| self.passthrough_report(topology, &node.device))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::linux::fixture::SysfsFixture;
+    use crate::backend::linux::iommu::PassthroughBlocker;
+
+    #[test]
+    fn test_passthrough_report() {
+        let fixture = SysfsFixture::new();
+        fixture.add_device("pci0000:00/0000:00:1c.0", 0x8086, 0xa110, 0x060400);
+        fixture.add_device("pci0000:00/0000:00:1c.0/0000:02:00.0", 0x8086, 0x1572, 0x020000);
+        fixture.add_device("pci0000:00/0000:00:1c.0/0000:02:00.1", 0x8086, 0x1572, 0x020000);
+        for address in ["0000:00:1c.0", "0000:02:00.0", "0000:02:00.1"] {
+            fixture.link(&format!("kernel/iommu_groups/7/devices/{}", address), &format!("bus/pci/devices/{}", address));
+            fixture.link(&format!("bus/pci/devices/{}/iommu_group", address), "kernel/iommu_groups/7");
+        }
+        fixture.link("bus/pci/devices/0000:00:1c.0/driver", "bus/pci/drivers/pcieport");
+        fixture.link("bus/pci/devices/0000:02:00.0/driver", "bus/pci/drivers/vfio-pci");
+        fixture.link("bus/pci/devices/0000:02:00.1/driver", "bus/pci/drivers/i40e");
+
+        let sysfs = fixture.sysfs();
+        let groups = sysfs.iommu_groups().unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].devices.len(), 3);
+
+        let topology = sysfs.topology().unwrap();
+        let nic = &topology.node(topology.find("0000:02:00.0").unwrap()).device;
+        let report = sysfs.passthrough_report(&topology, nic).unwrap();
+        assert_eq!(report.group, Some(7));
+        assert_eq!(report.group_members, vec!["0000:00:1c.0", "0000:02:00.1"]);
+        assert!(!report.assignable);
+        assert_eq!(report.blockers, vec![PassthroughBlocker::HostDriverBound {
+            address: "0000:02:00.1".to_string(),
+            driver: "i40e".to_string(),
+        }]);
+    }
+}
@@ -27,9 +27,31 @@
 
 use crate::backend::common::PciDevice;
 use std::fs::*;
+use std::path::Path;
 
 use super::common::*;
 
+pub mod aer;
+pub mod audit;
+pub mod config;
+pub mod driver;
+#[cfg(test)]
+pub(crate) mod fixture;
+pub mod identity;
+pub mod interrupts;
+pub mod iommu;
+pub mod modalias;
+pub mod numa;
+pub mod p2p;
+pub mod power;
+pub mod reset;
+pub mod rom;
+pub mod slots;
+pub mod sysfs;
+pub mod topology;
+pub mod vfio;
+pub mod vpd;
+
 // ahaha this particular code is by Shibe Drill
 
 #[inline]
@@ -47,7 +69,12 @@ fn comps_from_linux_pci_addr(address: &str) -> Result<(u32, u8, u8, u8), ()> {
 }
 
 #[inline]
-pub fn _get_pci_list() -> Result<Vec<PciDevice>, PciEnumerationError> {
+pub(crate) fn _get_pci_list() -> Result<Vec<PciDevice>, PciEnumerationError> {
+    get_pci_list_from(Path::new("/sys/bus/pci/devices/"))
+}
+
+// Enumerate the devices in a sysfs style directory, so fixture trees can be read the same way.
+pub(crate) fn get_pci_list_from(devices_directory: &Path) -> Result<Vec<PciDevice>, PciEnumerationError> {
     let mut device_list: Vec<PciDevice> = Vec::new();
 
     /*
@@ -69,7 +96,7 @@ pub fn _get_pci_list() -> Result<Vec<PciDevice>, PciEnumerationError> {
         Revision ID: file 'revision', 0x prefix
     */
 
-    for directory in read_dir("/sys/bus/pci/devices/").unwrap() {
+    for directory in read_dir(devices_directory)? {
         let label = String::from("Label");
         let vendor_id = get_pci_device_attribute_u16(&directory, "vendor")?; // Vendor ID
         let device_id = get_pci_device_attribute_u16(&directory, "device")?; // Device ID
@@ -83,7 +110,7 @@ pub fn _get_pci_list() -> Result<Vec<PciDevice>, PciEnumerationError> {
         let programming_interface: u8 = (class_code & 0xFF) as u8; // Device Programming Interface
 
         let revision_id = get_pci_device_attribute_u8(&directory, "revision")?; // Revision ID
-        let components = comps_from_linux_pci_addr(&directory.unwrap().file_name().to_str().unwrap()).unwrap(); // TODO: handle in case of error as to not panic on unwrap.
+        let components = comps_from_linux_pci_addr(directory.unwrap().file_name().to_str().unwrap()).unwrap(); // TODO: handle in case of error as to not panic on unwrap.
         let (domain, bus, device, function) = components;
 
         device_list.push(PciDevice {
@@ -108,7 +135,7 @@ pub fn _get_pci_list() -> Result<Vec<PciDevice>, PciEnumerationError> {
 }
 
 #[inline]
-pub fn _get_pci_by_id(vendor: u16, device: u16) -> Result<PciDevice, PciEnumerationError> {
+pub(crate) fn _get_pci_by_id(_vendor: u16, _device: u16) -> Result<PciDevice, PciEnumerationError> {
     todo!()
 }
 
@@ -0,0 +1,248 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Working out which kernel modules could drive a device from modules.alias.
+
+use std::fs::read_to_string;
+use std::path::Path;
+
+use crate::backend::common::{PciDevice, PciEnumerationError};
+use crate::backend::linux::sysfs::{Sysfs, SysfsDevice};
+
+// The modalias the kernel generates for a PCI device, as in drivers/pci/pci-driver.c
+pub fn modalias(device: &PciDevice) -> String {
+    format!(
+        "pci:v{:08X}d{:08X}sv{:08X}sd{:08X}bc{:02X}sc{:02X}i{:02X}",
+        device.vendor_id, device.device_id, device.subsys_vendor_id, device.subsys_device_id,
+        device.class, device.subclass, device.programming_interface,
+    )
+}
+
+impl SysfsDevice {
+    pub fn modalias(&self) -> Result<String, PciEnumerationError> {
+        self.read_attribute("modalias")
+    }
+}
+
+impl Sysfs {
+    // Whether the modalias built from the device's fields agrees with the one the kernel reports.
+    pub fn check_modalias(&self, device: &PciDevice) -> Result<bool, PciEnumerationError> {
+        Ok(self.device(device).modalias()? == modalias(device))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleAlias {
+    pub pattern: String,
+    pub module: String,
+}
+
+// The PCI entries of a modules.alias file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModuleAliases {
+    pub aliases: Vec<ModuleAlias>,
+}
+
+impl ModuleAliases {
+    // Lines look like: alias pci:v00008086d000015BBsv*sd*bc*sc*i* e1000e
+    pub fn parse(contents: &str) -> ModuleAliases {
+        let aliases = contents.lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                match (fields.next(), fields.next(), fields.next()) {
+                    (Some("alias"), Some(pattern), Some(module)) if pattern.starts_with("pci:") => Some(ModuleAlias {
+                        pattern: pattern.to_string(),
+                        module: module.to_string(),
+                    }),
+                    _ => None,
+                }
+            })
+            .collect();
+
+        ModuleAliases { aliases }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ModuleAliases, PciEnumerationError> {
+        Ok(ModuleAliases::parse(&read_to_string(path)?))
+    }
+
+    // modules.alias for the running kernel, plus modules.builtin.alias where the kernel has one,
+    // since built in drivers never show up in modules.alias.
+    pub fn load_running_kernel() -> Result<ModuleAliases, PciEnumerationError> {
+        let release = read_to_string("/proc/sys/kernel/osrelease")?;
+        let directory = Path::new("/lib/modules").join(release.trim());
+
+        let mut aliases = ModuleAliases::load(directory.join("modules.alias"))?;
+        if let Ok(builtin) = ModuleAliases::load(directory.join("modules.builtin.alias")) {
+            aliases.aliases.extend(builtin.aliases);
+        }
+        Ok(aliases)
+    }
+
+    // Every module whose alias matches, in file order and without repeats.
+    pub fn candidates(&self, modalias: &str) -> Vec<String> {
+        let mut modules: Vec<String> = Vec::new();
+        for alias in &self.aliases {
+            if glob_match(alias.pattern.as_bytes(), modalias.as_bytes()) && !modules.contains(&alias.module) {
+                modules.push(alias.module.clone());
+            }
+        }
+        modules
+    }
+
+    pub fn candidates_for(&self, device: &PciDevice) -> Vec<String> {
+        self.candidates(&modalias(device))
+    }
+
+    // Devices no module claims, bound or not.
+    pub fn unclaimed<'a>(&self, devices: &'a [PciDevice]) -> Vec<&'a PciDevice> {
+        devices.iter().filter(|device| self.candidates_for(device).is_empty()).collect()
+    }
+}
+
+// fnmatch style matching as modprobe does it: *, ? and [...] classes with ranges and ! or ^.
+// Only the most recent * is ever backtracked to, which keeps this linear for alias patterns.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let mut pattern_index = 0;
+    let mut text_index = 0;
+    let mut backtrack: Option<(usize, usize)> = None; // (pattern after the *, text position)
+
+    while text_index < text.len() {
+        let step = match pattern.get(pattern_index) {
+            Some(b'*') => {
+                backtrack = Some((pattern_index + 1, text_index));
+                pattern_index += 1;
+                continue;
+            }
+            Some(b'?') => Some(1),
+            Some(b'[') => match match_class(&pattern[pattern_index + 1..], text[text_index]) {
+                Some((true, rest)) => Some(pattern.len() - rest.len() - pattern_index),
+                Some((false, _)) => None,
+                // An unterminated class is a literal [.
+                None => if text[text_index] == b'[' { Some(1) } else { None },
+            },
+            Some(literal) if *literal == text[text_index] => Some(1),
+            _ => None,
+        };
+
+        match (step, backtrack) {
+            (Some(length), _) => {
+                pattern_index += length;
+                text_index += 1;
+            }
+            (None, Some((star_pattern, star_text))) => {
+                pattern_index = star_pattern;
+                text_index = star_text + 1;
+                backtrack = Some((star_pattern, star_text + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+
+    pattern[pattern_index..].iter().all(|character| *character == b'*')
+}
+
+// Returns whether the character is in the class and the pattern after the closing bracket.
+fn match_class(pattern: &[u8], character: u8) -> Option<(bool, &[u8])> {
+    let (negated, mut index) = match pattern.first() {
+        Some(b'!') | Some(b'^') => (true, 1),
+        _ => (false, 0),
+    };
+
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let current = *pattern.get(index)?;
+        if current == b']' && !first {
+            return Some((matched != negated, &pattern[index + 1..]));
+        }
+        first = false;
+
+        if pattern.get(index + 1) == Some(&b'-') && pattern.get(index + 2).is_some_and(|end| *end != b']') {
+            let end = pattern[index + 2];
+            matched |= current <= character && character <= end;
+            index += 3;
+        } else {
+            matched |= current == character;
+            index += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::common::PciDevice;
+    use crate::backend::linux::fixture::SysfsFixture;
+    use crate::backend::linux::modalias::{modalias, ModuleAliases};
+
+    #[test]
+    fn test_modalias_matching() {
+        let device = PciDevice {
+            domain: 0,
+            bus: 0,
+            device: 0x1f,
+            function: 6,
+            label: String::new(),
+            vendor_id: 0x8086,
+            device_id: 0x15bb,
+            subsys_device_id: 0x0872,
+            subsys_vendor_id: 0x1028,
+            class: 0x02,
+            subclass: 0x00,
+            programming_interface: 0x00,
+            revision_id: 0x10,
+        };
+        assert_eq!(modalias(&device), "pci:v00008086d000015BBsv00001028sd00000872bc02sc00i00");
+
+        let aliases = ModuleAliases::parse(concat!(
+            "alias pci:v00008086d000015BBsv*sd*bc*sc*i* e1000e\n",
+            "alias pci:v*d*sv*sd*bc02sc0[0-1]i* generic_nic\n",
+            "alias pci:v*d*sv*sd*bc03sc*i* vga_driver\n",
+            "alias usb:v*p*d* usb_thing\n",
+        ));
+        assert_eq!(aliases.aliases.len(), 3);
+        assert_eq!(aliases.candidates_for(&device), vec!["e1000e", "generic_nic"]);
+        assert!(aliases.unclaimed(&[device]).is_empty());
+    }
+
+    #[test]
+    fn test_check_modalias() {
+        let fixture = SysfsFixture::new();
+        fixture.add_device("pci0000:00/0000:00:1d.0/0000:03:00.0", 0x144d, 0xa808, 0x010802);
+        fixture.add_device("pci0000:00/0000:00:1d.0/0000:04:00.0", 0x144d, 0xa808, 0x010802);
+        fixture.write("bus/pci/devices/0000:03:00.0/modalias", "pci:v0000144Dd0000A808sv00000000sd00000000bc01sc08i02\n");
+        // The kernel saw a different subsystem than the one read back.
+        fixture.write("bus/pci/devices/0000:04:00.0/modalias", "pci:v0000144Dd0000A808sv0000144Dsd0000A801bc01sc08i02\n");
+
+        let sysfs = fixture.sysfs();
+        let devices = sysfs.pci_list().unwrap();
+        let drive = devices.iter().find(|device| device.bus == 3).unwrap();
+        let other = devices.iter().find(|device| device.bus == 4).unwrap();
+        assert!(sysfs.check_modalias(drive).unwrap());
+        assert!(!sysfs.check_modalias(other).unwrap());
+    }
+}
@@ -0,0 +1,251 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! NUMA node and CPU affinity of devices, and where to pin work for them.
+
+use core::fmt;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::fs::read_to_string;
+
+use crate::backend::common::{PciDevice, PciEnumerationError};
+use crate::backend::linux::sysfs::{Sysfs, SysfsDevice};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CpuSet {
+    cpus: BTreeSet<usize>,
+}
+
+impl CpuSet {
+    pub fn new() -> Self {
+        CpuSet::default()
+    }
+
+    // Parse the list format, e.g. 0-7,16-23
+    pub fn parse_list(list: &str) -> Result<CpuSet, PciEnumerationError> {
+        let mut cpus = BTreeSet::new();
+        for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+            match range.split_once('-') {
+                Some((first, last)) => cpus.extend(first.parse::<usize>()?..=last.parse()?),
+                None => {
+                    cpus.insert(range.parse()?);
+                }
+            }
+        }
+        Ok(CpuSet { cpus })
+    }
+
+    // Parse the mask format: comma separated 32 bit hex words, most significant first,
+    // e.g. 00000000,00ff00ff
+    pub fn parse_mask(mask: &str) -> Result<CpuSet, PciEnumerationError> {
+        let mut cpus = BTreeSet::new();
+        for (index, word) in mask.trim().split(',').rev().enumerate() {
+            let word = u32::from_str_radix(word, 16)?;
+            cpus.extend((0..32).filter(|bit| word & (1 << bit) != 0).map(|bit| index * 32 + bit));
+        }
+        Ok(CpuSet { cpus })
+    }
+
+    pub fn len(&self) -> usize {
+        self.cpus.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cpus.is_empty()
+    }
+
+    pub fn contains(&self, cpu: usize) -> bool {
+        self.cpus.contains(&cpu)
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = usize> + '_ {
+        self.cpus.iter().copied()
+    }
+
+    pub fn intersection(&self, other: &CpuSet) -> CpuSet {
+        self.cpus.intersection(&other.cpus).copied().collect()
+    }
+
+    pub fn difference(&self, other: &CpuSet) -> CpuSet {
+        self.cpus.difference(&other.cpus).copied().collect()
+    }
+}
+
+impl FromIterator<usize> for CpuSet {
+    fn from_iter<I: IntoIterator<Item = usize>>(iter: I) -> Self {
+        CpuSet { cpus: iter.into_iter().collect() }
+    }
+}
+
+// Written back in the list format, which is also what taskset -c and irqbalance accept.
+impl Display for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        for cpu in self.iter() {
+            match ranges.last_mut() {
+                Some((_, last)) if *last + 1 == cpu => *last = cpu,
+                _ => ranges.push((cpu, cpu)),
+            }
+        }
+
+        let ranges: Vec<String> = ranges
+            .into_iter()
+            .map(|(first, last)| if first == last { first.to_string() } else { format!("{}-{}", first, last) })
+            .collect();
+        write!(f, "{}", ranges.join(","))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceAffinity {
+    pub numa_node: Option<u32>, // None when the platform doesn't say, which sysfs shows as -1.
+    pub local_cpus: CpuSet,
+}
+
+// Where to run a device's interrupt handlers and the threads that feed it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PinningRecommendation {
+    pub numa_node: Option<u32>,
+    pub interrupts: CpuSet,
+    pub workers: CpuSet,
+}
+
+impl SysfsDevice {
+    pub fn numa_node(&self) -> Option<u32> {
+        self.read_attribute("numa_node").ok()?.parse().ok()
+    }
+
+    // local_cpulist is easier to read, but older kernels only have the local_cpus mask.
+    pub fn local_cpus(&self) -> Result<CpuSet, PciEnumerationError> {
+        match self.read_attribute("local_cpulist") {
+            Ok(list) => CpuSet::parse_list(&list),
+            Err(_) => CpuSet::parse_mask(&self.read_attribute("local_cpus")?),
+        }
+    }
+
+    pub fn affinity(&self) -> Result<DeviceAffinity, PciEnumerationError> {
+        Ok(DeviceAffinity {
+            numa_node: self.numa_node(),
+            local_cpus: self.local_cpus()?,
+        })
+    }
+}
+
+impl Sysfs {
+    fn cpu_list(&self, name: &str) -> Result<CpuSet, PciEnumerationError> {
+        CpuSet::parse_list(&read_to_string(self.root().join("devices/system/cpu").join(name))?)
+    }
+
+    pub fn online_cpus(&self) -> Result<CpuSet, PciEnumerationError> {
+        self.cpu_list("online")
+    }
+
+    // CPUs kept away from the scheduler with isolcpus. Empty if there are none.
+    pub fn isolated_cpus(&self) -> CpuSet {
+        self.cpu_list("isolated").unwrap_or_default()
+    }
+
+    // Every device, grouped by NUMA node. Devices with no node are under None.
+    pub fn devices_by_numa_node(&self) -> Result<BTreeMap<Option<u32>, Vec<PciDevice>>, PciEnumerationError> {
+        let mut nodes: BTreeMap<Option<u32>, Vec<PciDevice>> = BTreeMap::new();
+        for device in self.pci_list()? {
+            nodes.entry(self.device(&device).numa_node()).or_default().push(device);
+        }
+        Ok(nodes)
+    }
+
+    // The devices on one node that pass a filter, e.g. every NIC on node 1 with
+    // sysfs.devices_on_node(1, |device| device.class == 0x02)
+    pub fn devices_on_node<F: Fn(&PciDevice) -> bool>(&self, node: u32, filter: F) -> Result<Vec<PciDevice>, PciEnumerationError> {
+        Ok(self.devices_by_numa_node()?.remove(&Some(node)).unwrap_or_default().into_iter().filter(|device| filter(device)).collect())
+    }
+
+    // Keep work on the device's own node. Workers get isolated CPUs first, then the highest
+    // numbered local CPUs, leaving the low ones (where housekeeping tends to run) for
+    // interrupts. If the workers take every local CPU, interrupts share them.
+    pub fn recommend_pinning(&self, device: &PciDevice, workers: usize) -> Result<PinningRecommendation, PciEnumerationError> {
+        let affinity = self.device(device).affinity()?;
+        let online = self.online_cpus()?;
+        let local = match affinity.local_cpus.intersection(&online) {
+            local if local.is_empty() => online,
+            local => local,
+        };
+
+        let isolated = local.intersection(&self.isolated_cpus());
+        let others = local.difference(&isolated);
+        let worker_cpus: CpuSet = isolated.iter().chain(others.iter().rev()).take(workers).collect();
+
+        let interrupts = match local.difference(&worker_cpus).difference(&isolated) {
+            interrupts if interrupts.is_empty() => local.difference(&isolated),
+            interrupts => interrupts,
+        };
+
+        Ok(PinningRecommendation {
+            numa_node: affinity.numa_node,
+            interrupts: if interrupts.is_empty() { local } else { interrupts },
+            workers: worker_cpus,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::linux::fixture::SysfsFixture;
+    use crate::backend::linux::numa::CpuSet;
+
+    #[test]
+    fn test_cpu_sets() {
+        let list = CpuSet::parse_list("0-3,8,10-11\n").unwrap();
+        assert_eq!(list.len(), 7);
+        assert_eq!(list.to_string(), "0-3,8,10-11");
+
+        let mask = CpuSet::parse_mask("00000001,0000000f").unwrap();
+        assert_eq!(mask.to_string(), "0-3,32");
+    }
+
+    #[test]
+    fn test_pinning_on_numa_node() {
+        let fixture = SysfsFixture::new();
+        fixture.add_device("pci0000:00/0000:00:03.0", 0x8086, 0x2030, 0x060400);
+        fixture.add_device("pci0000:80/0000:80:02.0/0000:81:00.0", 0x15b3, 0x101d, 0x020000);
+        fixture.write("bus/pci/devices/0000:00:03.0/numa_node", "0\n");
+        fixture.write("bus/pci/devices/0000:81:00.0/numa_node", "1\n");
+        fixture.write("bus/pci/devices/0000:81:00.0/local_cpus", "0000ff00\n");
+        fixture.write("devices/system/cpu/online", "0-15\n");
+        fixture.write("devices/system/cpu/isolated", "12-13\n");
+
+        let sysfs = fixture.sysfs();
+        let nics = sysfs.devices_on_node(1, |device| device.class == 0x02).unwrap();
+        assert_eq!(nics.len(), 1);
+        assert!(sysfs.devices_on_node(0, |device| device.class == 0x02).unwrap().is_empty());
+
+        let pinning = sysfs.recommend_pinning(&nics[0], 4).unwrap();
+        assert_eq!(pinning.numa_node, Some(1));
+        assert_eq!(pinning.workers.to_string(), "12-15");
+        assert_eq!(pinning.interrupts.to_string(), "8-11");
+    }
+}
@@ -0,0 +1,82 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Peer to peer DMA information from sysfs.
+
+use crate::backend::common::{PciDevice, PciEnumerationError};
+use crate::backend::linux::sysfs::{Sysfs, SysfsDevice};
+use crate::pci::p2p::{p2p_reachability, P2pMemory, P2pReport};
+use crate::pci::topology::PciTopology;
+
+impl SysfsDevice {
+    // Devices registered with the kernel's p2pdma framework have a p2pmem directory.
+    pub fn p2pmem(&self) -> Option<P2pMemory> {
+        if !self.has_attribute("p2pmem") {
+            return None;
+        }
+        Some(P2pMemory {
+            size: self.read_attribute("p2pmem/size").ok()?.parse().ok()?,
+            available: self.read_attribute("p2pmem/available").ok()?.parse().ok()?,
+            published: self.read_attribute("p2pmem/published").ok()?.trim() == "1",
+        })
+    }
+}
+
+impl Sysfs {
+    // Reachability from a DMA client to a memory provider, with the provider's p2pmem filled in.
+    pub fn p2p_reachability(&self, topology: &PciTopology, client: &PciDevice, provider: &PciDevice) -> Result<P2pReport, PciEnumerationError> {
+        let from = topology.find_device(client).ok_or(PciEnumerationError::NotFound)?;
+        let to = topology.find_device(provider).ok_or(PciEnumerationError::NotFound)?;
+
+        let mut report = p2p_reachability(topology, from, to);
+        report.p2pmem = self.device(provider).p2pmem();
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::linux::fixture::SysfsFixture;
+    use crate::pci::p2p::P2pMemory;
+
+    #[test]
+    fn test_read_p2pmem() {
+        let fixture = SysfsFixture::new();
+        fixture.add_device("pci0000:00/0000:00:1d.0/0000:3b:00.0", 0x144d, 0xa824, 0x010802);
+        fixture.add_device("pci0000:00/0000:00:1d.0/0000:3c:00.0", 0x144d, 0xa824, 0x010802);
+        fixture.write("bus/pci/devices/0000:3b:00.0/p2pmem/size", "1073741824\n");
+        fixture.write("bus/pci/devices/0000:3b:00.0/p2pmem/available", "536870912\n");
+        fixture.write("bus/pci/devices/0000:3b:00.0/p2pmem/published", "1\n");
+
+        let sysfs = fixture.sysfs();
+        assert_eq!(
+            sysfs.device_by_address("0000:3b:00.0").p2pmem(),
+            Some(P2pMemory { size: 1 << 30, available: 1 << 29, published: true })
+        );
+        assert_eq!(sysfs.device_by_address("0000:3c:00.0").p2pmem(), None);
+    }
+}
@@ -0,0 +1,173 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Linux runtime power management state from sysfs.
+
+use core::fmt;
+use std::fmt::Display;
+use std::time::Duration;
+
+use crate::backend::common::PciEnumerationError;
+use crate::backend::linux::sysfs::SysfsDevice;
+use crate::pci::power::{PowerManagement, PowerState};
+
+// The values of power/control.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimePmControl {
+    Auto, // The device may be suspended when idle.
+    On, // The device is kept powered.
+}
+
+impl Display for RuntimePmControl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuntimePmControl::Auto => write!(f, "auto"),
+            RuntimePmControl::On => write!(f, "on"),
+        }
+    }
+}
+
+// The values of power/runtime_status.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuntimeStatus {
+    Active,
+    Suspended,
+    Suspending,
+    Resuming,
+    Error,
+    Unsupported,
+    Other(String),
+}
+
+impl From<&str> for RuntimeStatus {
+    fn from(status: &str) -> Self {
+        match status {
+            "active" => RuntimeStatus::Active,
+            "suspended" => RuntimeStatus::Suspended,
+            "suspending" => RuntimeStatus::Suspending,
+            "resuming" => RuntimeStatus::Resuming,
+            "error" => RuntimeStatus::Error,
+            "unsupported" => RuntimeStatus::Unsupported,
+            other => RuntimeStatus::Other(other.to_string()),
+        }
+    }
+}
+
+// Everything the kernel reports about a device's power. Attributes the kernel doesn't
+// provide, or that can't be read, are None.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DevicePower {
+    pub capability: Option<PowerManagement>, // Needs root, as the capability is past 64 bytes.
+    pub power_state: Option<PowerState>,
+    pub d3cold_allowed: Option<bool>,
+    pub control: Option<RuntimePmControl>,
+    pub runtime_status: Option<RuntimeStatus>,
+    pub runtime_active_time: Option<Duration>,
+    pub runtime_suspended_time: Option<Duration>,
+}
+
+impl DevicePower {
+    // Runtime PM is off, so the device stays powered however idle it is.
+    pub fn never_suspends(&self) -> bool {
+        self.control == Some(RuntimePmControl::On)
+    }
+
+    // The share of time spent suspended since the counters started.
+    pub fn suspended_fraction(&self) -> Option<f64> {
+        let active = self.runtime_active_time?.as_secs_f64();
+        let suspended = self.runtime_suspended_time?.as_secs_f64();
+        (active + suspended > 0.0).then(|| suspended / (active + suspended))
+    }
+}
+
+impl SysfsDevice {
+    fn read_milliseconds(&self, attribute: &str) -> Option<Duration> {
+        Some(Duration::from_millis(self.read_attribute(attribute).ok()?.parse().ok()?))
+    }
+
+    pub fn power(&self) -> DevicePower {
+        let control = match self.read_attribute("power/control").ok().as_deref() {
+            Some("auto") => Some(RuntimePmControl::Auto),
+            Some("on") => Some(RuntimePmControl::On),
+            _ => None,
+        };
+
+        DevicePower {
+            capability: self.config_space().ok().and_then(|config| config.power_management()),
+            power_state: self.read_attribute("power_state").ok().map(|state| PowerState::from_name(&state)),
+            d3cold_allowed: self.read_attribute("d3cold_allowed").ok().map(|allowed| allowed == "1"),
+            control,
+            runtime_status: self.read_attribute("power/runtime_status").ok().map(|status| RuntimeStatus::from(status.as_str())),
+            runtime_active_time: self.read_milliseconds("power/runtime_active_time"),
+            runtime_suspended_time: self.read_milliseconds("power/runtime_suspended_time"),
+        }
+    }
+
+    pub fn set_runtime_pm(&self, control: RuntimePmControl) -> Result<(), PciEnumerationError> {
+        self.write_attribute("power/control", &control.to_string())
+    }
+
+    pub fn set_d3cold_allowed(&self, allowed: bool) -> Result<(), PciEnumerationError> {
+        self.write_attribute("d3cold_allowed", if allowed { "1" } else { "0" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::backend::linux::fixture::SysfsFixture;
+    use crate::backend::linux::power::{RuntimePmControl, RuntimeStatus};
+    use crate::pci::power::PowerState;
+
+    #[test]
+    fn test_runtime_power() {
+        let fixture = SysfsFixture::new();
+        fixture.add_device("pci0000:00/0000:00:1f.6", 0x8086, 0x15bc, 0x020000);
+        fixture.write("bus/pci/devices/0000:00:1f.6/power_state", "D0\n");
+        fixture.write("bus/pci/devices/0000:00:1f.6/d3cold_allowed", "1\n");
+        fixture.write("bus/pci/devices/0000:00:1f.6/power/control", "on\n");
+        fixture.write("bus/pci/devices/0000:00:1f.6/power/runtime_status", "active\n");
+        fixture.write("bus/pci/devices/0000:00:1f.6/power/runtime_active_time", "30000\n");
+        fixture.write("bus/pci/devices/0000:00:1f.6/power/runtime_suspended_time", "10000\n");
+
+        let sysfs = fixture.sysfs().with_write_log();
+        let device = sysfs.device_by_address("0000:00:1f.6");
+        let power = device.power();
+        assert_eq!(power.capability, None);
+        assert_eq!(power.power_state, Some(PowerState::D0));
+        assert_eq!(power.d3cold_allowed, Some(true));
+        assert_eq!(power.runtime_status, Some(RuntimeStatus::Active));
+        assert_eq!(power.runtime_active_time, Some(Duration::from_secs(30)));
+        assert_eq!(power.suspended_fraction(), Some(0.25));
+        assert!(power.never_suspends());
+
+        device.set_runtime_pm(RuntimePmControl::Auto).unwrap();
+        assert_eq!(device.power().control, Some(RuntimePmControl::Auto));
+        assert_eq!(sysfs.writes()[0].value(), "auto");
+    }
+}
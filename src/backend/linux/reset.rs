@@ -0,0 +1,214 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Resetting devices, and putting their config space back afterwards.
+
+use core::fmt;
+use std::fmt::Display;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::backend::common::{PciDevice, PciEnumerationError};
+use crate::backend::linux::sysfs::{Sysfs, SysfsDevice};
+use crate::pci::config::{CONFIG_BRIDGE_CONTROL, EXTENDED_CONFIG_START};
+use crate::pci::state::{RegisterMismatch, SavedRegister, SavedState};
+use crate::pci::topology::PciTopology;
+
+pub const BRIDGE_CONTROL_BUS_RESET: u16 = 0x0040;
+
+// How long the kernel holds secondary bus reset, and how long it waits for devices to recover.
+const BUS_RESET_ASSERT_TIME: Duration = Duration::from_millis(2);
+const BUS_RESET_RECOVERY_TIME: Duration = Duration::from_secs(1);
+
+// The names the kernel uses in reset_method.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResetMethod {
+    DeviceSpecific,
+    Acpi,
+    Flr,
+    AfFlr,
+    Pm,
+    Bus,
+    CxlBus,
+    Other(String),
+}
+
+impl From<&str> for ResetMethod {
+    fn from(name: &str) -> Self {
+        match name {
+            "device_specific" => ResetMethod::DeviceSpecific,
+            "acpi" => ResetMethod::Acpi,
+            "flr" => ResetMethod::Flr,
+            "af_flr" => ResetMethod::AfFlr,
+            "pm" => ResetMethod::Pm,
+            "bus" => ResetMethod::Bus,
+            "cxl_bus" => ResetMethod::CxlBus,
+            other => ResetMethod::Other(other.to_string()),
+        }
+    }
+}
+
+impl Display for ResetMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResetMethod::DeviceSpecific => write!(f, "device_specific"),
+            ResetMethod::Acpi => write!(f, "acpi"),
+            ResetMethod::Flr => write!(f, "flr"),
+            ResetMethod::AfFlr => write!(f, "af_flr"),
+            ResetMethod::Pm => write!(f, "pm"),
+            ResetMethod::Bus => write!(f, "bus"),
+            ResetMethod::CxlBus => write!(f, "cxl_bus"),
+            ResetMethod::Other(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+// What happened to a device's config space across a reset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResetOutcome {
+    pub address: String,
+    pub restored: Vec<SavedRegister>, // Registers that had lost their value and were written back.
+    pub mismatches: Vec<RegisterMismatch>, // Registers that still differ after restoring.
+}
+
+impl ResetOutcome {
+    pub fn is_clean(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+impl SysfsDevice {
+    // The methods the kernel will try, in order. Empty if resets are disabled for the device.
+    pub fn reset_methods(&self) -> Result<Vec<ResetMethod>, PciEnumerationError> {
+        Ok(self.read_attribute("reset_method")?.split_whitespace().map(ResetMethod::from).collect())
+    }
+
+    // An empty list disables resetting the device.
+    pub fn set_reset_methods(&self, methods: &[ResetMethod]) -> Result<(), PciEnumerationError> {
+        let methods: Vec<String> = methods.iter().map(ResetMethod::to_string).collect();
+        self.write_attribute("reset_method", &format!("{}\n", methods.join(" ")))
+    }
+
+    pub fn restore_default_reset_methods(&self) -> Result<(), PciEnumerationError> {
+        self.write_attribute("reset_method", "default")
+    }
+
+    // Capability registers live past the first 64 bytes, which only root can read.
+    pub fn save_state(&self) -> Result<SavedState, PciEnumerationError> {
+        let config = self.config_space()?;
+        if config.len() < EXTENDED_CONFIG_START {
+            return Err(PciEnumerationError::PermissionDenied);
+        }
+        Ok(SavedState::capture(&config))
+    }
+
+    // Write back every saved register that changed, like the kernel's pci_restore_state, then
+    // read config space again to check they all stuck.
+    pub fn restore_state(&self, state: &SavedState) -> Result<ResetOutcome, PciEnumerationError> {
+        let mut restored = Vec::new();
+        for mismatch in state.differences(&self.config_space()?) {
+            let register = mismatch.register;
+            self.write_config_register(register.offset, register.width, register.value)?;
+            restored.push(register);
+        }
+
+        Ok(ResetOutcome {
+            address: self.address().to_string(),
+            restored,
+            mismatches: state.differences(&self.config_space()?),
+        })
+    }
+
+    // Reset through the kernel's reset attribute, using the first method in reset_method that
+    // works. The kernel saves and restores state itself; doing it here as well catches anything
+    // it missed and verifies the result.
+    pub fn reset(&self) -> Result<ResetOutcome, PciEnumerationError> {
+        let state = self.save_state()?;
+        self.write_attribute("reset", "1")?;
+        self.restore_state(&state)
+    }
+}
+
+impl Sysfs {
+    // Pulse secondary bus reset on the bridge above a device. This resets every device below
+    // that bridge, so all of them are saved beforehand and restored top down afterwards.
+    pub fn secondary_bus_reset(&self, topology: &PciTopology, device: &PciDevice) -> Result<Vec<ResetOutcome>, PciEnumerationError> {
+        let id = topology.find_device(device).ok_or(PciEnumerationError::NotFound)?;
+        let bridge = topology.parent(id).ok_or(PciEnumerationError::NotFound)?;
+        let bridge_device = self.device(&topology.node(bridge).device);
+
+        let mut states = Vec::new();
+        for below in topology.descendants(bridge) {
+            let sysfs_device = self.device(&topology.node(below).device);
+            states.push((sysfs_device.save_state()?, sysfs_device));
+        }
+
+        let control = bridge_device.config_space()?.read_u16(CONFIG_BRIDGE_CONTROL).ok_or(PciEnumerationError::NotFound)?;
+        bridge_device.write_config_register(CONFIG_BRIDGE_CONTROL, 2, (control | BRIDGE_CONTROL_BUS_RESET) as u32)?;
+        sleep(BUS_RESET_ASSERT_TIME);
+        bridge_device.write_config_register(CONFIG_BRIDGE_CONTROL, 2, (control & !BRIDGE_CONTROL_BUS_RESET) as u32)?;
+        sleep(BUS_RESET_RECOVERY_TIME);
+
+        states.iter().map(|(state, sysfs_device)| sysfs_device.restore_state(state)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::linux::fixture::SysfsFixture;
+    use crate::backend::linux::reset::ResetMethod;
+
+    #[test]
+    fn test_reset_restores_state() {
+        let fixture = SysfsFixture::new();
+        fixture.add_device("pci0000:00/0000:00:02.0", 0x8086, 0x3e92, 0x030000);
+        fixture.write("bus/pci/devices/0000:00:02.0/reset_method", "flr bus\n");
+        fixture.write("bus/pci/devices/0000:00:02.0/reset", "");
+
+        let mut config = vec![0u8; 256];
+        config[0x04] = 0x07; // I/O, memory and bus mastering enabled.
+        config[0x10..0x14].copy_from_slice(&0xF6000000u32.to_le_bytes());
+        let config_path = fixture.write("bus/pci/devices/0000:00:02.0/config", &config);
+
+        let sysfs = fixture.sysfs().with_write_log();
+        let device = sysfs.device_by_address("0000:00:02.0");
+        assert_eq!(device.reset_methods().unwrap(), vec![ResetMethod::Flr, ResetMethod::Bus]);
+
+        // Nothing changes across a reset in the fixture, so nothing needs restoring.
+        let outcome = device.reset().unwrap();
+        assert!(outcome.is_clean());
+        assert!(outcome.restored.is_empty());
+        assert_eq!(sysfs.writes().len(), 1);
+
+        // Simulate a reset that cleared the command register and BAR 0.
+        let state = device.save_state().unwrap();
+        std::fs::write(&config_path, vec![0u8; 256]).unwrap();
+        let outcome = device.restore_state(&state).unwrap();
+        assert!(outcome.is_clean());
+        assert_eq!(outcome.restored.iter().map(|register| register.offset).collect::<Vec<usize>>(), vec![0x10, 0x04]);
+    }
+}
@@ -0,0 +1,52 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Reading expansion ROMs through the sysfs rom attribute.
+
+use crate::backend::common::PciEnumerationError;
+use crate::backend::linux::sysfs::SysfsDevice;
+use crate::pci::rom::ExpansionRom;
+
+impl SysfsDevice {
+    // The kernel only allows reading the rom attribute after "1" has been written to it, and it
+    // should be switched back off with "0" afterwards. This needs root.
+    pub fn read_rom(&self) -> Result<Vec<u8>, PciEnumerationError> {
+        self.write_attribute("rom", "1")?;
+        let contents = self.read_attribute_bytes("rom");
+
+        // Disable the ROM again even if reading it failed.
+        let disabled = self.write_attribute("rom", "0");
+        let contents = contents?;
+        disabled?;
+
+        Ok(contents)
+    }
+
+    pub fn expansion_rom(&self) -> Result<ExpansionRom, PciEnumerationError> {
+        ExpansionRom::parse(&self.read_rom()?)
+    }
+}
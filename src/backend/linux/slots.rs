@@ -0,0 +1,316 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Physical PCI slots as described by /sys/bus/pci/slots, plus rescan, removal and hotplug power.
+
+use core::fmt;
+use std::fmt::Display;
+use std::fs::{read_dir, read_to_string};
+use std::path::{Path, PathBuf};
+
+use crate::backend::common::{PciDevice, PciEnumerationError};
+use crate::backend::linux::sysfs::{Sysfs, SysfsDevice};
+use crate::pci::smbios::{SmbiosLocation, SmbiosTables};
+use crate::pci::topology::PciTopology;
+
+// Where the kernel exposes the raw SMBIOS structure table, relative to the sysfs root.
+pub const SMBIOS_TABLE: &str = "firmware/dmi/tables/DMI";
+
+// The attention indicator values understood by the hotplug drivers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttentionIndicator {
+    Off,
+    On,
+    Blink,
+    Other(u8),
+}
+
+impl From<u8> for AttentionIndicator {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => AttentionIndicator::Off,
+            1 => AttentionIndicator::On,
+            2 => AttentionIndicator::Blink,
+            other => AttentionIndicator::Other(other),
+        }
+    }
+}
+
+impl From<AttentionIndicator> for u8 {
+    fn from(indicator: AttentionIndicator) -> Self {
+        match indicator {
+            AttentionIndicator::Off => 0,
+            AttentionIndicator::On => 1,
+            AttentionIndicator::Blink => 2,
+            AttentionIndicator::Other(value) => value,
+        }
+    }
+}
+
+// A slot directory. Only slots driven by a hotplug driver have power, attention and latch files,
+// so those are None for plain ACPI slot descriptions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PciSlot {
+    pub name: String,
+    pub path: PathBuf,
+    pub address: Option<String>, // domain:bus:device, with no function.
+    pub power: Option<bool>,
+    pub attention: Option<AttentionIndicator>,
+    pub latch: Option<bool>, // true when the retention latch is closed.
+    pub adapter: Option<bool>, // true when a card is present.
+}
+
+impl PciSlot {
+    pub fn is_hotplug_capable(&self) -> bool {
+        self.power.is_some()
+    }
+
+    // Whether a device sits in this slot. Slots hold every function of a card, so the
+    // function number is ignored.
+    pub fn contains(&self, device: &PciDevice) -> bool {
+        let wanted = format!("{:04x}:{:02x}:{:02x}", device.domain, device.bus, device.device);
+        self.address.as_deref() == Some(wanted.as_str())
+    }
+}
+
+// A device's physical location, from the kernel's slot list, the SMBIOS tables or both.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhysicalLocation {
+    pub slot: Option<PciSlot>,
+    pub smbios: Option<SmbiosLocation>,
+}
+
+// Prefers the SMBIOS description, which carries the name printed on the board.
+impl Display for PhysicalLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.smbios, &self.slot) {
+            (Some(smbios), _) => write!(f, "{}", smbios),
+            (None, Some(slot)) => write!(f, "Slot {} / Occupied", slot.name),
+            (None, None) => write!(f, "Unknown"),
+        }
+    }
+}
+
+// Read a 0 or 1 slot attribute. Missing files and anything unreadable come back as None.
+fn read_slot_flag(slot: &Path, attribute: &str) -> Option<bool> {
+    read_slot_value(slot, attribute).map(|value| value != 0)
+}
+
+fn read_slot_value(slot: &Path, attribute: &str) -> Option<u8> {
+    read_to_string(slot.join(attribute)).ok()?.trim().parse().ok()
+}
+
+impl Sysfs {
+    // Ask the kernel to rescan every PCI bus for new devices.
+    pub fn rescan(&self) -> Result<(), PciEnumerationError> {
+        self.write("bus/pci/rescan", "1")
+    }
+
+    pub fn slots(&self) -> Result<Vec<PciSlot>, PciEnumerationError> {
+        let mut slots = Vec::new();
+        for entry in read_dir(self.root().join("bus/pci/slots"))?.flatten() {
+            let path = entry.path();
+            slots.push(PciSlot {
+                name: entry.file_name().to_string_lossy().to_string(),
+                address: read_to_string(path.join("address")).ok().map(|address| address.trim().to_string()),
+                power: read_slot_flag(&path, "power"),
+                attention: read_slot_value(&path, "attention").map(AttentionIndicator::from),
+                latch: read_slot_flag(&path, "latch"),
+                adapter: read_slot_flag(&path, "adapter"),
+                path,
+            });
+        }
+        slots.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(slots)
+    }
+
+    pub fn slot(&self, name: &str) -> Result<PciSlot, PciEnumerationError> {
+        self.slots()?.into_iter().find(|slot| slot.name == name).ok_or(PciEnumerationError::NotFound)
+    }
+
+    // The name of the slot a device sits in, if the firmware described one.
+    pub fn physical_slot(&self, device: &PciDevice) -> Option<String> {
+        self.slots().ok()?.into_iter().find(|slot| slot.contains(device)).map(|slot| slot.name)
+    }
+
+    pub fn smbios(&self) -> Result<SmbiosTables, PciEnumerationError> {
+        SmbiosTables::load(self.root().join(SMBIOS_TABLE))
+    }
+
+    // Work out which slot a device is plugged into. The kernel's slot address points at the
+    // card, while SMBIOS may point at the card or the port above it. When SMBIOS has no
+    // address for the slot, its slot ID is matched against the kernel's slot name instead,
+    // since both normally come from the same ACPI slot number.
+    pub fn physical_location(&self, topology: &PciTopology, smbios: &SmbiosTables, device: &PciDevice) -> Option<PhysicalLocation> {
+        let id = topology.find_device(device)?;
+        let slots = self.slots().unwrap_or_default();
+        let slot = std::iter::once(id)
+            .chain(topology.ancestors(id))
+            .find_map(|node| slots.iter().find(|slot| slot.contains(&topology.node(node).device)))
+            .cloned();
+
+        let smbios = smbios.locate(topology, id).or_else(|| {
+            let slot_id: u16 = slot.as_ref()?.name.parse().ok()?;
+            smbios.system_slots().into_iter().find(|smbios_slot| smbios_slot.slot_id == slot_id).map(SmbiosLocation::Slot)
+        });
+
+        match (&slot, &smbios) {
+            (None, None) => None,
+            _ => Some(PhysicalLocation { slot, smbios }),
+        }
+    }
+
+    // Every device with a known physical location.
+    pub fn physical_locations(&self, topology: &PciTopology, smbios: &SmbiosTables) -> Vec<(PciDevice, PhysicalLocation)> {
+        topology
+            .nodes()
+            .iter()
+            .filter_map(|node| Some((node.device.clone(), self.physical_location(topology, smbios, &node.device)?)))
+            .collect()
+    }
+
+    // Every function currently present in a slot.
+    pub fn slot_devices(&self, slot: &PciSlot) -> Result<Vec<SysfsDevice>, PciEnumerationError> {
+        Ok(self.pci_list()?.iter().filter(|device| slot.contains(device)).map(|device| self.device(device)).collect())
+    }
+
+    pub fn set_slot_attention(&self, slot: &PciSlot, indicator: AttentionIndicator) -> Result<(), PciEnumerationError> {
+        self.write(&format!("bus/pci/slots/{}/attention", slot.name), &u8::from(indicator).to_string())
+    }
+
+    pub fn power_on_slot(&self, slot: &PciSlot) -> Result<(), PciEnumerationError> {
+        if !slot.is_hotplug_capable() {
+            return Err(PciEnumerationError::NoSlotPowerControl(slot.name.clone()));
+        }
+        self.write(&format!("bus/pci/slots/{}/power", slot.name), "1")
+    }
+
+    // Remove every function in the slot from the kernel before cutting power, so drivers are
+    // detached in an orderly way rather than finding the card gone underneath them.
+    pub fn power_off_slot(&self, slot: &PciSlot) -> Result<(), PciEnumerationError> {
+        if !slot.is_hotplug_capable() {
+            return Err(PciEnumerationError::NoSlotPowerControl(slot.name.clone()));
+        }
+
+        for device in self.slot_devices(slot)? {
+            device.remove()?;
+        }
+        self.write(&format!("bus/pci/slots/{}/power", slot.name), "0")
+    }
+}
+
+impl SysfsDevice {
+    // Detach drivers and delete the device from the kernel. It comes back on the next rescan.
+    pub fn remove(&self) -> Result<(), PciEnumerationError> {
+        self.write_attribute("remove", "1")
+    }
+
+    // Rescan the device, or for a bridge everything below it. Older kernels call the
+    // attribute dev_rescan.
+    pub fn rescan(&self) -> Result<(), PciEnumerationError> {
+        if !self.has_attribute("rescan") && self.has_attribute("dev_rescan") {
+            return self.write_attribute("dev_rescan", "1");
+        }
+        self.write_attribute("rescan", "1")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::backend::common::PciEnumerationError;
+    use crate::backend::linux::fixture::SysfsFixture;
+    use crate::backend::linux::slots::AttentionIndicator;
+    use crate::pci::smbios::SmbiosTables;
+
+    #[test]
+    fn test_slot_power_off() {
+        let fixture = SysfsFixture::new();
+        fixture.add_device("pci0000:00/0000:00:1d.0", 0x8086, 0xa330, 0x060400);
+        fixture.add_device("pci0000:00/0000:00:1d.0/0000:03:00.0", 0x144d, 0xa808, 0x010802);
+        fixture.write("devices/pci0000:00/0000:00:1d.0/0000:03:00.0/remove", "");
+        fixture.write("devices/pci0000:00/0000:00:1d.0/dev_rescan", "");
+        fixture.write("bus/pci/slots/4/address", "0000:03:00\n");
+        fixture.write("bus/pci/slots/4/power", "1\n");
+        fixture.write("bus/pci/slots/4/attention", "2\n");
+        fixture.write("bus/pci/slots/4/latch", "1\n");
+        fixture.write("bus/pci/slots/7/address", "0000:05:00\n");
+
+        let sysfs = fixture.sysfs().with_write_log();
+        let slots = sysfs.slots().unwrap();
+        assert_eq!(slots.len(), 2);
+        assert_eq!(slots[0].attention, Some(AttentionIndicator::Blink));
+        assert_eq!(slots[0].latch, Some(true));
+        assert_eq!(slots[0].adapter, None);
+        assert!(!slots[1].is_hotplug_capable());
+
+        let drive = sysfs.pci_list().unwrap().into_iter().find(|device| device.bus == 3).unwrap();
+        assert_eq!(sysfs.physical_slot(&drive).as_deref(), Some("4"));
+
+        sysfs.power_off_slot(&slots[0]).unwrap();
+        assert!(matches!(sysfs.power_off_slot(&slots[1]), Err(PciEnumerationError::NoSlotPowerControl(name)) if name == slots[1].name));
+        assert!(matches!(sysfs.power_on_slot(&slots[1]), Err(PciEnumerationError::NoSlotPowerControl(name)) if name == slots[1].name));
+        sysfs.device_by_address("0000:00:1d.0").rescan().unwrap();
+
+        let writes: Vec<(PathBuf, String)> = sysfs.writes().into_iter().map(|write| (write.path.clone(), write.value())).collect();
+        assert_eq!(
+            writes,
+            vec![
+                (PathBuf::from("bus/pci/devices/0000:03:00.0/remove"), "1".to_string()),
+                (PathBuf::from("bus/pci/slots/4/power"), "0".to_string()),
+                (PathBuf::from("bus/pci/devices/0000:00:1d.0/dev_rescan"), "1".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_physical_location_by_slot_id() {
+        let fixture = SysfsFixture::new();
+        fixture.add_device("pci0000:00/0000:00:1c.0", 0x8086, 0xa338, 0x060400);
+        fixture.add_device("pci0000:00/0000:00:1c.0/0000:02:00.0", 0x10de, 0x2204, 0x030000);
+        fixture.write("bus/pci/slots/3/address", "0000:02:00\n");
+
+        // A PCIe x16 slot with slot ID 3 and no bus address, then the end of table.
+        let mut table = vec![9, 0x0D, 0x01, 0x00, 1, 0xAA, 0x0D, 0x04, 0x04, 0x03, 0x00, 0x0C, 0x01];
+        table.extend_from_slice(b"PCIE3\0\0");
+        table.extend_from_slice(&[127, 4, 0x02, 0x00, 0, 0]);
+        fixture.write("firmware/dmi/tables/DMI", &table);
+
+        let sysfs = fixture.sysfs();
+        let smbios = sysfs.smbios().unwrap();
+        let topology = sysfs.topology().unwrap();
+        let locations = sysfs.physical_locations(&topology, &smbios);
+        assert_eq!(locations.len(), 1);
+        assert_eq!(locations[0].0.bus, 2);
+        assert_eq!(locations[0].1.to_string(), "PCIE3 / PCIe x16 / Occupied");
+
+        // Without SMBIOS only the kernel's slot name is known.
+        let location = sysfs.physical_location(&topology, &SmbiosTables::default(), &locations[0].0).unwrap();
+        assert_eq!(location.to_string(), "Slot 3 / Occupied");
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Access to the Linux sysfs attributes of PCI devices.
+
+use core::fmt;
+use std::fs::{read, read_to_string, write, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::backend::common::{PciDevice, PciEnumerationError};
+use crate::backend::linux::get_pci_list_from;
+
+// Where sysfs is mounted on a normal system.
+pub const SYSFS_ROOT: &str = "/sys";
+
+// A write made through a Sysfs handle, with the path relative to the sysfs root. Text
+// attributes are written whole; binary ones like config are written at an offset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SysfsWrite {
+    pub path: PathBuf,
+    pub offset: Option<u64>,
+    pub data: Vec<u8>,
+}
+
+impl SysfsWrite {
+    pub fn value(&self) -> String {
+        String::from_utf8_lossy(&self.data).to_string()
+    }
+}
+
+// Shared between a Sysfs handle and the device handles it hands out.
+type WriteLog = Arc<Mutex<Vec<SysfsWrite>>>;
+
+// Run after each write lands, with the same record the log gets.
+#[derive(Clone)]
+struct WriteHook(Arc<dyn Fn(&SysfsWrite) + Send + Sync>);
+
+impl fmt::Debug for WriteHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WriteHook")
+    }
+}
+
+// The root of a sysfs tree. Tests point this at a fixture directory instead of /sys.
+#[derive(Debug, Clone)]
+pub struct Sysfs {
+    root: PathBuf,
+    write_log: Option<WriteLog>,
+    write_hook: Option<WriteHook>,
+}
+
+impl Default for Sysfs {
+    fn default() -> Self {
+        Sysfs::with_root(SYSFS_ROOT)
+    }
+}
+
+impl Sysfs {
+    pub fn new() -> Self {
+        Sysfs::default()
+    }
+
+    pub fn with_root<P: AsRef<Path>>(root: P) -> Self {
+        Sysfs {
+            root: root.as_ref().to_path_buf(),
+            write_log: None,
+            write_hook: None,
+        }
+    }
+
+    // Keep a record of every write made through this handle and the devices it hands out.
+    pub fn with_write_log(mut self) -> Self {
+        self.write_log = Some(WriteLog::default());
+        self
+    }
+
+    // Call a function after every successful write through this handle and the devices it
+    // hands out, e.g. for a fixture to move driver links the way the kernel would.
+    pub fn with_write_hook<F: Fn(&SysfsWrite) + Send + Sync + 'static>(mut self, hook: F) -> Self {
+        self.write_hook = Some(WriteHook(Arc::new(hook)));
+        self
+    }
+
+    // Everything written so far, oldest first. Empty unless with_write_log was used.
+    pub fn writes(&self) -> Vec<SysfsWrite> {
+        match &self.write_log {
+            Some(log) => log.lock().unwrap().clone(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // Write to a file relative to the root, e.g. bus/pci/rescan
+    pub fn write(&self, relative: &str, value: &str) -> Result<(), PciEnumerationError> {
+        write_logged(&self.root, self.write_log.as_ref(), self.write_hook.as_ref(), &self.root.join(relative), None, value.as_bytes())
+    }
+
+    // Enumerate the devices under this root, the same way get_pci_list does for /sys.
+    pub fn pci_list(&self) -> Result<Vec<PciDevice>, PciEnumerationError> {
+        get_pci_list_from(&self.root.join("bus/pci/devices"))
+    }
+
+    // The sysfs directory of a device, e.g. /sys/bus/pci/devices/0000:00:1c.0
+    pub fn device(&self, device: &PciDevice) -> SysfsDevice {
+        self.device_by_address(&device.address())
+    }
+
+    pub fn device_by_address(&self, address: &str) -> SysfsDevice {
+        SysfsDevice {
+            address: address.to_string(),
+            path: self.root.join("bus/pci/devices").join(address),
+            root: self.root.clone(),
+            write_log: self.write_log.clone(),
+            write_hook: self.write_hook.clone(),
+        }
+    }
+}
+
+// A single device's directory in sysfs.
+#[derive(Debug, Clone)]
+pub struct SysfsDevice {
+    address: String,
+    path: PathBuf,
+    root: PathBuf,
+    write_log: Option<WriteLog>,
+    write_hook: Option<WriteHook>,
+}
+
+impl SysfsDevice {
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn has_attribute(&self, attribute: &str) -> bool {
+        self.path.join(attribute).exists()
+    }
+
+    // Read a text attribute with the trailing newline removed.
+    pub fn read_attribute(&self, attribute: &str) -> Result<String, PciEnumerationError> {
+        let contents = read_to_string(self.path.join(attribute))?;
+        Ok(contents.trim_end().to_string())
+    }
+
+    // Read a binary attribute such as config, rom or vpd.
+    pub fn read_attribute_bytes(&self, attribute: &str) -> Result<Vec<u8>, PciEnumerationError> {
+        Ok(read(self.path.join(attribute))?)
+    }
+
+    pub fn write_attribute(&self, attribute: &str, value: &str) -> Result<(), PciEnumerationError> {
+        write_logged(&self.root, self.write_log.as_ref(), self.write_hook.as_ref(), &self.path.join(attribute), None, value.as_bytes())
+    }
+
+    // Write into a binary attribute such as config without touching the rest of it.
+    pub fn write_attribute_bytes_at(&self, attribute: &str, offset: u64, data: &[u8]) -> Result<(), PciEnumerationError> {
+        write_logged(&self.root, self.write_log.as_ref(), self.write_hook.as_ref(), &self.path.join(attribute), Some(offset), data)
+    }
+}
+
+fn write_logged(root: &Path, write_log: Option<&WriteLog>, write_hook: Option<&WriteHook>, path: &Path, offset: Option<u64>, data: &[u8]) -> Result<(), PciEnumerationError> {
+    let record = SysfsWrite {
+        path: path.strip_prefix(root).unwrap_or(path).to_path_buf(),
+        offset,
+        data: data.to_vec(),
+    };
+
+    // Record the attempt before making it, so failed writes show up too.
+    if let Some(log) = write_log {
+        log.lock().unwrap().push(record.clone());
+    }
+
+    match offset {
+        Some(offset) => OpenOptions::new().write(true).open(path)?.write_all_at(data, offset)?,
+        None => write(path, data)?,
+    }
+
+    if let Some(hook) = write_hook {
+        (hook.0)(&record);
+    }
+    Ok(())
+}
@@ -0,0 +1,105 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Building the PCI topology from the sysfs device hierarchy.
+
+use std::fs::canonicalize;
+
+use crate::backend::common::PciEnumerationError;
+use crate::backend::linux::sysfs::{Sysfs, SysfsDevice};
+use crate::pci::topology::{PciTopology, TopologyDevice};
+
+impl SysfsDevice {
+    // /sys/bus/pci/devices/<address> links into /sys/devices, where each device directory sits
+    // inside its parent bridge's, e.g. /sys/devices/pci0000:00/0000:00:1c.0/0000:02:00.0
+    // Returns (parent address, host bridge name); only one of them is set.
+    pub fn hierarchy_parent(&self) -> (Option<String>, Option<String>) {
+        let path = match canonicalize(self.path()) {
+            Ok(path) => path,
+            Err(_) => return (None, None),
+        };
+        let parent = match path.parent().and_then(|parent| parent.file_name()) {
+            Some(parent) => parent.to_string_lossy().to_string(),
+            None => return (None, None),
+        };
+
+        if is_pci_address(&parent) {
+            (Some(parent), None)
+        } else if parent.starts_with("pci") {
+            (None, Some(parent))
+        } else {
+            (None, None)
+        }
+    }
+}
+
+impl Sysfs {
+    // Config space is read where possible so bridge bus numbers can fill in for a missing
+    // hierarchy, and so later analysis has the capabilities at hand.
+    pub fn topology(&self) -> Result<PciTopology, PciEnumerationError> {
+        let mut devices = Vec::new();
+
+        for device in self.pci_list()? {
+            let sysfs_device = self.device(&device);
+            let (parent_address, root_complex) = sysfs_device.hierarchy_parent();
+
+            devices.push(TopologyDevice {
+                config: sysfs_device.config_space().ok(),
+                device,
+                parent_address,
+                root_complex,
+            });
+        }
+
+        Ok(PciTopology::build(devices))
+    }
+}
+
+// Addresses look like 0000:00:1c.0
+fn is_pci_address(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    bytes.len() == 12 && bytes[4] == b':' && bytes[7] == b':' && bytes[10] == b'.'
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::linux::fixture::SysfsFixture;
+
+    #[test]
+    fn test_topology_from_sysfs_hierarchy() {
+        let fixture = SysfsFixture::new();
+        fixture.add_device("pci0000:00/0000:00:1c.0", 0x8086, 0xa110, 0x060400);
+        fixture.add_device("pci0000:00/0000:00:1c.0/0000:02:00.0", 0x10de, 0x2204, 0x030000);
+        fixture.add_device("pci0000:00/0000:00:1f.0", 0x8086, 0xa305, 0x060100);
+
+        let topology = fixture.sysfs().topology().unwrap();
+        let gpu = topology.find("0000:02:00.0").unwrap();
+        assert_eq!(topology.parent(gpu), topology.find("0000:00:1c.0"));
+        assert_eq!(topology.root_complex(gpu), "pci0000:00");
+        assert_eq!(topology.roots().len(), 2);
+    }
+}
@@ -0,0 +1,296 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Detaching devices from their host drivers for VFIO passthrough, and putting them back.
+
+use std::path::{Path, PathBuf};
+
+use crate::backend::common::{PciDevice, PciEnumerationError};
+use crate::backend::linux::sysfs::Sysfs;
+use crate::pci::topology::PciTopology;
+
+pub const VFIO_PCI_DRIVER: &str = "vfio-pci";
+
+// Where VFIO puts the character device for each IOMMU group.
+pub const VFIO_DEVICE_ROOT: &str = "/dev/vfio";
+
+// How a device was bound before it was handed to vfio-pci.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OriginalBinding {
+    pub device: PciDevice,
+    pub driver: Option<String>,
+    pub driver_override: Option<String>,
+}
+
+// Every non-bridge function in an IOMMU group, bound to vfio-pci. The original drivers are
+// restored when this is dropped, unless persist() is called first.
+#[derive(Debug)]
+pub struct VfioDetach {
+    sysfs: Sysfs,
+    group: u32,
+    bindings: Vec<OriginalBinding>, // In the order they were detached.
+    finished: bool,
+}
+
+impl Sysfs {
+    // The sequence per function is: record the current binding, point driver_override at
+    // vfio-pci, unbind, reprobe, and check vfio-pci picked the device up. If any function
+    // fails, the ones already moved are restored before returning the error.
+    pub fn detach_for_passthrough(&self, topology: &PciTopology, device: &PciDevice) -> Result<VfioDetach, PciEnumerationError> {
+        if !self.root().join("bus/pci/drivers").join(VFIO_PCI_DRIVER).exists() {
+            // The vfio-pci module is not loaded.
+            return Err(PciEnumerationError::NotFound);
+        }
+
+        let group = self.device(device).iommu_group().ok_or(PciEnumerationError::NoIommuGroup)?;
+        let mut detach = VfioDetach {
+            sysfs: self.clone(),
+            group,
+            bindings: Vec::new(),
+            finished: false,
+        };
+
+        for address in self.iommu_group(group)?.devices {
+            let node = match topology.find(&address) {
+                Some(node) => topology.node(node),
+                None => return Err(PciEnumerationError::NotFound),
+            };
+            if node.is_bridge() {
+                continue;
+            }
+
+            // Errors drop detach, which restores whatever has been moved so far.
+            detach.detach(&node.device)?;
+        }
+
+        Ok(detach)
+    }
+}
+
+impl VfioDetach {
+    pub fn group(&self) -> u32 {
+        self.group
+    }
+
+    // What a VMM opens to use the devices, e.g. /dev/vfio/12
+    pub fn group_path(&self) -> PathBuf {
+        Path::new(VFIO_DEVICE_ROOT).join(self.group.to_string())
+    }
+
+    pub fn bindings(&self) -> &[OriginalBinding] {
+        &self.bindings
+    }
+
+    fn detach(&mut self, device: &PciDevice) -> Result<(), PciEnumerationError> {
+        let sysfs_device = self.sysfs.device(device);
+        let driver = sysfs_device.driver();
+        if driver.as_deref() == Some(VFIO_PCI_DRIVER) {
+            return Ok(());
+        }
+
+        self.bindings.push(OriginalBinding {
+            device: device.clone(),
+            driver,
+            driver_override: sysfs_device.driver_override()?,
+        });
+
+        sysfs_device.set_driver_override(VFIO_PCI_DRIVER)?;
+        sysfs_device.unbind()?;
+        self.sysfs.probe(device)?;
+
+        if sysfs_device.driver().as_deref() != Some(VFIO_PCI_DRIVER) {
+            return Err(PciEnumerationError::DriverBindFailed(device.address()));
+        }
+        Ok(())
+    }
+
+    // Leave the devices on vfio-pci after this is dropped, e.g. for a VM that outlives us.
+    pub fn persist(mut self) -> Vec<OriginalBinding> {
+        self.finished = true;
+        std::mem::take(&mut self.bindings)
+    }
+
+    // Put every device back on its original driver, newest first. Every device is attempted
+    // even if an earlier one fails; the first error is returned.
+    pub fn rollback(mut self) -> Result<(), PciEnumerationError> {
+        self.restore()
+    }
+
+    fn restore(&mut self) -> Result<(), PciEnumerationError> {
+        self.finished = true;
+        let mut result = Ok(());
+
+        while let Some(binding) = self.bindings.pop() {
+            let restored = restore_binding(&self.sysfs, &binding);
+            if result.is_ok() {
+                result = restored;
+            }
+        }
+
+        result
+    }
+}
+
+fn restore_binding(sysfs: &Sysfs, binding: &OriginalBinding) -> Result<(), PciEnumerationError> {
+    let sysfs_device = sysfs.device(&binding.device);
+
+    // The override has to go first, or the original driver would refuse to bind.
+    match &binding.driver_override {
+        Some(driver_override) => sysfs_device.set_driver_override(driver_override)?,
+        None => sysfs_device.clear_driver_override()?,
+    }
+
+    if sysfs_device.driver() == binding.driver {
+        return Ok(());
+    }
+    sysfs_device.unbind()?;
+
+    match &binding.driver {
+        Some(driver) => sysfs.bind(&binding.device, driver)?,
+        None => return Ok(()),
+    }
+
+    if sysfs_device.driver() != binding.driver {
+        return Err(PciEnumerationError::DriverBindFailed(binding.device.address()));
+    }
+    Ok(())
+}
+
+impl Drop for VfioDetach {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.restore();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::backend::common::PciEnumerationError;
+    use crate::backend::linux::fixture::SysfsFixture;
+    use crate::backend::linux::sysfs::Sysfs;
+
+    // vfio-pci refuses the device, so the detach has to undo itself.
+    #[test]
+    fn test_failed_detach_restores_binding() {
+        let fixture = SysfsFixture::new();
+        fixture.add_device("pci0000:00/0000:00:1f.6", 0x8086, 0x15bb, 0x020000);
+        fixture.add_driver("e1000e");
+        fixture.add_driver("vfio-pci");
+        fixture.write("bus/pci/drivers_probe", "");
+        fixture.write("bus/pci/devices/0000:00:1f.6/driver_override", "(null)\n");
+        fixture.link("bus/pci/devices/0000:00:1f.6/driver", "bus/pci/drivers/e1000e");
+        fixture.link("kernel/iommu_groups/3/devices/0000:00:1f.6", "bus/pci/devices/0000:00:1f.6");
+        fixture.link("bus/pci/devices/0000:00:1f.6/iommu_group", "kernel/iommu_groups/3");
+        fixture.emulate_driver_core();
+        fixture.fail_binding("0000:00:1f.6", "vfio-pci");
+
+        let sysfs = fixture.sysfs().with_write_log();
+        let topology = sysfs.topology().unwrap();
+        let nic = topology.node(0).device.clone();
+
+        match sysfs.detach_for_passthrough(&topology, &nic) {
+            Err(PciEnumerationError::DriverBindFailed(address)) => assert_eq!(address, "0000:00:1f.6"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let writes: Vec<(PathBuf, String)> = sysfs.writes().into_iter().map(|write| (write.path.clone(), write.value())).collect();
+        assert_eq!(writes, vec![
+            (PathBuf::from("bus/pci/devices/0000:00:1f.6/driver_override"), "vfio-pci".to_string()),
+            (PathBuf::from("bus/pci/devices/0000:00:1f.6/driver/unbind"), "0000:00:1f.6".to_string()),
+            (PathBuf::from("bus/pci/drivers_probe"), "0000:00:1f.6".to_string()),
+            (PathBuf::from("bus/pci/devices/0000:00:1f.6/driver_override"), "\n".to_string()),
+            (PathBuf::from("bus/pci/drivers/e1000e/bind"), "0000:00:1f.6".to_string()),
+        ]);
+        assert_eq!(sysfs.device(&nic).driver().as_deref(), Some("e1000e"));
+    }
+
+    // A GPU and its audio function sharing IOMMU group 12, with the kernel's binding emulated.
+    fn gpu_group() -> SysfsFixture {
+        let fixture = SysfsFixture::new();
+        fixture.add_device("pci0000:00/0000:00:01.0", 0x8086, 0xa70d, 0x060400);
+        fixture.add_device("pci0000:00/0000:00:01.0/0000:01:00.0", 0x10de, 0x2684, 0x030000);
+        fixture.add_device("pci0000:00/0000:00:01.0/0000:01:00.1", 0x10de, 0x22ba, 0x040300);
+        for driver in ["nvidia", "snd_hda_intel", "vfio-pci"] {
+            fixture.add_driver(driver);
+        }
+        fixture.write("bus/pci/drivers_probe", "");
+        for (address, driver) in [("0000:01:00.0", "nvidia"), ("0000:01:00.1", "snd_hda_intel")] {
+            fixture.write(&format!("bus/pci/devices/{}/driver_override", address), "(null)\n");
+            fixture.link(&format!("bus/pci/devices/{}/driver", address), &format!("bus/pci/drivers/{}", driver));
+            fixture.link(&format!("kernel/iommu_groups/12/devices/{}", address), &format!("bus/pci/devices/{}", address));
+            fixture.link(&format!("bus/pci/devices/{}/iommu_group", address), "kernel/iommu_groups/12");
+        }
+        fixture.emulate_driver_core();
+        fixture
+    }
+
+    fn drivers(sysfs: &Sysfs) -> Vec<(Option<String>, Option<String>)> {
+        ["0000:01:00.0", "0000:01:00.1"].iter()
+            .map(|address| {
+                let device = sysfs.device_by_address(address);
+                (device.driver(), device.driver_override().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_detach_whole_group() {
+        let fixture = gpu_group();
+        let sysfs = fixture.sysfs();
+        let topology = sysfs.topology().unwrap();
+        let gpu = topology.node(topology.find("0000:01:00.0").unwrap()).device.clone();
+
+        let detach = sysfs.detach_for_passthrough(&topology, &gpu).unwrap();
+        assert_eq!(detach.group_path(), PathBuf::from("/dev/vfio/12"));
+        let originals: Vec<Option<&str>> = detach.bindings().iter().map(|binding| binding.driver.as_deref()).collect();
+        assert_eq!(originals, vec![Some("nvidia"), Some("snd_hda_intel")]);
+        let vfio = (Some("vfio-pci".to_string()), Some("vfio-pci".to_string()));
+        assert_eq!(drivers(&sysfs), vec![vfio.clone(), vfio]);
+
+        detach.rollback().unwrap();
+        assert_eq!(drivers(&sysfs), vec![(Some("nvidia".to_string()), None), (Some("snd_hda_intel".to_string()), None)]);
+    }
+
+    #[test]
+    fn test_failed_sibling_restores_group() {
+        let fixture = gpu_group();
+        fixture.fail_binding("0000:01:00.1", "vfio-pci");
+        let sysfs = fixture.sysfs();
+        let topology = sysfs.topology().unwrap();
+        let gpu = topology.node(topology.find("0000:01:00.0").unwrap()).device.clone();
+
+        // The GPU moves to vfio-pci before its audio function fails, and both go back.
+        match sysfs.detach_for_passthrough(&topology, &gpu) {
+            Err(PciEnumerationError::DriverBindFailed(address)) => assert_eq!(address, "0000:01:00.1"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(drivers(&sysfs), vec![(Some("nvidia".to_string()), None), (Some("snd_hda_intel".to_string()), None)]);
+    }
+}
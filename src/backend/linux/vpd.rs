@@ -23,4 +23,17 @@
 // SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
 // CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
 // OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
-// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
\ No newline at end of file
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Reading Vital Product Data through the sysfs vpd attribute.
+
+use crate::backend::common::PciEnumerationError;
+use crate::backend::linux::sysfs::SysfsDevice;
+use crate::pci::vpd::Vpd;
+
+impl SysfsDevice {
+    // Devices without VPD have no vpd attribute at all, which shows up as NotFound.
+    pub fn vpd(&self) -> Result<Vpd, PciEnumerationError> {
+        Vpd::parse(&self.read_attribute_bytes("vpd")?)
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Decoding of the Access Control Services (ACS) extended capability.
+
+use crate::pci::config::{ConfigSpace, ECAP_ID_ACS};
+
+// Offsets into the ACS capability.
+pub const ACS_CAPABILITIES: usize = 0x04;
+pub const ACS_CONTROL: usize = 0x06;
+
+// Bits shared by the capability and control registers.
+pub const ACS_SOURCE_VALIDATION: u16 = 0x0001;
+pub const ACS_TRANSLATION_BLOCKING: u16 = 0x0002;
+pub const ACS_REQUEST_REDIRECT: u16 = 0x0004;
+pub const ACS_COMPLETION_REDIRECT: u16 = 0x0008;
+pub const ACS_UPSTREAM_FORWARDING: u16 = 0x0010;
+pub const ACS_EGRESS_CONTROL: u16 = 0x0020;
+pub const ACS_DIRECT_TRANSLATED: u16 = 0x0040;
+
+// What Linux requires of a port before it will put the devices below it in separate IOMMU groups.
+pub const ACS_ISOLATION_FLAGS: u16 = ACS_SOURCE_VALIDATION | ACS_REQUEST_REDIRECT | ACS_COMPLETION_REDIRECT | ACS_UPSTREAM_FORWARDING;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Acs {
+    pub offset: usize,
+    pub capabilities: u16,
+    pub control: u16,
+}
+
+impl Acs {
+    pub fn find(config: &ConfigSpace) -> Option<Acs> {
+        let offset = config.find_extended_capability(ECAP_ID_ACS)?;
+        Some(Acs {
+            offset,
+            capabilities: config.read_u16(offset + ACS_CAPABILITIES)?,
+            control: config.read_u16(offset + ACS_CONTROL)?,
+        })
+    }
+
+    pub fn supports(&self, flags: u16) -> bool {
+        self.capabilities & flags == flags
+    }
+
+    pub fn enabled(&self, flags: u16) -> bool {
+        self.control & flags == flags
+    }
+
+    // Whether peer to peer requests or completions entering this port get sent upstream to
+    // the root complex instead of straight to their target.
+    pub fn redirects_p2p(&self) -> bool {
+        self.control & (ACS_REQUEST_REDIRECT | ACS_COMPLETION_REDIRECT | ACS_EGRESS_CONTROL) != 0
+    }
+
+    // Whether the port isolates the devices below it. Controls a port does not implement
+    // count as satisfied, as they do for the kernel.
+    pub fn isolates(&self) -> bool {
+        let required = ACS_ISOLATION_FLAGS & self.capabilities;
+        self.control & required == required
+    }
+}
+
+impl ConfigSpace {
+    pub fn acs(&self) -> Option<Acs> {
+        Acs::find(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pci::acs::{Acs, ACS_COMPLETION_REDIRECT, ACS_EGRESS_CONTROL, ACS_ISOLATION_FLAGS, ACS_REQUEST_REDIRECT, ACS_SOURCE_VALIDATION, ACS_TRANSLATION_BLOCKING};
+    use crate::pci::config::{ConfigSpace, ECAP_ID_ACS, ECAP_ID_AER};
+
+    #[test]
+    fn test_acs_decoding() {
+        // AER at 0x100 pointing at ACS at 0x148.
+        let mut data = vec![0u8; 4096];
+        data[0x100..0x104].copy_from_slice(&(ECAP_ID_AER as u32 | 1 << 16 | 0x148 << 20).to_le_bytes());
+        data[0x148..0x14C].copy_from_slice(&(ECAP_ID_ACS as u32 | 1 << 16).to_le_bytes());
+        data[0x14C..0x14E].copy_from_slice(&0x003Fu16.to_le_bytes());
+        data[0x14E..0x150].copy_from_slice(&0x001Du16.to_le_bytes());
+        let config = ConfigSpace::new(data);
+
+        let acs = config.acs().unwrap();
+        assert_eq!(acs, Acs { offset: 0x148, capabilities: 0x003F, control: 0x001D });
+        assert!(acs.supports(ACS_TRANSLATION_BLOCKING | ACS_EGRESS_CONTROL));
+        assert!(acs.enabled(ACS_ISOLATION_FLAGS));
+        assert!(!acs.enabled(ACS_TRANSLATION_BLOCKING));
+        assert!(acs.redirects_p2p());
+
+        assert_eq!(ConfigSpace::new(vec![0u8; 256]).acs(), None);
+    }
+
+    #[test]
+    fn test_acs_isolation() {
+        let acs = |capabilities, control| Acs { offset: 0x100, capabilities, control };
+
+        assert!(acs(ACS_ISOLATION_FLAGS, ACS_ISOLATION_FLAGS).isolates());
+        // Upstream forwarding isn't implemented, so it isn't required.
+        let redirect = ACS_SOURCE_VALIDATION | ACS_REQUEST_REDIRECT | ACS_COMPLETION_REDIRECT;
+        assert!(acs(redirect, redirect).isolates());
+        // A port with no ACS controls has nothing to leave disabled.
+        assert!(acs(0, 0).isolates());
+
+        assert!(!acs(ACS_ISOLATION_FLAGS, ACS_SOURCE_VALIDATION | ACS_COMPLETION_REDIRECT).isolates());
+        assert!(!acs(ACS_ISOLATION_FLAGS, 0).isolates());
+        assert!(!acs(ACS_ISOLATION_FLAGS, 0).redirects_p2p());
+        assert!(acs(ACS_EGRESS_CONTROL, ACS_EGRESS_CONTROL).redirects_p2p());
+    }
+}
@@ -0,0 +1,427 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Active State Power Management and L1 PM Substates, checked against each endpoint's
+//! acceptable exit latency.
+
+use core::fmt;
+use std::fmt::Display;
+
+use crate::pci::bandwidth::is_downstream_port;
+use crate::pci::config::ECAP_ID_L1SS;
+use crate::pci::express::{PciePortType, EXP_DEVICE_CAP, EXP_LINK_CAP, EXP_LINK_CONTROL};
+use crate::pci::topology::{NodeId, PciTopology, TopologyNode};
+
+pub const LINK_CAP_ASPM_L0S: u32 = 0x0400;
+pub const LINK_CAP_ASPM_L1: u32 = 0x0800;
+pub const LINK_CONTROL_ASPM_L0S: u16 = 0x0001;
+pub const LINK_CONTROL_ASPM_L1: u16 = 0x0002;
+
+// Offsets into the L1 PM Substates capability, and the bits shared by its capability and
+// first control register.
+pub const L1SS_CAP: usize = 0x04;
+pub const L1SS_CONTROL1: usize = 0x08;
+pub const L1SS_PCIPM_L1_2: u32 = 0x0001;
+pub const L1SS_PCIPM_L1_1: u32 = 0x0002;
+pub const L1SS_ASPM_L1_2: u32 = 0x0004;
+pub const L1SS_ASPM_L1_1: u32 = 0x0008;
+
+// The kernel adds this much L1 exit latency for every switch between an endpoint and the link.
+const L1_SWITCH_LATENCY_NS: u32 = 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AspmStates {
+    pub l0s: bool,
+    pub l1: bool,
+}
+
+impl AspmStates {
+    fn both(&self, other: &AspmStates) -> AspmStates {
+        AspmStates {
+            l0s: self.l0s && other.l0s,
+            l1: self.l1 && other.l1,
+        }
+    }
+}
+
+impl Display for AspmStates {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.l0s, self.l1) {
+            (true, true) => write!(f, "L0s L1"),
+            (true, false) => write!(f, "L0s"),
+            (false, true) => write!(f, "L1"),
+            (false, false) => write!(f, "Disabled"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct L1Substates {
+    pub aspm_l1_1: bool,
+    pub aspm_l1_2: bool,
+    pub pcipm_l1_1: bool,
+    pub pcipm_l1_2: bool,
+}
+
+impl L1Substates {
+    fn from_bits(bits: u32) -> L1Substates {
+        L1Substates {
+            aspm_l1_1: bits & L1SS_ASPM_L1_1 != 0,
+            aspm_l1_2: bits & L1SS_ASPM_L1_2 != 0,
+            pcipm_l1_1: bits & L1SS_PCIPM_L1_1 != 0,
+            pcipm_l1_2: bits & L1SS_PCIPM_L1_2 != 0,
+        }
+    }
+
+    fn both(&self, other: &L1Substates) -> L1Substates {
+        L1Substates {
+            aspm_l1_1: self.aspm_l1_1 && other.aspm_l1_1,
+            aspm_l1_2: self.aspm_l1_2 && other.aspm_l1_2,
+            pcipm_l1_1: self.pcipm_l1_1 && other.pcipm_l1_1,
+            pcipm_l1_2: self.pcipm_l1_2 && other.pcipm_l1_2,
+        }
+    }
+}
+
+// Exit latencies are encoded as ranges; like the kernel, use the top of each range and a bit
+// more for the open ended last one. All values are in nanoseconds.
+fn l0s_exit_latency(encoding: u32) -> u32 {
+    match encoding {
+        7 => 5000,
+        encoding => 64 << encoding,
+    }
+}
+
+fn l1_exit_latency(encoding: u32) -> u32 {
+    match encoding {
+        7 => 65000,
+        encoding => 1000 << encoding,
+    }
+}
+
+// None means the endpoint accepts any latency.
+fn l0s_acceptable_latency(encoding: u32) -> Option<u32> {
+    (encoding != 7).then(|| 64 << encoding)
+}
+
+fn l1_acceptable_latency(encoding: u32) -> Option<u32> {
+    (encoding != 7).then(|| 1000 << encoding)
+}
+
+// One end of a link, as its own config space describes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkPowerEnd {
+    pub supported: AspmStates,
+    pub enabled: AspmStates,
+    pub l0s_exit_latency: u32,
+    pub l1_exit_latency: u32,
+    pub l1ss_supported: L1Substates,
+    pub l1ss_enabled: L1Substates,
+}
+
+impl LinkPowerEnd {
+    pub fn read(node: &TopologyNode) -> Option<LinkPowerEnd> {
+        let config = node.config.as_ref()?;
+        let pcie = config.pcie()?;
+        let link_cap = pcie.read_u32(EXP_LINK_CAP)?;
+        let link_control = pcie.read_u16(EXP_LINK_CONTROL)?;
+
+        let l1ss = config.find_extended_capability(ECAP_ID_L1SS);
+        let l1ss_bits = |register| l1ss.and_then(|offset| config.read_u32(offset + register)).unwrap_or(0);
+
+        Some(LinkPowerEnd {
+            supported: AspmStates {
+                l0s: link_cap & LINK_CAP_ASPM_L0S != 0,
+                l1: link_cap & LINK_CAP_ASPM_L1 != 0,
+            },
+            enabled: AspmStates {
+                l0s: link_control & LINK_CONTROL_ASPM_L0S != 0,
+                l1: link_control & LINK_CONTROL_ASPM_L1 != 0,
+            },
+            l0s_exit_latency: l0s_exit_latency((link_cap >> 12) & 0x7),
+            l1_exit_latency: l1_exit_latency((link_cap >> 15) & 0x7),
+            l1ss_supported: L1Substates::from_bits(l1ss_bits(L1SS_CAP)),
+            l1ss_enabled: L1Substates::from_bits(l1ss_bits(L1SS_CONTROL1)),
+        })
+    }
+}
+
+// A link seen from both ends. A state is only in effect when both ends have it enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkPower {
+    pub device: NodeId, // The upstream facing end.
+    pub port: NodeId, // The root or switch downstream port above it.
+    pub device_end: LinkPowerEnd,
+    pub port_end: LinkPowerEnd,
+}
+
+impl LinkPower {
+    pub fn supported(&self) -> AspmStates {
+        self.device_end.supported.both(&self.port_end.supported)
+    }
+
+    pub fn enabled(&self) -> AspmStates {
+        self.device_end.enabled.both(&self.port_end.enabled)
+    }
+
+    pub fn l1ss_supported(&self) -> L1Substates {
+        self.device_end.l1ss_supported.both(&self.port_end.l1ss_supported)
+    }
+
+    pub fn l1ss_enabled(&self) -> L1Substates {
+        self.device_end.l1ss_enabled.both(&self.port_end.l1ss_enabled)
+    }
+
+    // The slower end decides how long the link takes to wake.
+    pub fn l0s_exit_latency(&self) -> u32 {
+        self.device_end.l0s_exit_latency.max(self.port_end.l0s_exit_latency)
+    }
+
+    pub fn l1_exit_latency(&self) -> u32 {
+        self.device_end.l1_exit_latency.max(self.port_end.l1_exit_latency)
+    }
+}
+
+// The link between a device and the port above it, if both ends can be read.
+pub fn link_power(topology: &PciTopology, device: NodeId) -> Option<LinkPower> {
+    let port = topology.parent(device).filter(|port| is_downstream_port(topology.node(*port)))?;
+    Some(LinkPower {
+        device,
+        port,
+        device_end: LinkPowerEnd::read(topology.node(device))?,
+        port_end: LinkPowerEnd::read(topology.node(port))?,
+    })
+}
+
+// Every link between an endpoint and its root port, endpoint first.
+pub fn path_power(topology: &PciTopology, endpoint: NodeId) -> Vec<LinkPower> {
+    let mut links = Vec::new();
+    let mut device = Some(endpoint);
+    while let Some(link) = device.and_then(|device| link_power(topology, device)) {
+        // The next link up starts at the switch upstream port above this one's port.
+        device = topology.parent(link.port);
+        links.push(link);
+    }
+    links
+}
+
+// How long an endpoint can wait for the path to wake, from Device Capabilities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcceptableLatency {
+    pub l0s: Option<u32>,
+    pub l1: Option<u32>,
+}
+
+pub fn acceptable_latency(node: &TopologyNode) -> Option<AcceptableLatency> {
+    let device_cap = node.config.as_ref()?.pcie()?.read_u32(EXP_DEVICE_CAP)?;
+    Some(AcceptableLatency {
+        l0s: l0s_acceptable_latency((device_cap >> 6) & 0x7),
+        l1: l1_acceptable_latency((device_cap >> 9) & 0x7),
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AspmState {
+    L0s,
+    L1,
+}
+
+impl Display for AspmState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AspmState::L0s => write!(f, "L0s"),
+            AspmState::L1 => write!(f, "L1"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AspmIssue {
+    // An enabled state takes longer to leave than an endpoint below the link can tolerate.
+    LatencyExceeded { link: NodeId, endpoint: NodeId, state: AspmState, latency: u32, acceptable: u32 },
+    // Both ends support states that are off, and every endpoint below could tolerate them.
+    DisabledButSafe { link: NodeId, states: AspmStates },
+}
+
+impl Display for AspmIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AspmIssue::LatencyExceeded { state, latency, acceptable, .. } => {
+                write!(f, "{} exit latency {}ns exceeds the endpoint's acceptable {}ns", state, latency, acceptable)
+            }
+            AspmIssue::DisabledButSafe { states, .. } => write!(f, "{} supported and within latency but not enabled", states),
+        }
+    }
+}
+
+fn is_endpoint(node: &TopologyNode) -> bool {
+    matches!(node.port_type(), Some(PciePortType::Endpoint) | Some(PciePortType::LegacyEndpoint))
+}
+
+// Check every link the way the kernel's pcie_aspm_check_latency does: walk up from each
+// endpoint, taking the worst L1 exit latency seen so far and adding a microsecond for each
+// switch crossed, since the links wake in parallel. L0s is checked one link at a time.
+pub fn check_aspm(topology: &PciTopology) -> Vec<AspmIssue> {
+    let mut issues = Vec::new();
+    let mut allowed: Vec<Option<AspmStates>> = vec![None; topology.len()];
+
+    for endpoint in (0..topology.len()).filter(|id| is_endpoint(topology.node(*id))) {
+        let acceptable = match acceptable_latency(topology.node(endpoint)) {
+            Some(acceptable) => acceptable,
+            None => continue,
+        };
+
+        let mut switch_latency = 0;
+        let mut l1_max_latency = 0;
+        for link in path_power(topology, endpoint) {
+            let supported = link.supported();
+            let enabled = link.enabled();
+            let states = allowed[link.device].get_or_insert(supported);
+            l1_max_latency = l1_max_latency.max(link.l1_exit_latency());
+
+            let checks = [
+                (AspmState::L0s, supported.l0s, enabled.l0s, link.l0s_exit_latency(), acceptable.l0s),
+                (AspmState::L1, supported.l1, enabled.l1, l1_max_latency + switch_latency, acceptable.l1),
+            ];
+            for (state, supported, enabled, latency, acceptable) in checks {
+                let acceptable = match acceptable {
+                    Some(acceptable) if supported && latency > acceptable => acceptable,
+                    _ => continue,
+                };
+                match state {
+                    AspmState::L0s => states.l0s = false,
+                    AspmState::L1 => states.l1 = false,
+                }
+                if enabled {
+                    issues.push(AspmIssue::LatencyExceeded { link: link.device, endpoint, state, latency, acceptable });
+                }
+            }
+
+            switch_latency += L1_SWITCH_LATENCY_NS;
+        }
+    }
+
+    for (link, states) in allowed.into_iter().enumerate() {
+        let (states, enabled) = match (states, link_power(topology, link)) {
+            (Some(states), Some(power)) => (states, power.enabled()),
+            _ => continue,
+        };
+        let disabled = AspmStates {
+            l0s: states.l0s && !enabled.l0s,
+            l1: states.l1 && !enabled.l1,
+        };
+        if disabled.l0s || disabled.l1 {
+            issues.push(AspmIssue::DisabledButSafe { link, states: disabled });
+        }
+    }
+
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::PciDevice;
+    use crate::pci::aspm::{check_aspm, path_power, AspmIssue, AspmState, AspmStates};
+    use crate::pci::config::ConfigSpace;
+    use crate::pci::topology::{PciTopology, TopologyDevice};
+
+    // A device with a PCIe capability at 0x40 of the given port type.
+    fn device(bus: u8, port_type: u8, parent: Option<&str>, link_cap: u32, link_control: u16, device_cap: u32) -> TopologyDevice {
+        let mut config = vec![0u8; 256];
+        config[0x06] = 0x10;
+        config[0x34] = 0x40;
+        config[0x40] = 0x10;
+        config[0x42] = (port_type << 4) | 0x2;
+        config[0x44..0x48].copy_from_slice(&device_cap.to_le_bytes());
+        config[0x4C..0x50].copy_from_slice(&link_cap.to_le_bytes());
+        config[0x50..0x52].copy_from_slice(&link_control.to_le_bytes());
+
+        let mut device = TopologyDevice::new(PciDevice {
+            domain: 0,
+            bus,
+            device: 0,
+            function: 0,
+            label: String::new(),
+            vendor_id: 0x8086,
+            device_id: 0x1234,
+            subsys_device_id: 0,
+            subsys_vendor_id: 0,
+            class: if port_type == 0 { 0x02 } else { 0x06 },
+            subclass: if port_type == 0 { 0x00 } else { 0x04 },
+            programming_interface: 0,
+            revision_id: 0,
+        });
+        device.config = Some(ConfigSpace::new(config));
+        device.parent_address = parent.map(str::to_string);
+        device
+    }
+
+    #[test]
+    fn test_aspm_latency() {
+        // L0s and L1 supported with 2-4us L1 exit latency on both ends.
+        let link_cap = 0x0C00 | (2 << 15);
+
+        // The first endpoint tolerates any L0s latency but only 1us of L1 latency, and has just
+        // L1 enabled. The second tolerates anything and has ASPM off.
+        let topology = PciTopology::build(vec![
+            device(0, 4, None, link_cap, 0x0002, 0),
+            device(1, 0, Some("0000:00:00.0"), link_cap, 0x0002, 7 << 6),
+            device(2, 4, None, link_cap, 0x0000, 0),
+            device(3, 0, Some("0000:02:00.0"), link_cap, 0x0000, (7 << 6) | (7 << 9)),
+        ]);
+
+        let link = path_power(&topology, 1)[0];
+        assert_eq!(link.supported(), AspmStates { l0s: true, l1: true });
+        assert_eq!(link.l1_exit_latency(), 4000);
+
+        let issues = check_aspm(&topology);
+        assert_eq!(issues.len(), 3);
+        assert!(matches!(issues[0], AspmIssue::LatencyExceeded { link: 1, state: AspmState::L1, latency: 4000, acceptable: 1000, .. }));
+        assert_eq!(issues[1], AspmIssue::DisabledButSafe { link: 1, states: AspmStates { l0s: true, l1: false } });
+        assert_eq!(issues[2], AspmIssue::DisabledButSafe { link: 3, states: AspmStates { l0s: true, l1: true } });
+    }
+
+    #[test]
+    fn test_aspm_latency_behind_switch() {
+        // L1 only, with 8us exit latency on the endpoint's link and 2us on the switch's.
+        let slow = 0x0800 | (3 << 15);
+        let fast = 0x0800 | (1 << 15);
+
+        // The endpoint accepts 8us. The endpoint's link is just within that, but the switch's
+        // link has to wait for it too, plus a microsecond to cross the switch.
+        let topology = PciTopology::build(vec![
+            device(0, 4, None, fast, 0x0002, 0),
+            device(1, 5, Some("0000:00:00.0"), fast, 0x0002, 0),
+            device(2, 6, Some("0000:01:00.0"), slow, 0x0002, 0),
+            device(3, 0, Some("0000:02:00.0"), slow, 0x0002, (7 << 6) | (3 << 9)),
+        ]);
+
+        let issues = check_aspm(&topology);
+        assert_eq!(issues.len(), 1);
+        assert!(matches!(issues[0], AspmIssue::LatencyExceeded { link: 1, endpoint: 3, state: AspmState::L1, latency: 9000, acceptable: 8000 }));
+    }
+}
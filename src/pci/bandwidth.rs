@@ -0,0 +1,279 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Effective bandwidth along the path from a device to its root port.
+
+use core::fmt;
+use std::fmt::Display;
+
+use crate::pci::express::{LinkSpeed, PcieLink, PciePortType};
+use crate::pci::topology::{NodeId, PciTopology, TopologyNode};
+
+impl LinkSpeed {
+    // Raw transfer rate per lane in GT/s.
+    pub fn transfer_rate(&self) -> f64 {
+        match self {
+            LinkSpeed::Gen1 => 2.5,
+            LinkSpeed::Gen2 => 5.0,
+            LinkSpeed::Gen3 => 8.0,
+            LinkSpeed::Gen4 => 16.0,
+            LinkSpeed::Gen5 => 32.0,
+            LinkSpeed::Gen6 => 64.0,
+            LinkSpeed::Unknown(_) => 0.0,
+        }
+    }
+
+    // Gen1 and Gen2 use 8b/10b encoding, Gen3 to Gen5 128b/130b, and Gen6 carries 242 bytes of
+    // payload in every 256 byte FLIT.
+    pub fn encoding_efficiency(&self) -> f64 {
+        match self {
+            LinkSpeed::Gen1 | LinkSpeed::Gen2 => 8.0 / 10.0,
+            LinkSpeed::Gen3 | LinkSpeed::Gen4 | LinkSpeed::Gen5 => 128.0 / 130.0,
+            LinkSpeed::Gen6 => 242.0 / 256.0,
+            LinkSpeed::Unknown(_) => 0.0,
+        }
+    }
+}
+
+impl PcieLink {
+    // Usable bandwidth in each direction in Gbit/s, after encoding overhead.
+    pub fn usable_gbps(&self) -> f64 {
+        self.speed.transfer_rate() * self.speed.encoding_efficiency() * self.width as f64
+    }
+}
+
+// One physical link between a device and the port above it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkHop {
+    pub device: NodeId, // The upstream facing end, e.g. an endpoint or switch upstream port.
+    pub port: Option<NodeId>, // The downstream facing end, a root or switch downstream port.
+    pub current: PcieLink,
+    pub capable: Option<PcieLink>, // The best both ends support.
+}
+
+impl LinkHop {
+    // Many GPUs drop their link to Gen1 while idle, so a slow link is only meaningful under load.
+    pub fn speed_degraded(&self) -> bool {
+        matches!(self.capable, Some(capable) if self.current.speed < capable.speed)
+    }
+
+    pub fn width_degraded(&self) -> bool {
+        matches!(self.capable, Some(capable) if self.current.width < capable.width)
+    }
+
+    // Whether the link trained below what both ends can do.
+    pub fn is_degraded(&self) -> bool {
+        self.speed_degraded() || self.width_degraded()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathBandwidth {
+    pub hops: Vec<LinkHop>, // From the device up to the root port.
+    pub bottleneck: Option<usize>, // Index into hops of the slowest link.
+    pub usable_gbps: f64, // What the path delivers as trained.
+    pub capable_gbps: f64, // What it would deliver if every link trained at its best.
+}
+
+impl PathBandwidth {
+    pub fn bottleneck_hop(&self) -> Option<&LinkHop> {
+        self.hops.get(self.bottleneck?)
+    }
+
+    pub fn degraded_hops(&self) -> impl Iterator<Item = &LinkHop> {
+        self.hops.iter().filter(|hop| hop.is_degraded())
+    }
+}
+
+impl Display for PathBandwidth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:.2} Gbit/s usable of {:.2} Gbit/s capable over {} link(s)", self.usable_gbps, self.capable_gbps, self.hops.len())?;
+        let degraded = self.degraded_hops().count();
+        if degraded > 0 {
+            write!(f, ", {} degraded", degraded)?;
+        }
+        Ok(())
+    }
+}
+
+// Ports whose link status describes the link below them rather than above.
+pub(crate) fn is_downstream_port(node: &TopologyNode) -> bool {
+    matches!(node.port_type(), Some(PciePortType::RootPort) | Some(PciePortType::DownstreamPort))
+}
+
+fn link_capabilities(node: &TopologyNode) -> Option<PcieLink> {
+    node.config.as_ref()?.pcie()?.link_capabilities()
+}
+
+fn link_status(node: &TopologyNode) -> Option<PcieLink> {
+    node.config.as_ref()?.pcie()?.link_status()
+}
+
+// Collect every link between a device and its root port. Each link is read from its upstream
+// facing end, or from the port above when that end's config space is unreadable.
+pub fn path_links(topology: &PciTopology, id: NodeId) -> Vec<LinkHop> {
+    let mut hops = Vec::new();
+    let mut path = vec![id];
+    path.extend(topology.ancestors(id));
+
+    for (index, device_id) in path.iter().enumerate() {
+        let device = topology.node(*device_id);
+        if is_downstream_port(device) {
+            continue;
+        }
+
+        let port_id = path.get(index + 1).copied().filter(|port| is_downstream_port(topology.node(*port)));
+        let port = port_id.map(|port| topology.node(port));
+
+        let current = match link_status(device).or_else(|| port.and_then(link_status)) {
+            Some(current) if current.width > 0 => current,
+            _ => continue,
+        };
+
+        let capable = match (link_capabilities(device), port.and_then(link_capabilities)) {
+            (Some(device), Some(port)) => Some(PcieLink {
+                speed: device.speed.min(port.speed),
+                width: device.width.min(port.width),
+            }),
+            (device, port) => device.or(port),
+        };
+
+        hops.push(LinkHop {
+            device: *device_id,
+            port: port_id,
+            current,
+            capable,
+        });
+    }
+
+    hops
+}
+
+pub fn path_bandwidth(topology: &PciTopology, id: NodeId) -> PathBandwidth {
+    let hops = path_links(topology, id);
+
+    let mut bottleneck = None;
+    let mut usable_gbps = 0.0;
+    for (index, hop) in hops.iter().enumerate() {
+        let gbps = hop.current.usable_gbps();
+        if bottleneck.is_none() || gbps < usable_gbps {
+            bottleneck = Some(index);
+            usable_gbps = gbps;
+        }
+    }
+
+    let capable_gbps = hops.iter()
+        .map(|hop| hop.capable.unwrap_or(hop.current).usable_gbps())
+        .fold(None, |min: Option<f64>, gbps| Some(min.map_or(gbps, |min| min.min(gbps))))
+        .unwrap_or(0.0);
+
+    PathBandwidth {
+        hops,
+        bottleneck,
+        usable_gbps,
+        capable_gbps,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::PciDevice;
+    use crate::pci::bandwidth::path_bandwidth;
+    use crate::pci::config::ConfigSpace;
+    use crate::pci::express::LinkSpeed;
+    use crate::pci::topology::{PciTopology, TopologyDevice};
+
+    // A device with a PCIe capability at 0x40 reporting the given port type and links.
+    fn pcie_device(bus: u8, port_type: u8, capable: (u8, u8), current: (u8, u8), parent: Option<&str>) -> TopologyDevice {
+        let mut config = vec![0u8; 256];
+        config[0x06] = 0x10;
+        config[0x34] = 0x40;
+        config[0x40] = 0x10;
+        config[0x42] = 0x02 | (port_type << 4);
+        config[0x4C..0x50].copy_from_slice(&(capable.0 as u32 | (capable.1 as u32) << 4).to_le_bytes());
+        config[0x52..0x54].copy_from_slice(&(current.0 as u16 | (current.1 as u16) << 4).to_le_bytes());
+
+        let mut entry = TopologyDevice::new(PciDevice {
+            domain: 0,
+            bus,
+            device: if bus == 0 { 1 } else { 0 },
+            function: 0,
+            label: String::new(),
+            vendor_id: 0x10de,
+            device_id: 0x2204,
+            subsys_device_id: 0,
+            subsys_vendor_id: 0,
+            class: 0x03,
+            subclass: 0x00,
+            programming_interface: 0,
+            revision_id: 0,
+        });
+        entry.config = Some(ConfigSpace::new(config));
+        entry.parent_address = parent.map(str::to_string);
+        entry
+    }
+
+    #[test]
+    fn test_degraded_gpu_link() {
+        let topology = PciTopology::build(vec![
+            pcie_device(0, 0x4, (4, 16), (4, 4), None), // Root port.
+            pcie_device(1, 0x0, (4, 16), (4, 4), Some("0000:00:01.0")), // GPU trained at x4.
+        ]);
+
+        let gpu = topology.find("0000:01:00.0").unwrap();
+        let bandwidth = path_bandwidth(&topology, gpu);
+        assert_eq!(bandwidth.hops.len(), 1);
+
+        let hop = bandwidth.bottleneck_hop().unwrap();
+        assert!(hop.width_degraded());
+        assert!(!hop.speed_degraded());
+        assert_eq!(hop.capable.unwrap().speed, LinkSpeed::Gen4);
+        assert!((bandwidth.usable_gbps - 63.015).abs() < 0.01);
+        assert!((bandwidth.capable_gbps - 252.06).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_bottleneck_behind_switch() {
+        // Every link is Gen4 x16 except the one between the root port and the switch, which
+        // trained at x4.
+        let topology = PciTopology::build(vec![
+            pcie_device(0, 0x4, (4, 16), (4, 4), None), // Root port.
+            pcie_device(1, 0x5, (4, 16), (4, 4), Some("0000:00:01.0")), // Switch upstream port.
+            pcie_device(2, 0x6, (4, 16), (4, 16), Some("0000:01:00.0")), // Switch downstream port.
+            pcie_device(3, 0x0, (4, 16), (4, 16), Some("0000:02:00.0")), // GPU.
+        ]);
+
+        let gpu = topology.find("0000:03:00.0").unwrap();
+        let bandwidth = path_bandwidth(&topology, gpu);
+        assert_eq!(bandwidth.hops.len(), 2);
+        assert_eq!(bandwidth.bottleneck, Some(1));
+        assert_eq!(bandwidth.bottleneck_hop().unwrap().device, topology.find("0000:01:00.0").unwrap());
+        assert_eq!(bandwidth.degraded_hops().count(), 1);
+        assert!((bandwidth.usable_gbps - 63.015).abs() < 0.01);
+        assert!((bandwidth.capable_gbps - 252.06).abs() < 0.01);
+    }
+}
@@ -0,0 +1,279 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Decoding of PCI configuration space and its capability lists.
+
+use crate::pci::{read_u16, read_u32, read_u8};
+
+// ############################## Begin register offsets ##############################
+pub const CONFIG_VENDOR_ID: usize = 0x00;
+pub const CONFIG_DEVICE_ID: usize = 0x02;
+pub const CONFIG_COMMAND: usize = 0x04;
+pub const CONFIG_STATUS: usize = 0x06;
+pub const CONFIG_REVISION_ID: usize = 0x08;
+pub const CONFIG_CLASS: usize = 0x09;
+pub const CONFIG_HEADER_TYPE: usize = 0x0E;
+pub const CONFIG_CAPABILITIES_POINTER: usize = 0x34;
+
+// Type 1 (bridge) header.
+pub const CONFIG_PRIMARY_BUS: usize = 0x18;
+pub const CONFIG_SECONDARY_BUS: usize = 0x19;
+pub const CONFIG_SUBORDINATE_BUS: usize = 0x1A;
+pub const CONFIG_BRIDGE_CONTROL: usize = 0x3E;
+
+pub const COMMAND_IO: u16 = 0x0001;
+pub const COMMAND_MEMORY: u16 = 0x0002;
+pub const COMMAND_BUS_MASTER: u16 = 0x0004;
+pub const COMMAND_INTX_DISABLE: u16 = 0x0400;
+
+pub const STATUS_CAPABILITIES: u16 = 0x0010;
+
+pub const HEADER_TYPE_NORMAL: u8 = 0x00;
+pub const HEADER_TYPE_BRIDGE: u8 = 0x01;
+pub const HEADER_TYPE_CARDBUS: u8 = 0x02;
+pub const HEADER_TYPE_MULTI_FUNCTION: u8 = 0x80;
+// ############################## End register offsets ##############################
+
+// ############################## Begin capability IDs ##############################
+pub const CAP_ID_PM: u8 = 0x01;
+pub const CAP_ID_VPD: u8 = 0x03;
+pub const CAP_ID_MSI: u8 = 0x05;
+pub const CAP_ID_VENDOR: u8 = 0x09;
+pub const CAP_ID_SUBSYSTEM_VENDOR: u8 = 0x0D;
+pub const CAP_ID_EXP: u8 = 0x10;
+pub const CAP_ID_MSIX: u8 = 0x11;
+
+pub const ECAP_ID_AER: u16 = 0x0001;
+pub const ECAP_ID_VC: u16 = 0x0002;
+pub const ECAP_ID_DSN: u16 = 0x0003;
+pub const ECAP_ID_PWR: u16 = 0x0004;
+pub const ECAP_ID_ACS: u16 = 0x000D;
+pub const ECAP_ID_ARI: u16 = 0x000E;
+pub const ECAP_ID_ATS: u16 = 0x000F;
+pub const ECAP_ID_SRIOV: u16 = 0x0010;
+pub const ECAP_ID_LTR: u16 = 0x0018;
+pub const ECAP_ID_SECONDARY_PCIE: u16 = 0x0019;
+pub const ECAP_ID_PASID: u16 = 0x001B;
+pub const ECAP_ID_DPC: u16 = 0x001D;
+pub const ECAP_ID_L1SS: u16 = 0x001E;
+// ############################## End capability IDs ##############################
+
+// Offset of the first extended capability, right after the 256 byte legacy space.
+pub const EXTENDED_CONFIG_START: usize = 0x100;
+
+// A standard capability in the linked list starting at the capabilities pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    pub offset: usize,
+}
+
+// A PCIe extended capability, found from offset 0x100 onwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtendedCapability {
+    pub id: u16,
+    pub version: u8,
+    pub offset: usize,
+}
+
+// A copy of a device's configuration space. Unprivileged readers on Linux only get the first
+// 64 bytes, so every accessor copes with the data being cut short.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigSpace {
+    data: Vec<u8>,
+}
+
+impl ConfigSpace {
+    pub fn new(data: Vec<u8>) -> Self {
+        ConfigSpace { data }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    // Whether the PCIe extended config space past 0x100 was readable.
+    pub fn is_extended(&self) -> bool {
+        self.data.len() > EXTENDED_CONFIG_START
+    }
+
+    pub fn read_u8(&self, offset: usize) -> Option<u8> {
+        read_u8(&self.data, offset)
+    }
+
+    pub fn read_u16(&self, offset: usize) -> Option<u16> {
+        read_u16(&self.data, offset)
+    }
+
+    pub fn read_u32(&self, offset: usize) -> Option<u32> {
+        read_u32(&self.data, offset)
+    }
+
+    pub fn command(&self) -> Option<u16> {
+        self.read_u16(CONFIG_COMMAND)
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        self.read_u16(CONFIG_STATUS)
+    }
+
+    pub fn header_type(&self) -> Option<u8> {
+        self.read_u8(CONFIG_HEADER_TYPE).map(|header| header & !HEADER_TYPE_MULTI_FUNCTION)
+    }
+
+    pub fn is_multi_function(&self) -> bool {
+        matches!(self.read_u8(CONFIG_HEADER_TYPE), Some(header) if header & HEADER_TYPE_MULTI_FUNCTION != 0)
+    }
+
+    pub fn is_bridge(&self) -> bool {
+        self.header_type() == Some(HEADER_TYPE_BRIDGE)
+    }
+
+    // (primary, secondary, subordinate) bus numbers of a type 1 header.
+    pub fn bridge_buses(&self) -> Option<(u8, u8, u8)> {
+        if !self.is_bridge() {
+            return None;
+        }
+        Some((
+            self.read_u8(CONFIG_PRIMARY_BUS)?,
+            self.read_u8(CONFIG_SECONDARY_BUS)?,
+            self.read_u8(CONFIG_SUBORDINATE_BUS)?,
+        ))
+    }
+
+    pub fn capabilities(&self) -> Vec<Capability> {
+        let mut capabilities = Vec::new();
+        match self.status() {
+            Some(status) if status & STATUS_CAPABILITIES != 0 => (),
+            _ => return capabilities,
+        }
+
+        let mut offset = match self.read_u8(CONFIG_CAPABILITIES_POINTER) {
+            Some(pointer) => (pointer & 0xFC) as usize,
+            None => return capabilities,
+        };
+
+        // There is room for at most 48 capabilities, anything more is a loop.
+        while offset >= 0x40 && capabilities.len() < 48 {
+            let (id, next) = match (self.read_u8(offset), self.read_u8(offset + 1)) {
+                (Some(id), Some(next)) => (id, next),
+                _ => break,
+            };
+            if id == 0xFF {
+                break;
+            }
+            capabilities.push(Capability { id, offset });
+            offset = (next & 0xFC) as usize;
+        }
+
+        capabilities
+    }
+
+    pub fn extended_capabilities(&self) -> Vec<ExtendedCapability> {
+        let mut capabilities = Vec::new();
+        let mut offset = EXTENDED_CONFIG_START;
+
+        // Each capability takes at least 4 bytes, so this bounds a looping list.
+        while offset >= EXTENDED_CONFIG_START && capabilities.len() < (4096 - EXTENDED_CONFIG_START) / 4 {
+            let header = match self.read_u32(offset) {
+                Some(header) => header,
+                None => break,
+            };
+            // An empty list reads as all zeroes, a missing device as all ones.
+            if header == 0 || header == 0xFFFFFFFF {
+                break;
+            }
+
+            capabilities.push(ExtendedCapability {
+                id: (header & 0xFFFF) as u16,
+                version: ((header >> 16) & 0xF) as u8,
+                offset,
+            });
+            offset = ((header >> 20) & 0xFFC) as usize;
+        }
+
+        capabilities
+    }
+
+    pub fn find_capability(&self, id: u8) -> Option<usize> {
+        self.capabilities().into_iter().find(|capability| capability.id == id).map(|capability| capability.offset)
+    }
+
+    pub fn find_extended_capability(&self, id: u16) -> Option<usize> {
+        self.extended_capabilities().into_iter().find(|capability| capability.id == id).map(|capability| capability.offset)
+    }
+
+    // The 64 bit Device Serial Number, usually an EUI-64.
+    pub fn serial_number(&self) -> Option<u64> {
+        let offset = self.find_extended_capability(ECAP_ID_DSN)?;
+        let low = self.read_u32(offset + 4)? as u64;
+        let high = self.read_u32(offset + 8)? as u64;
+        Some((high << 32) | low)
+    }
+}
+
+// Format a serial number the way lspci does, e.g. 00-1b-21-ff-ff-4a-3c-12
+pub fn format_serial_number(serial: u64) -> String {
+    serial.to_be_bytes().iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<String>>().join("-")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pci::config::{format_serial_number, ConfigSpace, CAP_ID_EXP, CAP_ID_PM, ECAP_ID_AER, ECAP_ID_DSN};
+
+    #[test]
+    fn test_capability_lists() {
+        let mut data = vec![0u8; 4096];
+        data[0x06] = 0x10; // Capabilities list present.
+        data[0x34] = 0x40;
+        data[0x40] = CAP_ID_PM;
+        data[0x41] = 0x50;
+        data[0x50] = CAP_ID_EXP;
+        data[0x51] = 0x00;
+
+        // AER at 0x100 pointing at DSN at 0x140.
+        data[0x100..0x104].copy_from_slice(&(ECAP_ID_AER as u32 | (1 << 16) | (0x140 << 20)).to_le_bytes());
+        data[0x140..0x144].copy_from_slice(&(ECAP_ID_DSN as u32 | (1 << 16)).to_le_bytes());
+        data[0x144..0x148].copy_from_slice(&0xFF4A3C12u32.to_le_bytes());
+        data[0x148..0x14C].copy_from_slice(&0x001B21FFu32.to_le_bytes());
+
+        let config = ConfigSpace::new(data);
+        assert_eq!(config.capabilities().len(), 2);
+        assert_eq!(config.find_capability(CAP_ID_EXP), Some(0x50));
+        assert_eq!(config.extended_capabilities().len(), 2);
+        assert_eq!(config.serial_number(), Some(0x001B21FFFF4A3C12));
+        assert_eq!(format_serial_number(0x001B21FFFF4A3C12), "00-1b-21-ff-ff-4a-3c-12");
+    }
+}
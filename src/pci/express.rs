@@ -0,0 +1,230 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Decoding of the PCI Express capability.
+
+use core::fmt;
+use std::fmt::Display;
+
+use crate::pci::config::{ConfigSpace, CAP_ID_EXP};
+
+// Offsets into the PCI Express capability.
+pub const EXP_FLAGS: usize = 0x02;
+pub const EXP_DEVICE_CAP: usize = 0x04;
+pub const EXP_DEVICE_CONTROL: usize = 0x08;
+pub const EXP_DEVICE_STATUS: usize = 0x0A;
+pub const EXP_LINK_CAP: usize = 0x0C;
+pub const EXP_LINK_CONTROL: usize = 0x10;
+pub const EXP_LINK_STATUS: usize = 0x12;
+pub const EXP_SLOT_CAP: usize = 0x14;
+pub const EXP_SLOT_CONTROL: usize = 0x18;
+pub const EXP_SLOT_STATUS: usize = 0x1A;
+pub const EXP_ROOT_CONTROL: usize = 0x1C;
+pub const EXP_DEVICE_CAP2: usize = 0x24;
+pub const EXP_DEVICE_CONTROL2: usize = 0x28;
+pub const EXP_LINK_CAP2: usize = 0x2C;
+pub const EXP_LINK_CONTROL2: usize = 0x30;
+pub const EXP_SLOT_CONTROL2: usize = 0x38;
+
+pub const EXP_FLAGS_SLOT: u16 = 0x0100;
+
+pub const LINK_STATUS_TRAINING: u16 = 0x0800;
+pub const LINK_STATUS_DLL_ACTIVE: u16 = 0x2000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LinkSpeed {
+    Gen1, // 2.5 GT/s
+    Gen2, // 5 GT/s
+    Gen3, // 8 GT/s
+    Gen4, // 16 GT/s
+    Gen5, // 32 GT/s
+    Gen6, // 64 GT/s
+    Unknown(u8),
+}
+
+impl From<u8> for LinkSpeed {
+    fn from(speed: u8) -> Self {
+        match speed {
+            1 => LinkSpeed::Gen1,
+            2 => LinkSpeed::Gen2,
+            3 => LinkSpeed::Gen3,
+            4 => LinkSpeed::Gen4,
+            5 => LinkSpeed::Gen5,
+            6 => LinkSpeed::Gen6,
+            other => LinkSpeed::Unknown(other),
+        }
+    }
+}
+
+impl Display for LinkSpeed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkSpeed::Gen1 => write!(f, "2.5GT/s"),
+            LinkSpeed::Gen2 => write!(f, "5GT/s"),
+            LinkSpeed::Gen3 => write!(f, "8GT/s"),
+            LinkSpeed::Gen4 => write!(f, "16GT/s"),
+            LinkSpeed::Gen5 => write!(f, "32GT/s"),
+            LinkSpeed::Gen6 => write!(f, "64GT/s"),
+            LinkSpeed::Unknown(speed) => write!(f, "unknown ({:x})", speed),
+        }
+    }
+}
+
+// A link's speed and width, either the maximum it supports or what it trained at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PcieLink {
+    pub speed: LinkSpeed,
+    pub width: u8,
+}
+
+impl Display for PcieLink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} x{}", self.speed, self.width)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciePortType {
+    Endpoint,
+    LegacyEndpoint,
+    RootPort,
+    UpstreamPort,
+    DownstreamPort,
+    PcieToPciBridge,
+    PciToPcieBridge,
+    RootComplexEndpoint,
+    RootComplexEventCollector,
+    Unknown(u8),
+}
+
+impl From<u8> for PciePortType {
+    fn from(port_type: u8) -> Self {
+        match port_type {
+            0x0 => PciePortType::Endpoint,
+            0x1 => PciePortType::LegacyEndpoint,
+            0x4 => PciePortType::RootPort,
+            0x5 => PciePortType::UpstreamPort,
+            0x6 => PciePortType::DownstreamPort,
+            0x7 => PciePortType::PcieToPciBridge,
+            0x8 => PciePortType::PciToPcieBridge,
+            0x9 => PciePortType::RootComplexEndpoint,
+            0xA => PciePortType::RootComplexEventCollector,
+            other => PciePortType::Unknown(other),
+        }
+    }
+}
+
+impl Display for PciePortType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PciePortType::Endpoint => write!(f, "Endpoint"),
+            PciePortType::LegacyEndpoint => write!(f, "Legacy Endpoint"),
+            PciePortType::RootPort => write!(f, "Root Port"),
+            PciePortType::UpstreamPort => write!(f, "Upstream Port"),
+            PciePortType::DownstreamPort => write!(f, "Downstream Port"),
+            PciePortType::PcieToPciBridge => write!(f, "PCI-Express to PCI/PCI-X Bridge"),
+            PciePortType::PciToPcieBridge => write!(f, "PCI/PCI-X to PCI-Express Bridge"),
+            PciePortType::RootComplexEndpoint => write!(f, "Root Complex Integrated Endpoint"),
+            PciePortType::RootComplexEventCollector => write!(f, "Root Complex Event Collector"),
+            PciePortType::Unknown(port_type) => write!(f, "Unknown ({:x})", port_type),
+        }
+    }
+}
+
+// A view of the PCI Express capability inside a device's config space.
+#[derive(Debug, Clone, Copy)]
+pub struct PcieCapability<'a> {
+    config: &'a ConfigSpace,
+    offset: usize,
+}
+
+impl<'a> PcieCapability<'a> {
+    pub fn find(config: &'a ConfigSpace) -> Option<PcieCapability<'a>> {
+        let offset = config.find_capability(CAP_ID_EXP)?;
+        Some(PcieCapability { config, offset })
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn version(&self) -> Option<u8> {
+        Some((self.config.read_u16(self.offset + EXP_FLAGS)? & 0xF) as u8)
+    }
+
+    pub fn port_type(&self) -> Option<PciePortType> {
+        Some(PciePortType::from(((self.config.read_u16(self.offset + EXP_FLAGS)? >> 4) & 0xF) as u8))
+    }
+
+    // Whether the port is connected to a slot, which gives it slot registers.
+    pub fn has_slot(&self) -> bool {
+        matches!(self.read_u16(EXP_FLAGS), Some(flags) if flags & EXP_FLAGS_SLOT != 0)
+    }
+
+    // Root complex integrated devices have no link.
+    pub fn has_link(&self) -> bool {
+        !matches!(self.port_type(), Some(PciePortType::RootComplexEndpoint) | Some(PciePortType::RootComplexEventCollector) | None)
+    }
+
+    pub fn read_u16(&self, register: usize) -> Option<u16> {
+        self.config.read_u16(self.offset + register)
+    }
+
+    pub fn read_u32(&self, register: usize) -> Option<u32> {
+        self.config.read_u32(self.offset + register)
+    }
+
+    // The fastest and widest the link can go.
+    pub fn link_capabilities(&self) -> Option<PcieLink> {
+        if !self.has_link() {
+            return None;
+        }
+        let link_cap = self.read_u32(EXP_LINK_CAP)?;
+        Some(PcieLink {
+            speed: LinkSpeed::from((link_cap & 0xF) as u8),
+            width: ((link_cap >> 4) & 0x3F) as u8,
+        })
+    }
+
+    // What the link actually trained at.
+    pub fn link_status(&self) -> Option<PcieLink> {
+        if !self.has_link() {
+            return None;
+        }
+        let link_status = self.read_u16(EXP_LINK_STATUS)?;
+        Some(PcieLink {
+            speed: LinkSpeed::from((link_status & 0xF) as u8),
+            width: ((link_status >> 4) & 0x3F) as u8,
+        })
+    }
+}
+
+impl ConfigSpace {
+    pub fn pcie(&self) -> Option<PcieCapability<'_>> {
+        PcieCapability::find(self)
+    }
+}
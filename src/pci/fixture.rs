@@ -0,0 +1,126 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Devices and config space for testing the decoders without real hardware.
+
+use crate::backend::PciDevice;
+use crate::pci::config::ConfigSpace;
+use crate::pci::topology::TopologyDevice;
+
+// Where the PCI Express capability goes in built config space.
+pub(crate) const PCIE_OFFSET: usize = 0x40;
+
+// Builds a device, optionally with config space, ready for a topology. Defaults to an Intel
+// network controller at the given address.
+pub(crate) struct TestDevice {
+    device: PciDevice,
+    config: Option<Vec<u8>>,
+    parent_address: Option<String>,
+}
+
+impl TestDevice {
+    pub(crate) fn new(bus: u8, device: u8, function: u8) -> Self {
+        TestDevice {
+            device: PciDevice {
+                domain: 0,
+                bus,
+                device,
+                function,
+                label: String::new(),
+                vendor_id: 0x8086,
+                device_id: 0x1234,
+                subsys_device_id: 0,
+                subsys_vendor_id: 0,
+                class: 0x02,
+                subclass: 0x00,
+                programming_interface: 0,
+                revision_id: 0,
+            },
+            config: None,
+            parent_address: None,
+        }
+    }
+
+    pub(crate) fn ids(mut self, vendor_id: u16, device_id: u16) -> Self {
+        self.device.vendor_id = vendor_id;
+        self.device.device_id = device_id;
+        self
+    }
+
+    pub(crate) fn class(mut self, class: u8, subclass: u8) -> Self {
+        self.device.class = class;
+        self.device.subclass = subclass;
+        self
+    }
+
+    pub(crate) fn parent(mut self, address: Option<&str>) -> Self {
+        self.parent_address = address.map(str::to_string);
+        self
+    }
+
+    // 256 bytes of config space with a PCI Express capability of the given port type at
+    // PCIE_OFFSET.
+    pub(crate) fn pcie(mut self, port_type: u8) -> Self {
+        let mut config = vec![0u8; 256];
+        config[0x06] = 0x10; // Capability list present.
+        config[0x34] = PCIE_OFFSET as u8;
+        config[PCIE_OFFSET] = 0x10;
+        config[PCIE_OFFSET + 2] = (port_type << 4) | 0x2;
+        self.config = Some(config);
+        self
+    }
+
+    pub(crate) fn config_bytes(mut self, offset: usize, bytes: &[u8]) -> Self {
+        let config = self.config.get_or_insert_with(|| vec![0u8; 256]);
+        config[offset..offset + bytes.len()].copy_from_slice(bytes);
+        self
+    }
+
+    pub(crate) fn config_u16(self, offset: usize, value: u16) -> Self {
+        self.config_bytes(offset, &value.to_le_bytes())
+    }
+
+    pub(crate) fn config_u32(self, offset: usize, value: u32) -> Self {
+        self.config_bytes(offset, &value.to_le_bytes())
+    }
+
+    // Registers inside the PCI Express capability, e.g. EXP_LINK_CAP.
+    pub(crate) fn pcie_u16(self, register: usize, value: u16) -> Self {
+        self.config_u16(PCIE_OFFSET + register, value)
+    }
+
+    pub(crate) fn pcie_u32(self, register: usize, value: u32) -> Self {
+        self.config_u32(PCIE_OFFSET + register, value)
+    }
+
+    pub(crate) fn build(self) -> TopologyDevice {
+        let mut entry = TopologyDevice::new(self.device);
+        entry.config = self.config.map(ConfigSpace::new);
+        entry.parent_address = self.parent_address;
+        entry
+    }
+}
@@ -0,0 +1,304 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Health checks over the topology: degraded or retraining links and latched error status.
+
+use core::fmt;
+use std::fmt::Display;
+
+use crate::pci::bandwidth::{is_downstream_port, path_links};
+use crate::pci::config::CONFIG_STATUS;
+use crate::pci::express::{EXP_DEVICE_STATUS, EXP_LINK_CAP, EXP_LINK_STATUS, LINK_STATUS_DLL_ACTIVE, LINK_STATUS_TRAINING};
+use crate::pci::topology::{NodeId, PciTopology, TopologyNode};
+
+pub const LINK_CAP_DLL_ACTIVE_REPORTING: u32 = 0x0010_0000;
+pub const LINK_STATUS_BANDWIDTH_MANAGEMENT: u16 = 0x4000;
+
+pub const DEVICE_STATUS_CORRECTABLE: u16 = 0x0001;
+pub const DEVICE_STATUS_NONFATAL: u16 = 0x0002;
+pub const DEVICE_STATUS_FATAL: u16 = 0x0004;
+pub const DEVICE_STATUS_UNSUPPORTED_REQUEST: u16 = 0x0008;
+
+pub const STATUS_MASTER_DATA_PARITY: u16 = 0x0100;
+pub const STATUS_SIGNALED_TARGET_ABORT: u16 = 0x0800;
+pub const STATUS_RECEIVED_TARGET_ABORT: u16 = 0x1000;
+pub const STATUS_RECEIVED_MASTER_ABORT: u16 = 0x2000;
+pub const STATUS_SIGNALED_SYSTEM_ERROR: u16 = 0x4000;
+pub const STATUS_DETECTED_PARITY: u16 = 0x8000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Info => write!(f, "info"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Critical => write!(f, "critical"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthIssue {
+    // Trained below what both ends support. Many devices drop speed while idle, so a slow
+    // link is only a fault if it stays slow under load.
+    SpeedDegraded { current: String, capable: String },
+    WidthDegraded { current: u8, capable: u8 },
+    // The port is still in link training.
+    LinkTraining,
+    // A device sits below the port but the data link layer is down.
+    LinkDown,
+    // The link retrained or changed speed without being asked, a sign of a flapping link.
+    LinkRetrained,
+    // Error bits latched in the PCIe Device Status register.
+    DeviceErrors { status: u16 },
+    // Error bits latched in the conventional Status register.
+    StatusErrors { status: u16 },
+    // More AER errors than the configured threshold.
+    AerThreshold { kind: String, count: u64, threshold: u64 },
+}
+
+impl Display for HealthIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HealthIssue::SpeedDegraded { current, capable } => write!(f, "link at {} but capable of {}", current, capable),
+            HealthIssue::WidthDegraded { current, capable } => write!(f, "link at x{} but capable of x{}", current, capable),
+            HealthIssue::LinkTraining => write!(f, "link training in progress"),
+            HealthIssue::LinkDown => write!(f, "device present but data link layer inactive"),
+            HealthIssue::LinkRetrained => write!(f, "link retrained autonomously"),
+            HealthIssue::DeviceErrors { status } => write!(f, "device status errors {:#06x}", status),
+            HealthIssue::StatusErrors { status } => write!(f, "status register errors {:#06x}", status),
+            HealthIssue::AerThreshold { kind, count, threshold } => write!(f, "{} {} AER errors, threshold {}", count, kind, threshold),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthFinding {
+    pub device: NodeId,
+    pub severity: Severity,
+    pub issue: HealthIssue,
+}
+
+// Error counts from whatever source the OS offers, checked against HealthThresholds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ErrorCounts {
+    pub correctable: u64,
+    pub nonfatal: u64,
+    pub fatal: u64,
+}
+
+// The most errors of each kind tolerated before a finding is raised.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HealthThresholds {
+    pub correctable: u64,
+    pub nonfatal: u64,
+    pub fatal: u64,
+}
+
+impl Default for HealthThresholds {
+    fn default() -> Self {
+        HealthThresholds {
+            correctable: 10,
+            nonfatal: 0,
+            fatal: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HealthReport {
+    pub findings: Vec<HealthFinding>,
+}
+
+impl HealthReport {
+    fn push(&mut self, device: NodeId, severity: Severity, issue: HealthIssue) {
+        self.findings.push(HealthFinding { device, severity, issue });
+    }
+
+    pub fn worst(&self) -> Option<Severity> {
+        self.findings.iter().map(|finding| finding.severity).max()
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.worst().is_none_or(|severity| severity == Severity::Info)
+    }
+
+    pub fn at_least(&self, severity: Severity) -> impl Iterator<Item = &HealthFinding> {
+        self.findings.iter().filter(move |finding| finding.severity >= severity)
+    }
+
+    // Check a device's error counts, e.g. from AER, against the thresholds.
+    pub fn check_errors(&mut self, device: NodeId, counts: &ErrorCounts, thresholds: &HealthThresholds) {
+        let checks = [
+            ("correctable", counts.correctable, thresholds.correctable, Severity::Warning),
+            ("non-fatal", counts.nonfatal, thresholds.nonfatal, Severity::Warning),
+            ("fatal", counts.fatal, thresholds.fatal, Severity::Critical),
+        ];
+        for (kind, count, threshold, severity) in checks {
+            if count > threshold {
+                self.push(device, severity, HealthIssue::AerThreshold { kind: kind.to_string(), count, threshold });
+            }
+        }
+    }
+}
+
+fn check_link(report: &mut HealthReport, topology: &PciTopology, id: NodeId) {
+    let hop = match path_links(topology, id).into_iter().next() {
+        Some(hop) if hop.device == id => hop,
+        _ => return,
+    };
+    let capable = match hop.capable {
+        Some(capable) => capable,
+        None => return,
+    };
+
+    if hop.width_degraded() {
+        report.push(id, Severity::Critical, HealthIssue::WidthDegraded { current: hop.current.width, capable: capable.width });
+    }
+    if hop.speed_degraded() {
+        report.push(id, Severity::Warning, HealthIssue::SpeedDegraded {
+            current: hop.current.speed.to_string(),
+            capable: capable.speed.to_string(),
+        });
+    }
+}
+
+// Link training state is only meaningful on the downstream end of a link.
+fn check_port(report: &mut HealthReport, topology: &PciTopology, id: NodeId) {
+    let node = topology.node(id);
+    let pcie = match node.config.as_ref().and_then(|config| config.pcie()) {
+        Some(pcie) => pcie,
+        None => return,
+    };
+    let (link_cap, link_status) = match (pcie.read_u32(EXP_LINK_CAP), pcie.read_u16(EXP_LINK_STATUS)) {
+        (Some(link_cap), Some(link_status)) => (link_cap, link_status),
+        _ => return,
+    };
+
+    if link_status & LINK_STATUS_TRAINING != 0 {
+        report.push(id, Severity::Warning, HealthIssue::LinkTraining);
+    }
+    let reports_dll = link_cap & LINK_CAP_DLL_ACTIVE_REPORTING != 0;
+    if reports_dll && link_status & LINK_STATUS_DLL_ACTIVE == 0 && !topology.children(id).is_empty() {
+        report.push(id, Severity::Critical, HealthIssue::LinkDown);
+    }
+    if link_status & LINK_STATUS_BANDWIDTH_MANAGEMENT != 0 {
+        report.push(id, Severity::Warning, HealthIssue::LinkRetrained);
+    }
+}
+
+fn check_status(report: &mut HealthReport, node: &TopologyNode, id: NodeId) {
+    let config = match &node.config {
+        Some(config) => config,
+        None => return,
+    };
+
+    let device_status = config.pcie().and_then(|pcie| pcie.read_u16(EXP_DEVICE_STATUS)).unwrap_or(0);
+    let device_errors = device_status & (DEVICE_STATUS_CORRECTABLE | DEVICE_STATUS_NONFATAL | DEVICE_STATUS_FATAL | DEVICE_STATUS_UNSUPPORTED_REQUEST);
+    if device_errors != 0 {
+        let severity = match device_errors {
+            errors if errors & DEVICE_STATUS_FATAL != 0 => Severity::Critical,
+            errors if errors & DEVICE_STATUS_NONFATAL != 0 => Severity::Warning,
+            _ => Severity::Info,
+        };
+        report.push(id, severity, HealthIssue::DeviceErrors { status: device_errors });
+    }
+
+    let status = config.read_u16(CONFIG_STATUS).unwrap_or(0);
+    let aborts = STATUS_SIGNALED_TARGET_ABORT | STATUS_RECEIVED_TARGET_ABORT | STATUS_RECEIVED_MASTER_ABORT;
+    let parity = STATUS_MASTER_DATA_PARITY | STATUS_SIGNALED_SYSTEM_ERROR | STATUS_DETECTED_PARITY;
+    if status & (aborts | parity) != 0 {
+        let severity = if status & parity != 0 { Severity::Critical } else { Severity::Warning };
+        report.push(id, severity, HealthIssue::StatusErrors { status: status & (aborts | parity) });
+    }
+}
+
+// Check every link and device in the topology. Error counters aren't part of config space;
+// add them with HealthReport::check_errors.
+pub fn check_health(topology: &PciTopology) -> HealthReport {
+    let mut report = HealthReport::default();
+    for (id, node) in topology.nodes().iter().enumerate() {
+        if is_downstream_port(node) {
+            check_port(&mut report, topology, id);
+        } else {
+            check_link(&mut report, topology, id);
+        }
+        check_status(&mut report, node, id);
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pci::express::{EXP_DEVICE_STATUS, EXP_LINK_CAP, EXP_LINK_STATUS};
+    use crate::pci::fixture::TestDevice;
+    use crate::pci::health::{check_health, ErrorCounts, HealthIssue, HealthThresholds, Severity};
+    use crate::pci::topology::{PciTopology, TopologyDevice};
+
+    fn device(bus: u8, port_type: u8, parent: Option<&str>, link_cap: u32, link_status: u16, device_status: u16) -> TopologyDevice {
+        TestDevice::new(bus, 0, 0)
+            .ids(0x15b3, 0x101d)
+            .class(if port_type == 0 { 0x02 } else { 0x06 }, if port_type == 0 { 0x00 } else { 0x04 })
+            .pcie(port_type)
+            .pcie_u16(EXP_DEVICE_STATUS, device_status)
+            .pcie_u32(EXP_LINK_CAP, link_cap)
+            .pcie_u16(EXP_LINK_STATUS, link_status)
+            .parent(parent)
+            .build()
+    }
+
+    #[test]
+    fn test_health_report() {
+        // Root port has retrained and reports DLL active; the NIC trained Gen4 x8 of Gen4 x16
+        // and has a correctable error latched.
+        let topology = PciTopology::build(vec![
+            device(0, 4, None, 0x0010_0104, 0x6044, 0),
+            device(1, 0, Some("0000:00:00.0"), 0x0104, 0x0084, 0x0001),
+        ]);
+
+        let mut report = check_health(&topology);
+        let issues: Vec<(Severity, &HealthIssue)> = report.findings.iter().map(|finding| (finding.severity, &finding.issue)).collect();
+        assert_eq!(
+            issues,
+            vec![
+                (Severity::Warning, &HealthIssue::LinkRetrained),
+                (Severity::Critical, &HealthIssue::WidthDegraded { current: 8, capable: 16 }),
+                (Severity::Info, &HealthIssue::DeviceErrors { status: 0x0001 }),
+            ]
+        );
+        assert_eq!(report.worst(), Some(Severity::Critical));
+
+        report.check_errors(1, &ErrorCounts { correctable: 50, nonfatal: 0, fatal: 0 }, &HealthThresholds::default());
+        assert_eq!(report.at_least(Severity::Warning).count(), 3);
+    }
+}
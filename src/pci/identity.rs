@@ -0,0 +1,143 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Stable identities for PCI devices that survive reboots and bus renumbering.
+
+use core::fmt;
+use std::fmt::Display;
+
+use crate::backend::PciDevice;
+use crate::pci::config::format_serial_number;
+
+// Where an identity came from, from most to least trustworthy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum IdentitySource {
+    SerialNumber, // The Device Serial Number extended capability.
+    VpdSerial, // The SN keyword in Vital Product Data.
+    PhysicalSlot, // The physical slot the device sits in, combined with its IDs.
+    Address, // The bus address, which changes when devices are added or removed.
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DeviceIdentity {
+    pub source: IdentitySource,
+    pub key: String,
+}
+
+// The inputs an identity can be built from, gathered by the backend.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdentityHints {
+    pub serial_number: Option<u64>,
+    pub vpd_serial: Option<String>,
+    pub physical_slot: Option<String>,
+}
+
+impl DeviceIdentity {
+    // Pick the most stable source available. The function number is part of every key since
+    // all functions of a multi-function device share the same serial numbers and slot.
+    pub fn new(device: &PciDevice, hints: &IdentityHints) -> DeviceIdentity {
+        let ids = format!("{:04x}:{:04x}", device.vendor_id, device.device_id);
+
+        if let Some(serial) = hints.serial_number.filter(|serial| *serial != 0 && *serial != u64::MAX) {
+            return DeviceIdentity {
+                source: IdentitySource::SerialNumber,
+                key: format!("dsn:{}.{:x}", format_serial_number(serial), device.function),
+            };
+        }
+
+        if let Some(serial) = hints.vpd_serial.as_deref().map(str::trim).filter(|serial| !serial.is_empty()) {
+            return DeviceIdentity {
+                source: IdentitySource::VpdSerial,
+                key: format!("vpd:{}:{}.{:x}", ids, serial, device.function),
+            };
+        }
+
+        if let Some(slot) = hints.physical_slot.as_deref().filter(|slot| !slot.is_empty()) {
+            return DeviceIdentity {
+                source: IdentitySource::PhysicalSlot,
+                key: format!("slot:{}:{}.{:x}", slot, ids, device.function),
+            };
+        }
+
+        DeviceIdentity {
+            source: IdentitySource::Address,
+            key: format!("addr:{}:{}", device.address(), ids),
+        }
+    }
+
+    // Whether the key will still match after the device moves to a different bus number.
+    pub fn is_stable(&self) -> bool {
+        self.source != IdentitySource::Address
+    }
+}
+
+impl Display for DeviceIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::PciDevice;
+    use crate::pci::identity::{DeviceIdentity, IdentityHints, IdentitySource};
+
+    fn device(bus: u8) -> PciDevice {
+        PciDevice {
+            domain: 0,
+            bus,
+            device: 0,
+            function: 1,
+            label: String::new(),
+            vendor_id: 0x8086,
+            device_id: 0x1572,
+            subsys_device_id: 0,
+            subsys_vendor_id: 0,
+            class: 0x02,
+            subclass: 0x00,
+            programming_interface: 0,
+            revision_id: 0x02,
+        }
+    }
+
+    #[test]
+    fn test_identity_survives_renumbering() {
+        let hints = IdentityHints {
+            serial_number: Some(0x001B21FFFF4A3C12),
+            vpd_serial: Some("AB1234".to_string()),
+            physical_slot: None,
+        };
+        let before = DeviceIdentity::new(&device(2), &hints);
+        let after = DeviceIdentity::new(&device(5), &hints);
+        assert_eq!(before, after);
+        assert_eq!(before.source, IdentitySource::SerialNumber);
+        assert_eq!(before.key, "dsn:00-1b-21-ff-ff-4a-3c-12.1");
+
+        let fallback = DeviceIdentity::new(&device(2), &IdentityHints::default());
+        assert!(!fallback.is_stable());
+    }
+}
@@ -0,0 +1,179 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Device ID match tables in the style of the kernel's struct pci_device_id.
+
+use crate::backend::PciDevice;
+
+// Matches any value in a vendor, device, subvendor or subdevice field.
+pub const PCI_ANY_ID: u32 = 0xFFFF_FFFF;
+
+// One entry in a match table. IDs are u32 like the kernel's so that PCI_ANY_ID fits, and class
+// is compared only in the bits set in class_mask.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDeviceId<T> {
+    pub vendor: u32,
+    pub device: u32,
+    pub subvendor: u32,
+    pub subdevice: u32,
+    pub class: u32,
+    pub class_mask: u32,
+    pub driver_data: T,
+}
+
+impl<T> PciDeviceId<T> {
+    // The same test as the kernel's pci_match_one_device.
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        (self.vendor == PCI_ANY_ID || self.vendor == device.vendor_id as u32)
+            && (self.device == PCI_ANY_ID || self.device == device.device_id as u32)
+            && (self.subvendor == PCI_ANY_ID || self.subvendor == device.subsys_vendor_id as u32)
+            && (self.subdevice == PCI_ANY_ID || self.subdevice == device.subsys_device_id as u32)
+            && (self.class ^ device.class_code()) & self.class_mask == 0
+    }
+
+    // How narrowly the entry picks out devices: the number of exact ID fields, then the number
+    // of class bits compared.
+    pub fn specificity(&self) -> (u32, u32) {
+        let exact = [self.vendor, self.device, self.subvendor, self.subdevice].iter().filter(|id| **id != PCI_ANY_ID).count() as u32;
+        (exact, self.class_mask.count_ones())
+    }
+}
+
+// The first matching entry, which is what the kernel uses.
+pub fn first_match<'a, T>(table: &'a [PciDeviceId<T>], device: &PciDevice) -> Option<&'a PciDeviceId<T>> {
+    table.iter().find(|id| id.matches(device))
+}
+
+// The most specific matching entry, so a table does not have to be ordered from the narrowest
+// entries to the broadest. Ties go to the earlier entry.
+pub fn best_match<'a, T>(table: &'a [PciDeviceId<T>], device: &PciDevice) -> Option<&'a PciDeviceId<T>> {
+    let mut best: Option<&PciDeviceId<T>> = None;
+    for id in table.iter().filter(|id| id.matches(device)) {
+        if best.is_none_or(|best| id.specificity() > best.specificity()) {
+            best = Some(id);
+        }
+    }
+    best
+}
+
+// Build a match table at compile time, mirroring the kernel's PCI_DEVICE family of macros:
+//
+//     const TABLE: [PciDeviceId<u32>; 3] = pci_device_table![
+//         device(0x8086, 0x15bb) => 1,
+//         device_sub(0x8086, 0x1572, 0x1028, 0x1f99) => 2,
+//         class(0x020000, 0xffff00) => 3,
+//     ];
+//
+// Entries can also be vendor(v) for every device from a vendor.
+#[macro_export]
+macro_rules! pci_device_table {
+    ($($kind:ident ( $($arg:expr),* ) => $data:expr),* $(,)?) => {
+        [$($crate::pci_device_table!(@entry $kind ( $($arg),* ) => $data)),*]
+    };
+    (@entry vendor ($vendor:expr) => $data:expr) => {
+        $crate::pci::matching::PciDeviceId {
+            vendor: $vendor as u32,
+            device: $crate::pci::matching::PCI_ANY_ID,
+            subvendor: $crate::pci::matching::PCI_ANY_ID,
+            subdevice: $crate::pci::matching::PCI_ANY_ID,
+            class: 0,
+            class_mask: 0,
+            driver_data: $data,
+        }
+    };
+    (@entry device ($vendor:expr, $device:expr) => $data:expr) => {
+        $crate::pci::matching::PciDeviceId {
+            vendor: $vendor as u32,
+            device: $device as u32,
+            subvendor: $crate::pci::matching::PCI_ANY_ID,
+            subdevice: $crate::pci::matching::PCI_ANY_ID,
+            class: 0,
+            class_mask: 0,
+            driver_data: $data,
+        }
+    };
+    (@entry device_sub ($vendor:expr, $device:expr, $subvendor:expr, $subdevice:expr) => $data:expr) => {
+        $crate::pci::matching::PciDeviceId {
+            vendor: $vendor as u32,
+            device: $device as u32,
+            subvendor: $subvendor as u32,
+            subdevice: $subdevice as u32,
+            class: 0,
+            class_mask: 0,
+            driver_data: $data,
+        }
+    };
+    (@entry class ($class:expr, $class_mask:expr) => $data:expr) => {
+        $crate::pci::matching::PciDeviceId {
+            vendor: $crate::pci::matching::PCI_ANY_ID,
+            device: $crate::pci::matching::PCI_ANY_ID,
+            subvendor: $crate::pci::matching::PCI_ANY_ID,
+            subdevice: $crate::pci::matching::PCI_ANY_ID,
+            class: $class as u32,
+            class_mask: $class_mask as u32,
+            driver_data: $data,
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::PciDevice;
+    use crate::pci::matching::{best_match, first_match, PciDeviceId};
+
+    const TABLE: [PciDeviceId<&str>; 4] = pci_device_table![
+        class(0x020000, 0xffff00) => "any ethernet",
+        vendor(0x8086) => "any intel",
+        device(0x8086, 0x1572) => "x710",
+        device_sub(0x8086, 0x1572, 0x1028, 0x1f99) => "dell x710",
+    ];
+
+    fn nic(subsys_vendor_id: u16) -> PciDevice {
+        PciDevice {
+            domain: 0,
+            bus: 1,
+            device: 0,
+            function: 0,
+            label: String::new(),
+            vendor_id: 0x8086,
+            device_id: 0x1572,
+            subsys_device_id: 0x1f99,
+            subsys_vendor_id,
+            class: 0x02,
+            subclass: 0x00,
+            programming_interface: 0x00,
+            revision_id: 0x02,
+        }
+    }
+
+    #[test]
+    fn test_match_table() {
+        assert_eq!(first_match(&TABLE, &nic(0x1028)).unwrap().driver_data, "any ethernet");
+        assert_eq!(best_match(&TABLE, &nic(0x1028)).unwrap().driver_data, "dell x710");
+        assert_eq!(best_match(&TABLE, &nic(0x8086)).unwrap().driver_data, "x710");
+    }
+}
@@ -0,0 +1,224 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Peer to peer DMA reachability between two devices.
+
+use crate::pci::topology::{NodeId, PciTopology};
+
+// Peer to peer memory a device publishes for other devices to DMA into, e.g. an NVMe CMB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct P2pMemory {
+    pub size: u64,
+    pub available: u64,
+    pub published: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum P2pSupport {
+    Direct, // Traffic turns around at a shared switch or bridge.
+    ThroughHostBridge, // Traffic goes up to the root complex, which only some host bridges allow.
+    Unsupported, // The devices sit under different host bridges.
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct P2pReport {
+    pub support: P2pSupport,
+    pub path: Vec<NodeId>, // From the first device up to the turnaround point and down to the second.
+    pub common_upstream: Option<NodeId>, // The lowest bridge both devices sit under.
+    pub acs_redirects: Vec<NodeId>, // Ports on the path whose ACS settings push traffic upstream.
+    pub crosses_root_complex: bool,
+    pub p2pmem: Option<P2pMemory>, // The second device's published memory, if the OS reported any.
+}
+
+// Work out the route a transaction from one device to another would take, following the same
+// rules as Linux's p2pdma distance calculation.
+pub fn p2p_reachability(topology: &PciTopology, from: NodeId, to: NodeId) -> P2pReport {
+    let from_ancestors = topology.ancestors(from);
+    let to_ancestors = topology.ancestors(to);
+
+    let common_upstream = from_ancestors.iter().find(|ancestor| to_ancestors.contains(ancestor)).copied();
+
+    // The bridges each side passes through below the turnaround point.
+    let below_common = |ancestors: &[NodeId]| -> Vec<NodeId> {
+        match common_upstream {
+            Some(common) => ancestors.iter().take_while(|ancestor| **ancestor != common).copied().collect(),
+            None => ancestors.to_vec(),
+        }
+    };
+    let from_bridges = below_common(&from_ancestors);
+    let to_bridges = below_common(&to_ancestors);
+
+    let mut path = vec![from];
+    path.extend(&from_bridges);
+    path.extend(common_upstream);
+    path.extend(to_bridges.iter().rev());
+    path.push(to);
+
+    // The common bridge routes between its ports, so its own ACS settings count too.
+    let acs_redirects: Vec<NodeId> = from_bridges.iter()
+        .chain(to_bridges.iter())
+        .chain(common_upstream.iter())
+        .copied()
+        .filter(|bridge| {
+            match topology.node(*bridge).config.as_ref().and_then(|config| config.acs()) {
+                Some(acs) => acs.redirects_p2p(),
+                None => false,
+            }
+        })
+        .collect();
+
+    let same_root_complex = topology.root_complex(from) == topology.root_complex(to);
+    let (support, crosses_root_complex) = if !same_root_complex {
+        (P2pSupport::Unsupported, true)
+    } else if from == to || (common_upstream.is_some() && acs_redirects.is_empty()) {
+        (P2pSupport::Direct, false)
+    } else {
+        (P2pSupport::ThroughHostBridge, true)
+    };
+
+    P2pReport {
+        support,
+        path,
+        common_upstream,
+        acs_redirects,
+        crosses_root_complex,
+        p2pmem: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::PciDevice;
+    use crate::pci::acs::{ACS_CAPABILITIES, ACS_CONTROL, ACS_ISOLATION_FLAGS, ACS_SOURCE_VALIDATION};
+    use crate::pci::config::{ConfigSpace, ECAP_ID_ACS};
+    use crate::pci::p2p::{p2p_reachability, P2pSupport};
+    use crate::pci::topology::{PciTopology, TopologyDevice};
+
+    fn device(bus: u8, device: u8, class: u8, subclass: u8, parent: Option<&str>) -> TopologyDevice {
+        let mut entry = TopologyDevice::new(PciDevice {
+            domain: 0,
+            bus,
+            device,
+            function: 0,
+            label: String::new(),
+            vendor_id: 0x8086,
+            device_id: 0x1234,
+            subsys_device_id: 0,
+            subsys_vendor_id: 0,
+            class,
+            subclass,
+            programming_interface: 0,
+            revision_id: 0,
+        });
+        entry.parent_address = parent.map(str::to_string);
+        entry
+    }
+
+    // A bridge with an ACS capability at the start of extended config space.
+    fn port(bus: u8, device_number: u8, parent: Option<&str>, acs_control: u16) -> TopologyDevice {
+        let mut config = vec![0u8; 4096];
+        config[0x100..0x104].copy_from_slice(&(ECAP_ID_ACS as u32 | 1 << 16).to_le_bytes());
+        config[0x100 + ACS_CAPABILITIES..0x102 + ACS_CAPABILITIES].copy_from_slice(&ACS_ISOLATION_FLAGS.to_le_bytes());
+        config[0x100 + ACS_CONTROL..0x102 + ACS_CONTROL].copy_from_slice(&acs_control.to_le_bytes());
+
+        let mut entry = device(bus, device_number, 0x06, 0x04, parent);
+        entry.config = Some(ConfigSpace::new(config));
+        entry
+    }
+
+    fn endpoint(bus: u8, parent: &str) -> TopologyDevice {
+        device(bus, 0, 0x01, 0x08, Some(parent))
+    }
+
+    // Two root ports on pci0000:00, the first with a switch below it, and a root port on a
+    // second host bridge. The switch downstream ports use the given ACS control.
+    fn topology(acs_control: u16) -> PciTopology {
+        PciTopology::build(vec![
+            port(0, 1, None, 0),
+            port(1, 0, Some("0000:00:01.0"), 0),
+            port(2, 0, Some("0000:01:00.0"), acs_control),
+            port(2, 1, Some("0000:01:00.0"), acs_control),
+            endpoint(3, "0000:02:00.0"),
+            endpoint(4, "0000:02:01.0"),
+            port(0, 2, None, 0),
+            endpoint(5, "0000:00:02.0"),
+            port(0x80, 1, None, 0),
+            endpoint(0x81, "0000:80:01.0"),
+        ])
+    }
+
+    #[test]
+    fn test_p2p_same_switch() {
+        let topology = topology(ACS_SOURCE_VALIDATION);
+        let from = topology.find("0000:03:00.0").unwrap();
+        let to = topology.find("0000:04:00.0").unwrap();
+
+        let report = p2p_reachability(&topology, from, to);
+        assert_eq!(report.support, P2pSupport::Direct);
+        assert_eq!(report.common_upstream, topology.find("0000:01:00.0"));
+        let path: Vec<String> = report.path.iter().map(|id| topology.node(*id).device.address()).collect();
+        assert_eq!(path, vec!["0000:03:00.0", "0000:02:00.0", "0000:01:00.0", "0000:02:01.0", "0000:04:00.0"]);
+        assert!(report.acs_redirects.is_empty());
+        assert!(!report.crosses_root_complex);
+    }
+
+    #[test]
+    fn test_p2p_acs_redirect() {
+        let topology = topology(ACS_ISOLATION_FLAGS);
+        let from = topology.find("0000:03:00.0").unwrap();
+        let to = topology.find("0000:04:00.0").unwrap();
+
+        let report = p2p_reachability(&topology, from, to);
+        assert_eq!(report.support, P2pSupport::ThroughHostBridge);
+        assert_eq!(report.acs_redirects, vec![topology.find("0000:02:00.0").unwrap(), topology.find("0000:02:01.0").unwrap()]);
+        assert!(report.crosses_root_complex);
+    }
+
+    #[test]
+    fn test_p2p_different_root_ports() {
+        let topology = topology(0);
+        let from = topology.find("0000:03:00.0").unwrap();
+        let to = topology.find("0000:05:00.0").unwrap();
+
+        let report = p2p_reachability(&topology, from, to);
+        assert_eq!(report.support, P2pSupport::ThroughHostBridge);
+        assert_eq!(report.common_upstream, None);
+        assert_eq!(report.path.len(), 6);
+        assert!(report.crosses_root_complex);
+    }
+
+    #[test]
+    fn test_p2p_different_host_bridges() {
+        let topology = topology(0);
+        let from = topology.find("0000:05:00.0").unwrap();
+        let to = topology.find("0000:81:00.0").unwrap();
+
+        let report = p2p_reachability(&topology, from, to);
+        assert_eq!(report.support, P2pSupport::Unsupported);
+        assert!(report.crosses_root_complex);
+    }
+}
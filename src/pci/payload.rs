@@ -0,0 +1,344 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Max Payload Size and Max Read Request Size checks, and the kernel's tuning policies.
+
+use core::fmt;
+use std::fmt::Display;
+
+use crate::pci::express::{EXP_DEVICE_CAP, EXP_DEVICE_CONTROL};
+use crate::pci::topology::{NodeId, PciTopology};
+
+pub const DEVICE_CAP_PAYLOAD: u32 = 0x0007;
+pub const DEVICE_CONTROL_PAYLOAD: u16 = 0x00E0;
+pub const DEVICE_CONTROL_READ_REQUEST: u16 = 0x7000;
+
+// Sizes are encoded as 128 << n, with n up to 5 for 4096 bytes.
+fn decode_size(code: u16) -> u16 {
+    128 << code.min(5)
+}
+
+fn encode_size(bytes: u16) -> u16 {
+    (bytes.max(128) / 128).trailing_zeros() as u16
+}
+
+// A device's payload settings, all in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadSettings {
+    pub control_offset: usize, // Absolute offset of Device Control.
+    pub control: u16,
+    pub max_payload_supported: u16,
+    pub max_payload: u16,
+    pub max_read_request: u16,
+}
+
+impl PayloadSettings {
+    // Device Control with the payload and read request fields replaced.
+    pub fn control_with(&self, max_payload: u16, max_read_request: u16) -> u16 {
+        (self.control & !(DEVICE_CONTROL_PAYLOAD | DEVICE_CONTROL_READ_REQUEST))
+            | (encode_size(max_payload) << 5)
+            | (encode_size(max_read_request) << 12)
+    }
+}
+
+pub fn payload_settings(topology: &PciTopology, id: NodeId) -> Option<PayloadSettings> {
+    let pcie = topology.node(id).config.as_ref()?.pcie()?;
+    let capabilities = pcie.read_u32(EXP_DEVICE_CAP)?;
+    let control = pcie.read_u16(EXP_DEVICE_CONTROL)?;
+
+    Some(PayloadSettings {
+        control_offset: pcie.offset() + EXP_DEVICE_CONTROL,
+        control,
+        max_payload_supported: decode_size((capabilities & DEVICE_CAP_PAYLOAD) as u16),
+        max_payload: decode_size((control & DEVICE_CONTROL_PAYLOAD) >> 5),
+        max_read_request: decode_size((control & DEVICE_CONTROL_READ_REQUEST) >> 12),
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PayloadIssue {
+    // Set higher than the device says it can handle.
+    ExceedsCapability { device: NodeId, max_payload: u16, supported: u16 },
+    // Larger than the port above it. Any TLP bigger than the receiver's setting is malformed,
+    // which the kernel warns about and pcie_bus_safe or pcie_bus_perf would fix.
+    ParentMismatch { device: NodeId, parent: NodeId, max_payload: u16, parent_max_payload: u16 },
+    // Smaller than the port above it, with read requests larger than its payload size. The
+    // port may return completions up to its own payload size, which the device would reject.
+    // pcie_bus_perf avoids this by capping read requests at the payload size.
+    ReadRequestExceedsPayload { device: NodeId, parent: NodeId, max_read_request: u16, max_payload: u16, parent_max_payload: u16 },
+    // A reserved Max Read Request Size encoding, i.e. above 4096 bytes.
+    InvalidReadRequest { device: NodeId, encoding: u16 },
+}
+
+impl Display for PayloadIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PayloadIssue::ExceedsCapability { max_payload, supported, .. } => {
+                write!(f, "Max Payload Size {} exceeds the supported {}", max_payload, supported)
+            }
+            PayloadIssue::ParentMismatch { max_payload, parent_max_payload, .. } => {
+                write!(f, "Max Payload Size {} exceeds the upstream port's {}", max_payload, parent_max_payload)
+            }
+            PayloadIssue::ReadRequestExceedsPayload { max_read_request, max_payload, parent_max_payload, .. } => {
+                write!(
+                    f,
+                    "Max Read Request Size {} exceeds Max Payload Size {}, below an upstream port set to {}",
+                    max_read_request, max_payload, parent_max_payload
+                )
+            }
+            PayloadIssue::InvalidReadRequest { encoding, .. } => {
+                write!(f, "Max Read Request Size uses reserved encoding {}", encoding)
+            }
+        }
+    }
+}
+
+// Every payload problem along every root port to endpoint path.
+pub fn check_payload(topology: &PciTopology) -> Vec<PayloadIssue> {
+    let mut issues = Vec::new();
+    for id in 0..topology.len() {
+        let settings = match payload_settings(topology, id) {
+            Some(settings) => settings,
+            None => continue,
+        };
+
+        if settings.max_payload > settings.max_payload_supported {
+            issues.push(PayloadIssue::ExceedsCapability {
+                device: id,
+                max_payload: settings.max_payload,
+                supported: settings.max_payload_supported,
+            });
+        }
+
+        let encoding = (settings.control & DEVICE_CONTROL_READ_REQUEST) >> 12;
+        if encoding > 5 {
+            issues.push(PayloadIssue::InvalidReadRequest { device: id, encoding });
+        }
+
+        let parent = topology.parent(id).and_then(|parent| Some((parent, payload_settings(topology, parent)?)));
+        if let Some((parent, parent_settings)) = parent {
+            if settings.max_payload > parent_settings.max_payload {
+                issues.push(PayloadIssue::ParentMismatch {
+                    device: id,
+                    parent,
+                    max_payload: settings.max_payload,
+                    parent_max_payload: parent_settings.max_payload,
+                });
+            } else if settings.max_payload < parent_settings.max_payload && settings.max_read_request > settings.max_payload {
+                issues.push(PayloadIssue::ReadRequestExceedsPayload {
+                    device: id,
+                    parent,
+                    max_read_request: settings.max_read_request,
+                    max_payload: settings.max_payload,
+                    parent_max_payload: parent_settings.max_payload,
+                });
+            }
+        }
+    }
+    issues
+}
+
+// The kernel's pci=pcie_bus_* choices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadPolicy {
+    // Everything below a root port uses the smallest size any of them supports, which keeps
+    // peer to peer traffic safe. Read request sizes are left alone.
+    Safe,
+    // Each device uses the most it and the port above it can take, and read requests are
+    // capped at the device's payload size. Faster, but peer to peer TLPs may be too large.
+    Performance,
+}
+
+// A Device Control update that brings a device in line with a policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayloadChange {
+    pub device: NodeId,
+    pub control_offset: usize,
+    pub old_control: u16,
+    pub new_control: u16,
+    pub max_payload: u16,
+    pub max_read_request: u16,
+}
+
+// Work out the Device Control writes a policy needs, ports before the devices below them.
+// Devices already in line are left out.
+pub fn plan_payload(topology: &PciTopology, policy: PayloadPolicy) -> Vec<PayloadChange> {
+    let mut targets: Vec<Option<u16>> = vec![None; topology.len()];
+    let mut order = Vec::new();
+
+    for root in topology.roots() {
+        let tree: Vec<NodeId> = std::iter::once(root).chain(topology.descendants(root)).collect();
+        let smallest = tree
+            .iter()
+            .filter_map(|id| payload_settings(topology, *id))
+            .map(|settings| settings.max_payload_supported)
+            .min();
+
+        // Descendants come parent first, so a parent's target is always set before its children.
+        for id in tree {
+            order.push(id);
+            let settings = match payload_settings(topology, id) {
+                Some(settings) => settings,
+                None => continue,
+            };
+            targets[id] = match policy {
+                PayloadPolicy::Safe => smallest,
+                PayloadPolicy::Performance => {
+                    let parent = topology.parent(id).and_then(|parent| targets[parent]);
+                    Some(parent.map_or(settings.max_payload_supported, |parent| parent.min(settings.max_payload_supported)))
+                }
+            };
+        }
+    }
+
+    let mut changes = Vec::new();
+    for id in order {
+        let (settings, max_payload) = match (payload_settings(topology, id), targets[id]) {
+            (Some(settings), Some(target)) => (settings, target),
+            _ => continue,
+        };
+        let max_read_request = match policy {
+            PayloadPolicy::Safe => settings.max_read_request,
+            PayloadPolicy::Performance => max_payload,
+        };
+
+        let new_control = settings.control_with(max_payload, max_read_request);
+        if new_control != settings.control {
+            changes.push(PayloadChange {
+                device: id,
+                control_offset: settings.control_offset,
+                old_control: settings.control,
+                new_control,
+                max_payload,
+                max_read_request,
+            });
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::PciDevice;
+    use crate::pci::config::ConfigSpace;
+    use crate::pci::payload::{check_payload, plan_payload, PayloadChange, PayloadIssue, PayloadPolicy};
+    use crate::pci::topology::{PciTopology, TopologyDevice};
+
+    // A device with a PCIe capability at 0x40 supporting the given MPS code and set to another,
+    // with the given MRRS code.
+    fn device(bus: u8, parent: Option<&str>, supported: u8, configured: u8, read_request: u8) -> TopologyDevice {
+        let mut config = vec![0u8; 256];
+        config[0x06] = 0x10;
+        config[0x34] = 0x40;
+        config[0x40] = 0x10;
+        config[0x44] = supported;
+        config[0x48] = configured << 5;
+        config[0x49] = read_request << 4;
+
+        let mut device = TopologyDevice::new(PciDevice {
+            domain: 0,
+            bus,
+            device: 0,
+            function: 0,
+            label: String::new(),
+            vendor_id: 0x8086,
+            device_id: 0x1234,
+            subsys_device_id: 0,
+            subsys_vendor_id: 0,
+            class: 0x06,
+            subclass: 0x04,
+            programming_interface: 0,
+            revision_id: 0,
+        });
+        device.config = Some(ConfigSpace::new(config));
+        device.parent_address = parent.map(str::to_string);
+        device
+    }
+
+    // The topology as it would be after writing each change's Device Control.
+    fn apply(topology: &PciTopology, changes: &[PayloadChange]) -> PciTopology {
+        PciTopology::build(topology.nodes().iter().enumerate().map(|(id, node)| {
+            let mut config = node.config.as_ref().unwrap().as_bytes().to_vec();
+            if let Some(change) = changes.iter().find(|change| change.device == id) {
+                config[change.control_offset..change.control_offset + 2].copy_from_slice(&change.new_control.to_le_bytes());
+            }
+            let mut device = TopologyDevice::new(node.device.clone());
+            device.config = Some(ConfigSpace::new(config));
+            device.parent_address = node.parent.map(|parent| topology.node(parent).address());
+            device
+        }).collect())
+    }
+
+    #[test]
+    fn test_payload_policies() {
+        // Root port supports 512, switch 256, endpoint 1024 but set to 512. All read 512 bytes.
+        let topology = PciTopology::build(vec![
+            device(0, None, 2, 1, 2),
+            device(1, Some("0000:00:00.0"), 1, 1, 2),
+            device(2, Some("0000:01:00.0"), 3, 2, 2),
+        ]);
+
+        let issues = check_payload(&topology);
+        assert_eq!(issues.len(), 1);
+        assert!(matches!(issues[0], PayloadIssue::ParentMismatch { max_payload: 512, parent_max_payload: 256, .. }));
+
+        let safe = plan_payload(&topology, PayloadPolicy::Safe);
+        assert_eq!(safe.len(), 1);
+        assert_eq!((safe[0].max_payload, safe[0].max_read_request), (256, 512));
+        assert_eq!(safe[0].new_control, 0x2020);
+
+        // The root port goes up to 512, the switch and endpoint stay capped at 256.
+        let performance = plan_payload(&topology, PayloadPolicy::Performance);
+        let sizes: Vec<(u16, u16)> = performance.iter().map(|change| (change.max_payload, change.max_read_request)).collect();
+        assert_eq!(sizes, vec![(512, 512), (256, 256), (256, 256)]);
+
+        // Devices below a larger port are what the policy asks for, not a problem.
+        assert_eq!(check_payload(&apply(&topology, &performance)), vec![]);
+        assert_eq!(check_payload(&apply(&topology, &safe)), vec![]);
+    }
+
+    #[test]
+    fn test_read_request_checks() {
+        // An endpoint capped at 256 below a 512 byte root port, reading 1024 bytes at a time.
+        let topology = PciTopology::build(vec![
+            device(0, None, 2, 2, 2),
+            device(1, Some("0000:00:00.0"), 1, 1, 3),
+        ]);
+        let issues = check_payload(&topology);
+        assert_eq!(issues, vec![PayloadIssue::ReadRequestExceedsPayload { device: 1, parent: 0, max_read_request: 1024, max_payload: 256, parent_max_payload: 512 }]);
+
+        // Reading no more than its payload size is safe.
+        let topology = PciTopology::build(vec![
+            device(0, None, 2, 2, 2),
+            device(1, Some("0000:00:00.0"), 1, 1, 1),
+        ]);
+        assert_eq!(check_payload(&topology), vec![]);
+
+        let topology = PciTopology::build(vec![device(0, None, 2, 2, 6)]);
+        assert_eq!(check_payload(&topology), vec![PayloadIssue::InvalidReadRequest { device: 0, encoding: 6 }]);
+    }
+}
@@ -0,0 +1,179 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Decoding of the PCI Power Management capability.
+
+use core::fmt;
+use std::fmt::Display;
+
+use crate::pci::config::{ConfigSpace, CAP_ID_PM};
+
+// Offsets into the Power Management capability.
+pub const PM_CAPABILITIES: usize = 0x02;
+pub const PM_CONTROL_STATUS: usize = 0x04;
+
+pub const PM_CAP_D1: u16 = 0x0200;
+pub const PM_CAP_D2: u16 = 0x0400;
+pub const PM_CAP_PME_SHIFT: u16 = 11;
+
+pub const PM_CTRL_STATE_MASK: u16 = 0x0003;
+pub const PM_CTRL_NO_SOFT_RESET: u16 = 0x0008;
+pub const PM_CTRL_PME_ENABLE: u16 = 0x0100;
+pub const PM_CTRL_PME_STATUS: u16 = 0x8000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PowerState {
+    D0,
+    D1,
+    D2,
+    D3Hot,
+    D3Cold,
+    Unknown,
+}
+
+impl PowerState {
+    // The names Linux uses in power_state.
+    pub fn from_name(name: &str) -> PowerState {
+        match name {
+            "D0" => PowerState::D0,
+            "D1" => PowerState::D1,
+            "D2" => PowerState::D2,
+            "D3hot" => PowerState::D3Hot,
+            "D3cold" => PowerState::D3Cold,
+            _ => PowerState::Unknown,
+        }
+    }
+}
+
+// The PowerState field can't express D3cold; a device in D3cold can't be read at all.
+impl From<u16> for PowerState {
+    fn from(state: u16) -> Self {
+        match state & PM_CTRL_STATE_MASK {
+            0 => PowerState::D0,
+            1 => PowerState::D1,
+            2 => PowerState::D2,
+            _ => PowerState::D3Hot,
+        }
+    }
+}
+
+impl Display for PowerState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PowerState::D0 => write!(f, "D0"),
+            PowerState::D1 => write!(f, "D1"),
+            PowerState::D2 => write!(f, "D2"),
+            PowerState::D3Hot => write!(f, "D3hot"),
+            PowerState::D3Cold => write!(f, "D3cold"),
+            PowerState::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PowerManagement {
+    pub offset: usize,
+    pub version: u8,
+    pub d1_support: bool,
+    pub d2_support: bool,
+    pub pme_support: Vec<PowerState>, // The states the device can signal PME from.
+    pub state: PowerState,
+    pub no_soft_reset: bool, // Set when D3hot to D0 keeps the device's configuration.
+    pub pme_enabled: bool,
+    pub pme_status: bool,
+}
+
+impl PowerManagement {
+    pub fn find(config: &ConfigSpace) -> Option<PowerManagement> {
+        let offset = config.find_capability(CAP_ID_PM)?;
+        let capabilities = config.read_u16(offset + PM_CAPABILITIES)?;
+        let control = config.read_u16(offset + PM_CONTROL_STATUS)?;
+
+        let pme_states = [PowerState::D0, PowerState::D1, PowerState::D2, PowerState::D3Hot, PowerState::D3Cold];
+        let pme_support = pme_states
+            .into_iter()
+            .enumerate()
+            .filter(|(bit, _)| capabilities & (1 << (PM_CAP_PME_SHIFT + *bit as u16)) != 0)
+            .map(|(_, state)| state)
+            .collect();
+
+        Some(PowerManagement {
+            offset,
+            version: (capabilities & 0x7) as u8,
+            d1_support: capabilities & PM_CAP_D1 != 0,
+            d2_support: capabilities & PM_CAP_D2 != 0,
+            pme_support,
+            state: PowerState::from(control),
+            no_soft_reset: control & PM_CTRL_NO_SOFT_RESET != 0,
+            pme_enabled: control & PM_CTRL_PME_ENABLE != 0,
+            pme_status: control & PM_CTRL_PME_STATUS != 0,
+        })
+    }
+
+    // Every D-state the device can be put in. D0 and D3hot are mandatory.
+    pub fn supported_states(&self) -> Vec<PowerState> {
+        let mut states = vec![PowerState::D0];
+        if self.d1_support {
+            states.push(PowerState::D1);
+        }
+        if self.d2_support {
+            states.push(PowerState::D2);
+        }
+        states.push(PowerState::D3Hot);
+        states
+    }
+}
+
+impl ConfigSpace {
+    pub fn power_management(&self) -> Option<PowerManagement> {
+        PowerManagement::find(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pci::config::ConfigSpace;
+    use crate::pci::power::PowerState;
+
+    #[test]
+    fn test_power_management() {
+        let mut config = vec![0u8; 256];
+        config[0x06] = 0x10;
+        config[0x34] = 0x50;
+        config[0x50] = 0x01;
+        config[0x52..0x54].copy_from_slice(&0xC803u16.to_le_bytes()); // PME from D0, D3hot and D3cold.
+        config[0x54..0x56].copy_from_slice(&0x0103u16.to_le_bytes()); // D3hot, PME enabled.
+
+        let pm = ConfigSpace::new(config).power_management().unwrap();
+        assert_eq!(pm.version, 3);
+        assert_eq!(pm.supported_states(), vec![PowerState::D0, PowerState::D3Hot]);
+        assert_eq!(pm.pme_support, vec![PowerState::D0, PowerState::D3Hot, PowerState::D3Cold]);
+        assert_eq!(pm.state, PowerState::D3Hot);
+        assert!(pm.pme_enabled);
+        assert!(!pm.pme_status);
+    }
+}
@@ -0,0 +1,268 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! A database of known broken devices and the workarounds they need.
+
+use std::fs::read_to_string;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::backend::{PciDevice, PciEnumerationError};
+use crate::pci::matching::{PciDeviceId, PCI_ANY_ID};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuirkFlag {
+    NoMsi, // MSI does not work and legacy interrupts must be used.
+    BrokenAspmL0s,
+    BrokenAspmL1,
+    BrokenIntxMasking, // The Interrupt Disable bit in the command register is ignored.
+    RequiresBusReset, // Function level reset is broken, only a secondary bus reset works.
+    BogusBarSizes, // The BARs report sizes that do not match the hardware.
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quirk {
+    pub id: PciDeviceId<()>,
+    pub description: String,
+    pub flags: Vec<QuirkFlag>,
+}
+
+// Quirks we know about without any configuration, taken from the kernel's drivers/pci/quirks.c
+const BUILTIN_QUIRKS: [PciDeviceId<(&str, &[QuirkFlag])>; 8] = crate::pci_device_table![
+    device(0x1022, 0x7450) => ("AMD 8131 PCI-X bridge: MSI is broken", &[QuirkFlag::NoMsi]),
+    device(0x1b21, 0x1080) => ("ASMedia ASM1083/1085 PCIe to PCI bridge: ASPM L0s and L1 are broken", &[QuirkFlag::BrokenAspmL0s, QuirkFlag::BrokenAspmL1]),
+    device(0x1814, 0x0601) => ("Ralink RT2800 wireless: INTx masking is broken", &[QuirkFlag::BrokenIntxMasking]),
+    device(0x8086, 0x1572) => ("Intel X710 Ethernet: INTx masking is broken", &[QuirkFlag::BrokenIntxMasking]),
+    device(0x8086, 0x1502) => ("Intel 82579LM Ethernet: FLR is broken", &[QuirkFlag::RequiresBusReset]),
+    device(0x1022, 0x149c) => ("AMD Matisse USB 3.0 controller: FLR is broken", &[QuirkFlag::RequiresBusReset]),
+    device(0x5333, 0x8880) => ("S3 868: BAR 0 reports 32MB instead of 64MB", &[QuirkFlag::BogusBarSizes]),
+    device(0x5333, 0x88f0) => ("S3 968: BAR 0 reports 32MB instead of 64MB", &[QuirkFlag::BogusBarSizes]),
+];
+
+// IDs in a quirks file may be numbers or hex strings, since JSON has no hex literals. "*" and
+// "any" match anything.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum FileId {
+    Number(u32),
+    Text(String),
+}
+
+impl FileId {
+    fn value(&self) -> Result<u32, PciEnumerationError> {
+        match self {
+            FileId::Number(value) => Ok(*value),
+            FileId::Text(text) if text == "*" || text.eq_ignore_ascii_case("any") => Ok(PCI_ANY_ID),
+            FileId::Text(text) => Ok(u32::from_str_radix(text.trim_start_matches("0x"), 16)?),
+        }
+    }
+}
+
+fn any_id(id: &Option<FileId>) -> Result<u32, PciEnumerationError> {
+    id.as_ref().map_or(Ok(PCI_ANY_ID), FileId::value)
+}
+
+// One entry in a quirks file. Missing IDs match anything.
+#[derive(Debug, Clone, Deserialize)]
+struct FileQuirk {
+    vendor: Option<FileId>,
+    device: Option<FileId>,
+    subvendor: Option<FileId>,
+    subdevice: Option<FileId>,
+    class: Option<FileId>,
+    class_mask: Option<FileId>,
+    description: String,
+    #[serde(default)]
+    flags: Vec<QuirkFlag>,
+}
+
+// The layout of a quirks file: a list of [[quirk]] tables in TOML, or {"quirk": [...]} in JSON.
+#[derive(Debug, Clone, Deserialize)]
+struct QuirkFile {
+    #[serde(default)]
+    quirk: Vec<FileQuirk>,
+}
+
+impl QuirkFile {
+    fn into_quirks(self) -> Result<Vec<Quirk>, PciEnumerationError> {
+        self.quirk.into_iter()
+            .map(|entry| {
+                let class = entry.class.as_ref().map_or(Ok(0), FileId::value)?;
+                // A class without a mask compares the whole class code.
+                let class_mask = match (&entry.class_mask, &entry.class) {
+                    (Some(mask), _) => mask.value()?,
+                    (None, Some(_)) => 0xFFFFFF,
+                    (None, None) => 0,
+                };
+
+                Ok(Quirk {
+                    id: PciDeviceId {
+                        vendor: any_id(&entry.vendor)?,
+                        device: any_id(&entry.device)?,
+                        subvendor: any_id(&entry.subvendor)?,
+                        subdevice: any_id(&entry.subdevice)?,
+                        class,
+                        class_mask,
+                        driver_data: (),
+                    },
+                    description: entry.description,
+                    flags: entry.flags,
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QuirkDatabase {
+    quirks: Vec<Quirk>,
+}
+
+impl QuirkDatabase {
+    pub fn builtin() -> QuirkDatabase {
+        let quirks = BUILTIN_QUIRKS.iter()
+            .map(|entry| Quirk {
+                id: PciDeviceId {
+                    vendor: entry.vendor,
+                    device: entry.device,
+                    subvendor: entry.subvendor,
+                    subdevice: entry.subdevice,
+                    class: entry.class,
+                    class_mask: entry.class_mask,
+                    driver_data: (),
+                },
+                description: entry.driver_data.0.to_string(),
+                flags: entry.driver_data.1.to_vec(),
+            })
+            .collect();
+
+        QuirkDatabase { quirks }
+    }
+
+    pub fn from_toml(contents: &str) -> Result<QuirkDatabase, PciEnumerationError> {
+        let file: QuirkFile = toml::from_str(contents)?;
+        Ok(QuirkDatabase { quirks: file.into_quirks()? })
+    }
+
+    pub fn from_json(contents: &str) -> Result<QuirkDatabase, PciEnumerationError> {
+        let file: QuirkFile = serde_json::from_str(contents)?;
+        Ok(QuirkDatabase { quirks: file.into_quirks()? })
+    }
+
+    // Files ending in .json are read as JSON, anything else as TOML.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<QuirkDatabase, PciEnumerationError> {
+        let contents = read_to_string(&path)?;
+        match path.as_ref().extension().and_then(|extension| extension.to_str()) {
+            Some("json") => QuirkDatabase::from_json(&contents),
+            _ => QuirkDatabase::from_toml(&contents),
+        }
+    }
+
+    // The built in quirks followed by the ones in a user supplied file.
+    pub fn builtin_with<P: AsRef<Path>>(path: P) -> Result<QuirkDatabase, PciEnumerationError> {
+        let mut database = QuirkDatabase::builtin();
+        database.extend(QuirkDatabase::load(path)?);
+        Ok(database)
+    }
+
+    pub fn extend(&mut self, other: QuirkDatabase) {
+        self.quirks.extend(other.quirks);
+    }
+
+    pub fn quirks(&self) -> &[Quirk] {
+        &self.quirks
+    }
+
+    pub fn quirks_for(&self, device: &PciDevice) -> Vec<&Quirk> {
+        self.quirks.iter().filter(|quirk| quirk.id.matches(device)).collect()
+    }
+
+    // Every flag that applies to the device, once each.
+    pub fn flags_for(&self, device: &PciDevice) -> Vec<QuirkFlag> {
+        let mut flags = Vec::new();
+        for quirk in self.quirks_for(device) {
+            for flag in &quirk.flags {
+                if !flags.contains(flag) {
+                    flags.push(*flag);
+                }
+            }
+        }
+        flags
+    }
+
+    pub fn has_flag(&self, device: &PciDevice, flag: QuirkFlag) -> bool {
+        self.quirks_for(device).iter().any(|quirk| quirk.flags.contains(&flag))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::PciDevice;
+    use crate::pci::quirks::{QuirkDatabase, QuirkFlag};
+
+    fn device(vendor_id: u16, device_id: u16, class: u8) -> PciDevice {
+        PciDevice {
+            domain: 0,
+            bus: 1,
+            device: 0,
+            function: 0,
+            label: String::new(),
+            vendor_id,
+            device_id,
+            subsys_device_id: 0,
+            subsys_vendor_id: 0,
+            class,
+            subclass: 0x00,
+            programming_interface: 0x00,
+            revision_id: 0x00,
+        }
+    }
+
+    #[test]
+    fn test_quirk_files() {
+        let mut database = QuirkDatabase::builtin();
+        database.extend(QuirkDatabase::from_toml(r#"
+            [[quirk]]
+            vendor = 0x8086
+            device = 0x1572
+            description = "Riser firmware loses ASPM state"
+            flags = ["broken_aspm_l1"]
+        "#).unwrap());
+        database.extend(QuirkDatabase::from_json(r#"
+            {"quirk": [{"class": "0x030000", "description": "Every GPU needs a bus reset", "flags": ["requires_bus_reset"]}]}
+        "#).unwrap());
+
+        let nic = device(0x8086, 0x1572, 0x02);
+        assert_eq!(database.quirks_for(&nic).len(), 2);
+        assert_eq!(database.flags_for(&nic), vec![QuirkFlag::BrokenIntxMasking, QuirkFlag::BrokenAspmL1]);
+        assert!(database.has_flag(&device(0x10de, 0x2204, 0x03), QuirkFlag::RequiresBusReset));
+        assert!(database.quirks_for(&device(0x10de, 0x2204, 0x02)).is_empty());
+        assert!(QuirkDatabase::from_toml("[[quirk]]\ndescription = \"x\"\nflags = [\"not_a_flag\"]").is_err());
+    }
+}
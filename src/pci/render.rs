@@ -0,0 +1,304 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Rendering the topology as an lspci -t style tree or as a Graphviz DOT graph.
+
+use std::collections::BTreeMap;
+
+use crate::pci::topology::{NodeId, PciTopology, TopologyNode};
+
+// ############################## Begin lspci -t rendering ##############################
+
+// Matches the layout of lspci -t.
+pub fn render_tree(topology: &PciTopology) -> String {
+    TreeWriter::new(topology, None).render()
+}
+
+// Matches the layout of lspci -tv. Devices the name lookup knows nothing about are shown by ID.
+pub fn render_tree_verbose<F: Fn(&TopologyNode) -> Option<String>>(topology: &PciTopology, names: F) -> String {
+    TreeWriter::new(topology, Some(&names)).render()
+}
+
+type NameLookup<'a> = &'a dyn Fn(&TopologyNode) -> Option<String>;
+
+// This follows the way lspci draws its tree: a line buffer is filled in left to right, printed,
+// then blanked out except for the vertical bars that continue onto the next line.
+struct TreeWriter<'a> {
+    topology: &'a PciTopology,
+    names: Option<NameLookup<'a>>,
+    line: Vec<u8>,
+    output: String,
+}
+
+impl<'a> TreeWriter<'a> {
+    fn new(topology: &'a PciTopology, names: Option<NameLookup<'a>>) -> Self {
+        TreeWriter {
+            topology,
+            names,
+            line: Vec::new(),
+            output: String::new(),
+        }
+    }
+
+    fn render(mut self) -> String {
+        // Group the top level devices by the root bus they sit on.
+        let mut root_buses: BTreeMap<(u32, u8), Vec<NodeId>> = BTreeMap::new();
+        for root in self.topology.roots() {
+            let device = &self.topology.node(root).device;
+            root_buses.entry((device.domain, device.bus)).or_default().push(root);
+        }
+
+        let p = self.put(0, "-");
+        let count = root_buses.len();
+        for (index, ((domain, bus), devices)) in root_buses.into_iter().enumerate() {
+            let p = if count == 1 {
+                self.put(p, &format!("[{:04x}:{:02x}]-", domain, bus))
+            } else if index + 1 < count {
+                self.put(p, &format!("+-[{:04x}:{:02x}]-", domain, bus))
+            } else {
+                self.put(p, &format!("\\-[{:04x}:{:02x}]-", domain, bus))
+            };
+            self.bus(&devices, p);
+        }
+
+        self.output
+    }
+
+    fn put(&mut self, position: usize, text: &str) -> usize {
+        self.line.truncate(position);
+        self.line.extend_from_slice(text.as_bytes());
+        self.line.len()
+    }
+
+    fn print_line(&mut self, position: usize) {
+        self.line.truncate(position);
+        self.output.push_str(&String::from_utf8_lossy(&self.line));
+        self.output.push('\n');
+
+        for byte in self.line.iter_mut() {
+            *byte = if *byte == b'+' || *byte == b'|' { b'|' } else { b' ' };
+        }
+    }
+
+    fn bus(&mut self, devices: &[NodeId], position: usize) {
+        match devices {
+            [] => self.print_line(position),
+            [device] => {
+                let position = self.put(position, "--");
+                self.device(*device, position);
+            }
+            [rest @ .., last] => {
+                for device in rest {
+                    let position = self.put(position, "+-");
+                    self.device(*device, position);
+                }
+                let position = self.put(position, "\\-");
+                self.device(*last, position);
+            }
+        }
+    }
+
+    fn device(&mut self, id: NodeId, position: usize) {
+        let node = self.topology.node(id);
+        let position = self.put(position, &format!("{:02x}.{:x}", node.device.device, node.device.function));
+
+        if let Some((secondary, subordinate)) = bridge_range(self.topology, id) {
+            let range = if secondary == subordinate {
+                format!("-[{:02x}]-", secondary)
+            } else {
+                format!("-[{:02x}-{:02x}]-", secondary, subordinate)
+            };
+            let position = self.put(position, &range);
+            let position = self.put(position, "-");
+            let children = self.topology.children(id).to_vec();
+            self.bus(&children, position);
+            return;
+        }
+
+        let position = match self.names {
+            Some(names) => {
+                let name = names(node).unwrap_or_else(|| format!("Device {:04x}:{:04x}", node.device.vendor_id, node.device.device_id));
+                self.put(position, &format!("  {}", name))
+            }
+            None => position,
+        };
+        self.print_line(position);
+    }
+}
+
+// The buses behind a bridge, from its config space or failing that from what sits behind it.
+fn bridge_range(topology: &PciTopology, id: NodeId) -> Option<(u8, u8)> {
+    let node = topology.node(id);
+    if let Some((_, secondary, subordinate)) = node.config.as_ref().and_then(|config| config.bridge_buses()) {
+        return Some((secondary, subordinate));
+    }
+    if !node.is_bridge() || node.children.is_empty() {
+        return None;
+    }
+
+    let secondary = topology.node(node.children[0]).device.bus;
+    let subordinate = topology.descendants(id).into_iter().map(|descendant| topology.node(descendant).device.bus).max()?;
+    Some((secondary, subordinate))
+}
+// ############################## End lspci -t rendering ##############################
+
+// ############################## Begin Graphviz rendering ##############################
+
+// The attributes every node gets: IDs, class and, for PCIe devices, the link.
+pub fn node_attributes(node: &TopologyNode) -> Vec<(String, String)> {
+    let device = &node.device;
+    let mut attributes = vec![
+        ("vendor".to_string(), format!("{:04x}", device.vendor_id)),
+        ("device".to_string(), format!("{:04x}", device.device_id)),
+        ("class".to_string(), format!("{:02x}{:02x}", device.class, device.subclass)),
+    ];
+
+    if let Some(pcie) = node.config.as_ref().and_then(|config| config.pcie()) {
+        if let Some(port_type) = pcie.port_type() {
+            attributes.push(("port_type".to_string(), port_type.to_string()));
+        }
+        if let Some(link) = pcie.link_status() {
+            attributes.push(("link_speed".to_string(), link.speed.to_string()));
+            attributes.push(("link_width".to_string(), format!("x{}", link.width)));
+        }
+    }
+
+    attributes
+}
+
+// Render the tree as a DOT digraph. The closure adds per node attributes on top of
+// node_attributes, such as the bound driver; "name" and "driver" also show up in the label.
+pub fn render_dot<F: Fn(&TopologyNode) -> Vec<(String, String)>>(topology: &PciTopology, extra_attributes: F) -> String {
+    let mut output = String::from("digraph pci {\n\trankdir=LR;\n\tnode [shape=box, fontname=\"monospace\"];\n");
+
+    let mut root_complexes: Vec<String> = Vec::new();
+    for root in topology.roots() {
+        let root_complex = topology.root_complex(root);
+        if !root_complexes.contains(&root_complex) {
+            output.push_str(&format!("\t\"{}\" [shape=ellipse];\n", escape(&root_complex)));
+            root_complexes.push(root_complex);
+        }
+    }
+
+    for (id, node) in topology.nodes().iter().enumerate() {
+        let mut attributes = node_attributes(node);
+        attributes.extend(extra_attributes(node));
+
+        let lookup = |key: &str| attributes.iter().find(|(name, _)| name == key).map(|(_, value)| value.clone());
+        let mut label = vec![node.address()];
+        label.extend(lookup("name"));
+        label.push(format!("class {}", lookup("class").unwrap_or_default()));
+        if let (Some(speed), Some(width)) = (lookup("link_speed"), lookup("link_width")) {
+            label.push(format!("{} {}", speed, width));
+        }
+        label.extend(lookup("driver"));
+
+        let label: Vec<String> = label.iter().map(|line| escape(line)).collect();
+        output.push_str(&format!("\t\"{}\" [label=\"{}\"", node.address(), label.join("\\n")));
+        for (name, value) in &attributes {
+            output.push_str(&format!(", {}=\"{}\"", name, escape(value)));
+        }
+        output.push_str("];\n");
+
+        let parent = match node.parent {
+            Some(parent) => topology.node(parent).address(),
+            None => topology.root_complex(id),
+        };
+        output.push_str(&format!("\t\"{}\" -> \"{}\";\n", escape(&parent), node.address()));
+    }
+
+    output.push_str("}\n");
+    output
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+// ############################## End Graphviz rendering ##############################
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::PciDevice;
+    use crate::pci::render::{render_dot, render_tree, render_tree_verbose};
+    use crate::pci::topology::{PciTopology, TopologyDevice};
+
+    fn entry(bus: u8, device: u8, function: u8, parent: Option<&str>) -> TopologyDevice {
+        let mut entry = TopologyDevice::new(PciDevice {
+            domain: 0,
+            bus,
+            device,
+            function,
+            label: String::new(),
+            vendor_id: 0x8086,
+            device_id: 0x1234,
+            subsys_device_id: 0,
+            subsys_vendor_id: 0,
+            class: if parent.is_none() && device != 0 { 0x06 } else { 0x02 },
+            subclass: if parent.is_none() && device != 0 { 0x04 } else { 0x00 },
+            programming_interface: 0,
+            revision_id: 0,
+        });
+        entry.parent_address = parent.map(str::to_string);
+        entry
+    }
+
+    fn topology() -> PciTopology {
+        PciTopology::build(vec![
+            entry(0, 0, 0, None),
+            entry(0, 1, 0, None),
+            entry(1, 0, 0, Some("0000:00:01.0")),
+            entry(1, 0, 1, Some("0000:00:01.0")),
+            entry(0, 0x1c, 0, None),
+            entry(2, 0, 0, Some("0000:00:1c.0")),
+            entry(0, 0x1f, 0, None),
+        ])
+    }
+
+    #[test]
+    fn test_lspci_tree_layout() {
+        let expected = concat!(
+            "-[0000:00]-+-00.0\n",
+            "           +-01.0-[01]--+-00.0\n",
+            "           |            \\-00.1\n",
+            "           +-1c.0-[02]----00.0\n",
+            "           \\-1f.0\n",
+        );
+        assert_eq!(render_tree(&topology()), expected);
+
+        let verbose = render_tree_verbose(&topology(), |_| Some("Intel Corporation Device".to_string()));
+        assert!(verbose.starts_with("-[0000:00]-+-00.0  Intel Corporation Device\n"));
+    }
+
+    #[test]
+    fn test_dot_output() {
+        let dot = render_dot(&topology(), |_| vec![("driver".to_string(), "e1000e".to_string())]);
+        assert!(dot.starts_with("digraph pci {\n"));
+        assert!(dot.contains("\t\"0000:00:1c.0\" -> \"0000:02:00.0\";\n"));
+        assert!(dot.contains("\t\"pci0000:00\" -> \"0000:00:00.0\";\n"));
+        assert!(dot.contains("driver=\"e1000e\""));
+    }
+}
@@ -0,0 +1,337 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Parsing of PCI expansion (option) ROM images.
+
+use core::fmt;
+use std::fmt::Display;
+
+use crate::backend::PciEnumerationError;
+use crate::pci::{read_u16, read_u32, read_u8};
+
+// Every image in a ROM starts with 0x55 0xAA and is a multiple of 512 bytes long.
+const ROM_SIGNATURE: u16 = 0xAA55;
+const PCIR_SIGNATURE: &[u8; 4] = b"PCIR";
+const EFI_SIGNATURE: u32 = 0x0EF1;
+const ROM_BLOCK_SIZE: usize = 512;
+
+// Offsets into the ROM header.
+const ROM_PCIR_POINTER: usize = 0x18;
+const ROM_EFI_INIT_SIZE: usize = 0x02;
+const ROM_EFI_SIGNATURE: usize = 0x04;
+const ROM_EFI_SUBSYSTEM: usize = 0x08;
+const ROM_EFI_MACHINE_TYPE: usize = 0x0A;
+const ROM_EFI_COMPRESSION: usize = 0x0C;
+const ROM_EFI_IMAGE_OFFSET: usize = 0x16;
+
+// Offsets into the PCI data structure.
+const PCIR_VENDOR_ID: usize = 0x04;
+const PCIR_DEVICE_ID: usize = 0x06;
+const PCIR_DEVICE_LIST: usize = 0x08;
+const PCIR_LENGTH: usize = 0x0A;
+const PCIR_REVISION: usize = 0x0C;
+const PCIR_PROG_IF: usize = 0x0D;
+const PCIR_SUBCLASS: usize = 0x0E;
+const PCIR_CLASS: usize = 0x0F;
+const PCIR_IMAGE_LENGTH: usize = 0x10;
+const PCIR_CODE_REVISION: usize = 0x12;
+const PCIR_CODE_TYPE: usize = 0x14;
+const PCIR_INDICATOR: usize = 0x15;
+const PCIR_MAX_RUNTIME_LENGTH: usize = 0x16;
+
+const PCIR_INDICATOR_LAST_IMAGE: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RomCodeType {
+    X86Bios,
+    OpenFirmware,
+    HpPaRisc,
+    Efi,
+    Unknown(u8),
+}
+
+impl From<u8> for RomCodeType {
+    fn from(code_type: u8) -> Self {
+        match code_type {
+            0x00 => RomCodeType::X86Bios,
+            0x01 => RomCodeType::OpenFirmware,
+            0x02 => RomCodeType::HpPaRisc,
+            0x03 => RomCodeType::Efi,
+            other => RomCodeType::Unknown(other),
+        }
+    }
+}
+
+impl Display for RomCodeType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomCodeType::X86Bios => write!(f, "x86 PC-AT BIOS"),
+            RomCodeType::OpenFirmware => write!(f, "Open Firmware"),
+            RomCodeType::HpPaRisc => write!(f, "HP PA-RISC"),
+            RomCodeType::Efi => write!(f, "EFI"),
+            RomCodeType::Unknown(code_type) => write!(f, "Unknown ({:02x})", code_type),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EfiSubsystem {
+    Application,
+    BootServiceDriver,
+    RuntimeDriver,
+    Unknown(u16),
+}
+
+impl From<u16> for EfiSubsystem {
+    fn from(subsystem: u16) -> Self {
+        match subsystem {
+            10 => EfiSubsystem::Application,
+            11 => EfiSubsystem::BootServiceDriver,
+            12 => EfiSubsystem::RuntimeDriver,
+            other => EfiSubsystem::Unknown(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EfiMachineType {
+    Ia32,
+    Itanium,
+    EfiByteCode,
+    X64,
+    Arm,
+    Aarch64,
+    RiscV32,
+    RiscV64,
+    LoongArch64,
+    Unknown(u16),
+}
+
+impl From<u16> for EfiMachineType {
+    fn from(machine_type: u16) -> Self {
+        match machine_type {
+            0x014C => EfiMachineType::Ia32,
+            0x0200 => EfiMachineType::Itanium,
+            0x0EBC => EfiMachineType::EfiByteCode,
+            0x8664 => EfiMachineType::X64,
+            0x01C2 => EfiMachineType::Arm,
+            0xAA64 => EfiMachineType::Aarch64,
+            0x5032 => EfiMachineType::RiscV32,
+            0x5064 => EfiMachineType::RiscV64,
+            0x6264 => EfiMachineType::LoongArch64,
+            other => EfiMachineType::Unknown(other),
+        }
+    }
+}
+
+// The EFI specific part of the ROM header, only present on EFI images.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EfiRomHeader {
+    pub initialization_size: usize, // In bytes.
+    pub subsystem: EfiSubsystem,
+    pub machine_type: EfiMachineType,
+    pub compressed: bool,
+    pub image_offset: u16, // Offset of the PE image from the start of this ROM image.
+}
+
+// A single image in the ROM chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomImage {
+    pub offset: usize, // Offset of this image from the start of the ROM.
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub device_list_pointer: u16,
+    pub pcir_length: u16,
+    pub pcir_revision: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub programming_interface: u8,
+    pub length: usize, // In bytes.
+    pub code_revision: u16,
+    pub code_type: RomCodeType,
+    pub last_image: bool,
+    pub max_runtime_length: Option<usize>, // In bytes, only in PCIR revision 3 and later.
+    pub efi: Option<EfiRomHeader>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpansionRom {
+    pub images: Vec<RomImage>,
+}
+
+impl ExpansionRom {
+    // Walk the image chain. The first image must be valid; parsing stops quietly at the first
+    // malformed image after it, since many ROMs are padded with garbage past the last image.
+    pub fn parse(data: &[u8]) -> Result<ExpansionRom, PciEnumerationError> {
+        let mut images = Vec::new();
+        let mut offset = 0;
+
+        loop {
+            let image = match parse_image(data, offset) {
+                Some(image) => image,
+                None if images.is_empty() => return Err(PciEnumerationError::InvalidRom),
+                None => break,
+            };
+
+            let next = offset + image.length;
+            let last = image.last_image || image.length == 0;
+            images.push(image);
+
+            if last || next >= data.len() {
+                break;
+            }
+            offset = next;
+        }
+
+        Ok(ExpansionRom { images })
+    }
+
+    pub fn efi_images(&self) -> impl Iterator<Item = &RomImage> {
+        self.images.iter().filter(|image| image.code_type == RomCodeType::Efi)
+    }
+
+    // Whether the ROM carries a UEFI driver (e.g. a GOP driver on a GPU) for the given machine type.
+    pub fn has_uefi_driver(&self, machine_type: EfiMachineType) -> bool {
+        self.efi_images().any(|image| match &image.efi {
+            Some(efi) => efi.subsystem == EfiSubsystem::BootServiceDriver && efi.machine_type == machine_type,
+            None => false,
+        })
+    }
+
+    pub fn has_legacy_bios(&self) -> bool {
+        self.images.iter().any(|image| image.code_type == RomCodeType::X86Bios)
+    }
+}
+
+fn parse_image(data: &[u8], offset: usize) -> Option<RomImage> {
+    let image = data.get(offset..)?;
+    if read_u16(image, 0)? != ROM_SIGNATURE {
+        return None;
+    }
+
+    let pcir_offset = read_u16(image, ROM_PCIR_POINTER)? as usize;
+    let pcir = image.get(pcir_offset..)?;
+    if pcir.get(0..4)? != PCIR_SIGNATURE {
+        return None;
+    }
+
+    let pcir_revision = read_u8(pcir, PCIR_REVISION)?;
+    let code_type = RomCodeType::from(read_u8(pcir, PCIR_CODE_TYPE)?);
+
+    let max_runtime_length = if pcir_revision >= 3 {
+        Some(read_u16(pcir, PCIR_MAX_RUNTIME_LENGTH)? as usize * ROM_BLOCK_SIZE)
+    } else {
+        None
+    };
+
+    let efi = if code_type == RomCodeType::Efi && read_u32(image, ROM_EFI_SIGNATURE)? == EFI_SIGNATURE {
+        Some(EfiRomHeader {
+            initialization_size: read_u16(image, ROM_EFI_INIT_SIZE)? as usize * ROM_BLOCK_SIZE,
+            subsystem: EfiSubsystem::from(read_u16(image, ROM_EFI_SUBSYSTEM)?),
+            machine_type: EfiMachineType::from(read_u16(image, ROM_EFI_MACHINE_TYPE)?),
+            compressed: read_u16(image, ROM_EFI_COMPRESSION)? != 0,
+            image_offset: read_u16(image, ROM_EFI_IMAGE_OFFSET)?,
+        })
+    } else {
+        None
+    };
+
+    Some(RomImage {
+        offset,
+        vendor_id: read_u16(pcir, PCIR_VENDOR_ID)?,
+        device_id: read_u16(pcir, PCIR_DEVICE_ID)?,
+        device_list_pointer: read_u16(pcir, PCIR_DEVICE_LIST)?,
+        pcir_length: read_u16(pcir, PCIR_LENGTH)?,
+        pcir_revision,
+        class: read_u8(pcir, PCIR_CLASS)?,
+        subclass: read_u8(pcir, PCIR_SUBCLASS)?,
+        programming_interface: read_u8(pcir, PCIR_PROG_IF)?,
+        length: read_u16(pcir, PCIR_IMAGE_LENGTH)? as usize * ROM_BLOCK_SIZE,
+        code_revision: read_u16(pcir, PCIR_CODE_REVISION)?,
+        code_type,
+        last_image: read_u8(pcir, PCIR_INDICATOR)? & PCIR_INDICATOR_LAST_IMAGE != 0,
+        max_runtime_length,
+        efi,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pci::rom::{EfiMachineType, EfiSubsystem, ExpansionRom, RomCodeType};
+
+    // Build a single 512 byte image with a PCIR structure at 0x1C.
+    fn build_image(code_type: u8, last: bool) -> Vec<u8> {
+        let mut image = vec![0u8; 512];
+        image[0] = 0x55;
+        image[1] = 0xAA;
+        image[0x18..0x1A].copy_from_slice(&0x1Cu16.to_le_bytes());
+
+        let pcir = 0x1C;
+        image[pcir..pcir + 4].copy_from_slice(b"PCIR");
+        image[pcir + 0x04..pcir + 0x06].copy_from_slice(&0x10DEu16.to_le_bytes());
+        image[pcir + 0x06..pcir + 0x08].copy_from_slice(&0x1B80u16.to_le_bytes());
+        image[pcir + 0x0A..pcir + 0x0C].copy_from_slice(&0x18u16.to_le_bytes());
+        image[pcir + 0x0C] = 3;
+        image[pcir + 0x0F] = 0x03;
+        image[pcir + 0x10..pcir + 0x12].copy_from_slice(&1u16.to_le_bytes());
+        image[pcir + 0x14] = code_type;
+        image[pcir + 0x15] = if last { 0x80 } else { 0x00 };
+
+        if code_type == 0x03 {
+            image[0x04..0x08].copy_from_slice(&0x0EF1u32.to_le_bytes());
+            image[0x08..0x0A].copy_from_slice(&11u16.to_le_bytes());
+            image[0x0A..0x0C].copy_from_slice(&0x8664u16.to_le_bytes());
+        }
+
+        image
+    }
+
+    #[test]
+    fn test_rom_image_chain() {
+        let mut rom = build_image(0x00, false);
+        rom.extend(build_image(0x03, true));
+        rom.extend(vec![0xFF; 512]); // Padding past the last image must be ignored.
+
+        let parsed = ExpansionRom::parse(&rom).unwrap();
+        assert_eq!(parsed.images.len(), 2);
+        assert_eq!(parsed.images[0].code_type, RomCodeType::X86Bios);
+        assert_eq!(parsed.images[0].vendor_id, 0x10DE);
+        assert_eq!(parsed.images[1].offset, 512);
+        assert!(parsed.images[1].last_image);
+
+        let efi = parsed.images[1].efi.as_ref().unwrap();
+        assert_eq!(efi.subsystem, EfiSubsystem::BootServiceDriver);
+        assert_eq!(efi.machine_type, EfiMachineType::X64);
+        assert!(parsed.has_legacy_bios());
+        assert!(parsed.has_uefi_driver(EfiMachineType::X64));
+    }
+
+    #[test]
+    fn test_rom_bad_signature() {
+        assert!(ExpansionRom::parse(&[0u8; 512]).is_err());
+    }
+}
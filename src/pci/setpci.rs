@@ -0,0 +1,301 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Parsing of setpci style register expressions, e.g. COMMAND.w=0x0406:0x0406 or CAP_EXP+10.w
+
+use crate::backend::PciEnumerationError;
+use crate::pci::config::ConfigSpace;
+
+// The header registers setpci knows by name, with their natural widths.
+const NAMED_REGISTERS: &[(&str, usize, usize)] = &[
+    ("VENDOR_ID", 0x00, 2),
+    ("DEVICE_ID", 0x02, 2),
+    ("COMMAND", 0x04, 2),
+    ("STATUS", 0x06, 2),
+    ("REVISION", 0x08, 1),
+    ("CLASS_PROG", 0x09, 1),
+    ("CLASS_DEVICE", 0x0A, 2),
+    ("CACHE_LINE_SIZE", 0x0C, 1),
+    ("LATENCY_TIMER", 0x0D, 1),
+    ("HEADER_TYPE", 0x0E, 1),
+    ("BIST", 0x0F, 1),
+    ("BASE_ADDRESS_0", 0x10, 4),
+    ("BASE_ADDRESS_1", 0x14, 4),
+    ("BASE_ADDRESS_2", 0x18, 4),
+    ("BASE_ADDRESS_3", 0x1C, 4),
+    ("BASE_ADDRESS_4", 0x20, 4),
+    ("BASE_ADDRESS_5", 0x24, 4),
+    ("CARDBUS_CIS", 0x28, 4),
+    ("SUBSYSTEM_VENDOR_ID", 0x2C, 2),
+    ("SUBSYSTEM_ID", 0x2E, 2),
+    ("ROM_ADDRESS", 0x30, 4),
+    ("CAPABILITIES", 0x34, 1),
+    ("INTERRUPT_LINE", 0x3C, 1),
+    ("INTERRUPT_PIN", 0x3D, 1),
+    ("MIN_GNT", 0x3E, 1),
+    ("MAX_LAT", 0x3F, 1),
+    ("PRIMARY_BUS", 0x18, 1),
+    ("SECONDARY_BUS", 0x19, 1),
+    ("SUBORDINATE_BUS", 0x1A, 1),
+    ("SEC_LATENCY_TIMER", 0x1B, 1),
+    ("IO_BASE", 0x1C, 1),
+    ("IO_LIMIT", 0x1D, 1),
+    ("SEC_STATUS", 0x1E, 2),
+    ("MEMORY_BASE", 0x20, 2),
+    ("MEMORY_LIMIT", 0x22, 2),
+    ("PREF_MEMORY_BASE", 0x24, 2),
+    ("PREF_MEMORY_LIMIT", 0x26, 2),
+    ("PREF_BASE_UPPER32", 0x28, 4),
+    ("PREF_LIMIT_UPPER32", 0x2C, 4),
+    ("IO_BASE_UPPER16", 0x30, 2),
+    ("IO_LIMIT_UPPER16", 0x32, 2),
+    ("BRIDGE_ROM_ADDRESS", 0x38, 4),
+    ("BRIDGE_CONTROL", 0x3E, 2),
+];
+
+const CAPABILITY_NAMES: &[(&str, u8)] = &[
+    ("PM", 0x01),
+    ("AGP", 0x02),
+    ("VPD", 0x03),
+    ("SLOTID", 0x04),
+    ("MSI", 0x05),
+    ("CHSWP", 0x06),
+    ("PCIX", 0x07),
+    ("HT", 0x08),
+    ("VNDR", 0x09),
+    ("DBG", 0x0A),
+    ("CCRC", 0x0B),
+    ("HOTPLUG", 0x0C),
+    ("SSVID", 0x0D),
+    ("AGP3", 0x0E),
+    ("SECURE", 0x0F),
+    ("EXP", 0x10),
+    ("MSIX", 0x11),
+    ("SATA", 0x12),
+    ("AF", 0x13),
+    ("EA", 0x14),
+];
+
+const EXTENDED_CAPABILITY_NAMES: &[(&str, u16)] = &[
+    ("AER", 0x01),
+    ("VC", 0x02),
+    ("DSN", 0x03),
+    ("PB", 0x04),
+    ("RCLINK", 0x05),
+    ("RCILINK", 0x06),
+    ("RCEC", 0x07),
+    ("MFVC", 0x08),
+    ("VC2", 0x09),
+    ("RBCB", 0x0A),
+    ("VNDR", 0x0B),
+    ("ACS", 0x0D),
+    ("ARI", 0x0E),
+    ("ATS", 0x0F),
+    ("SRIOV", 0x10),
+    ("MRIOV", 0x11),
+    ("MCAST", 0x12),
+    ("PRI", 0x13),
+    ("REBAR", 0x15),
+    ("DPA", 0x16),
+    ("TPH", 0x17),
+    ("LTR", 0x18),
+    ("SECPCI", 0x19),
+    ("PMUX", 0x1A),
+    ("PASID", 0x1B),
+    ("LNR", 0x1C),
+    ("DPC", 0x1D),
+    ("L1PM", 0x1E),
+    ("PTM", 0x1F),
+    ("DLF", 0x25),
+    ("PL16", 0x26),
+];
+
+// What an expression's offset is relative to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterBase {
+    Header,
+    Capability(u8),
+    ExtendedCapability(u16),
+}
+
+// A value to write and the bits of it that matter. Bits outside the mask keep their old value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaskedValue {
+    pub value: u32,
+    pub mask: u32,
+}
+
+impl MaskedValue {
+    pub fn apply(&self, current: u32) -> u32 {
+        (current & !self.mask) | (self.value & self.mask)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetpciExpression {
+    pub base: RegisterBase,
+    pub offset: usize,
+    pub width: usize, // In bytes: 1, 2 or 4.
+    pub values: Vec<MaskedValue>, // Empty for a read. Several values fill consecutive registers.
+}
+
+fn invalid(expression: &str, reason: &str) -> PciEnumerationError {
+    PciEnumerationError::InvalidExpression(format!("{}: {}", expression, reason))
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    let text = text.trim();
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).unwrap_or(text);
+    u32::from_str_radix(digits, 16).ok()
+}
+
+fn lookup<T: Copy>(table: &[(&str, T)], name: &str) -> Option<T> {
+    table.iter().find(|(entry, _)| entry.eq_ignore_ascii_case(name)).map(|(_, value)| *value)
+}
+
+impl SetpciExpression {
+    // Parse register[+offset][.width][=value[:mask][,value[:mask]...]]. As in setpci, numbers
+    // are hex, and a width is only optional for named header registers.
+    pub fn parse(expression: &str) -> Result<SetpciExpression, PciEnumerationError> {
+        let (register, values) = match expression.split_once('=') {
+            Some((register, values)) => (register.trim(), Some(values)),
+            None => (expression.trim(), None),
+        };
+
+        let (register, width) = match register.rsplit_once('.') {
+            Some((register, suffix)) => {
+                let width = match suffix.to_ascii_lowercase().as_str() {
+                    "b" => 1,
+                    "w" => 2,
+                    "l" => 4,
+                    _ => return Err(invalid(expression, "width must be .b, .w or .l")),
+                };
+                (register, Some(width))
+            }
+            None => (register, None),
+        };
+
+        let (name, extra) = match register.split_once('+') {
+            Some((name, extra)) => (name, parse_hex(extra).ok_or_else(|| invalid(expression, "bad offset"))? as usize),
+            None => (register, 0),
+        };
+
+        let upper = name.to_ascii_uppercase();
+        let (base, offset, natural_width) = if let Some(capability) = upper.strip_prefix("ECAP") {
+            let id = match capability.strip_prefix('_') {
+                Some(capability) => lookup(EXTENDED_CAPABILITY_NAMES, capability),
+                None => parse_hex(capability).and_then(|id| u16::try_from(id).ok()),
+            };
+            (RegisterBase::ExtendedCapability(id.ok_or_else(|| invalid(expression, "unknown extended capability"))?), 0, None)
+        } else if let Some(capability) = upper.strip_prefix("CAP").filter(|rest| !rest.starts_with("ABILITIES")) {
+            let id = match capability.strip_prefix('_') {
+                Some(capability) => lookup(CAPABILITY_NAMES, capability),
+                None => parse_hex(capability).and_then(|id| u8::try_from(id).ok()),
+            };
+            (RegisterBase::Capability(id.ok_or_else(|| invalid(expression, "unknown capability"))?), 0, None)
+        } else if let Some(index) = NAMED_REGISTERS.iter().position(|(entry, _, _)| *entry == upper) {
+            let (_, offset, width) = NAMED_REGISTERS[index];
+            (RegisterBase::Header, offset, Some(width))
+        } else {
+            let offset = parse_hex(name).ok_or_else(|| invalid(expression, "unknown register"))?;
+            (RegisterBase::Header, offset as usize, None)
+        };
+
+        let width = width.or(natural_width).ok_or_else(|| invalid(expression, "missing width"))?;
+        let full_mask = match width {
+            4 => u32::MAX,
+            width => (1u32 << (width * 8)) - 1,
+        };
+
+        let mut masked_values = Vec::new();
+        for value in values.into_iter().flat_map(|values| values.split(',')) {
+            let (value, mask) = match value.split_once(':') {
+                Some((value, mask)) => (parse_hex(value), parse_hex(mask)),
+                None => (parse_hex(value), Some(full_mask)),
+            };
+            let (value, mask) = value.zip(mask).ok_or_else(|| invalid(expression, "bad value"))?;
+            if value > full_mask || mask > full_mask {
+                return Err(invalid(expression, "value wider than the register"));
+            }
+            masked_values.push(MaskedValue { value, mask });
+        }
+        if values.is_some() && masked_values.is_empty() {
+            return Err(invalid(expression, "missing value"));
+        }
+
+        Ok(SetpciExpression {
+            base,
+            offset: offset + extra,
+            width,
+            values: masked_values,
+        })
+    }
+
+    pub fn is_write(&self) -> bool {
+        !self.values.is_empty()
+    }
+
+    // The absolute config space offset, or None if the device lacks the capability.
+    pub fn resolve(&self, config: &ConfigSpace) -> Option<usize> {
+        let base = match self.base {
+            RegisterBase::Header => 0,
+            RegisterBase::Capability(id) => config.find_capability(id)?,
+            RegisterBase::ExtendedCapability(id) => config.find_extended_capability(id)?,
+        };
+        Some(base + self.offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pci::setpci::{MaskedValue, RegisterBase, SetpciExpression};
+
+    #[test]
+    fn test_parse_expressions() {
+        let command = SetpciExpression::parse("COMMAND").unwrap();
+        assert_eq!((command.base, command.offset, command.width), (RegisterBase::Header, 0x04, 2));
+        assert!(!command.is_write());
+
+        let link_control = SetpciExpression::parse("CAP_EXP+10.w=0040:0040").unwrap();
+        assert_eq!(link_control.base, RegisterBase::Capability(0x10));
+        assert_eq!(link_control.offset, 0x10);
+        assert_eq!(link_control.values, vec![MaskedValue { value: 0x40, mask: 0x40 }]);
+        assert_eq!(link_control.values[0].apply(0x0003), 0x0043);
+
+        let aer = SetpciExpression::parse("ecap_aer+4.l=0,ffffffff").unwrap();
+        assert_eq!(aer.base, RegisterBase::ExtendedCapability(0x01));
+        assert_eq!(aer.values.len(), 2);
+
+        let raw = SetpciExpression::parse("3e.b=40").unwrap();
+        assert_eq!((raw.offset, raw.width), (0x3E, 1));
+
+        assert!(SetpciExpression::parse("3e").is_err());
+        assert!(SetpciExpression::parse("CAP_EXP+10").is_err());
+        assert!(SetpciExpression::parse("COMMAND.b=1ff").is_err());
+        assert!(SetpciExpression::parse("CAP_NOPE.w").is_err());
+    }
+}
@@ -0,0 +1,474 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Parsing of the SMBIOS System Slots (type 9) and Onboard Devices (type 41) records.
+
+use core::fmt;
+use std::fmt::Display;
+use std::fs::read;
+use std::path::Path;
+
+use crate::backend::{PciDevice, PciEnumerationError};
+use crate::pci::read_u16;
+use crate::pci::topology::{NodeId, PciTopology};
+
+pub const SMBIOS_TYPE_SYSTEM_SLOTS: u8 = 9;
+pub const SMBIOS_TYPE_ONBOARD_DEVICES: u8 = 41;
+pub const SMBIOS_TYPE_END: u8 = 127;
+
+// Segment, bus and device/function fields hold 0xFF when they don't apply.
+const NOT_APPLICABLE: u8 = 0xFF;
+
+// One structure from the table: a formatted area followed by its strings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmbiosStructure {
+    pub kind: u8,
+    pub handle: u16,
+    pub data: Vec<u8>, // The formatted area, header included.
+    pub strings: Vec<String>,
+}
+
+impl SmbiosStructure {
+    pub fn read_u8(&self, offset: usize) -> Option<u8> {
+        self.data.get(offset).copied()
+    }
+
+    pub fn read_u16(&self, offset: usize) -> Option<u16> {
+        read_u16(&self.data, offset)
+    }
+
+    // Strings are referenced by a 1 based index, with 0 meaning none.
+    pub fn string_at(&self, offset: usize) -> Option<String> {
+        let index = self.read_u8(offset)? as usize;
+        self.strings.get(index.checked_sub(1)?).cloned()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotUsage {
+    Other,
+    Unknown,
+    Available,
+    InUse,
+    Unavailable,
+}
+
+impl From<u8> for SlotUsage {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => SlotUsage::Other,
+            3 => SlotUsage::Available,
+            4 => SlotUsage::InUse,
+            5 => SlotUsage::Unavailable,
+            _ => SlotUsage::Unknown,
+        }
+    }
+}
+
+impl Display for SlotUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SlotUsage::Other => write!(f, "Other"),
+            SlotUsage::Unknown => write!(f, "Unknown"),
+            SlotUsage::Available => write!(f, "Empty"),
+            SlotUsage::InUse => write!(f, "Occupied"),
+            SlotUsage::Unavailable => write!(f, "Unavailable"),
+        }
+    }
+}
+
+// The PCI address a record points at. Older tables leave these fields out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SmbiosAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl SmbiosAddress {
+    fn read(structure: &SmbiosStructure, offset: usize) -> Option<SmbiosAddress> {
+        let segment = structure.read_u16(offset)?;
+        let bus = structure.read_u8(offset + 2)?;
+        let device_function = structure.read_u8(offset + 3)?;
+        if segment == 0xFFFF || bus == NOT_APPLICABLE || device_function == NOT_APPLICABLE {
+            return None;
+        }
+
+        Some(SmbiosAddress {
+            segment,
+            bus,
+            device: device_function >> 3,
+            function: device_function & 0x07,
+        })
+    }
+
+    // Slots hold every function of a card, so the function number isn't compared.
+    pub fn matches_slot(&self, device: &PciDevice) -> bool {
+        self.segment as u32 == device.domain && self.bus == device.bus && self.device == device.device
+    }
+
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        self.matches_slot(device) && self.function == device.function
+    }
+}
+
+// A type 9 record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemSlot {
+    pub designation: String,
+    pub slot_type: u8,
+    pub data_bus_width: u8,
+    pub usage: SlotUsage,
+    pub slot_id: u16,
+    pub address: Option<SmbiosAddress>,
+}
+
+impl SystemSlot {
+    fn parse(structure: &SmbiosStructure) -> Option<SystemSlot> {
+        Some(SystemSlot {
+            designation: structure.string_at(0x04).unwrap_or_default(),
+            slot_type: structure.read_u8(0x05)?,
+            data_bus_width: structure.read_u8(0x06)?,
+            usage: SlotUsage::from(structure.read_u8(0x07)?),
+            slot_id: structure.read_u16(0x09)?,
+            address: SmbiosAddress::read(structure, 0x0D),
+        })
+    }
+
+    // The family of slot, without the width.
+    pub fn type_name(&self) -> &'static str {
+        match self.slot_type {
+            0x06 | 0x0E => "PCI",
+            0x0F | 0x10 | 0x11 | 0x13 => "AGP",
+            0x12 => "PCI-X",
+            0x1F | 0x20 | 0x24 | 0x25 => "U.2",
+            0x21..=0x23 => "PCIe Mini",
+            0x26 | 0x27 => "OCP NIC 3.0",
+            0x28 => "OCP NIC",
+            0xA5..=0xC4 => "PCIe",
+            _ => "Other",
+        }
+    }
+
+    // The number of lanes or bits the slot is wired for, e.g. "x16" or "32-bit".
+    pub fn width_name(&self) -> Option<&'static str> {
+        match self.data_bus_width {
+            0x03 => Some("8-bit"),
+            0x04 => Some("16-bit"),
+            0x05 => Some("32-bit"),
+            0x06 => Some("64-bit"),
+            0x07 => Some("128-bit"),
+            0x08 => Some("x1"),
+            0x09 => Some("x2"),
+            0x0A => Some("x4"),
+            0x0B => Some("x8"),
+            0x0C => Some("x12"),
+            0x0D => Some("x16"),
+            0x0E => Some("x32"),
+            _ => None,
+        }
+    }
+}
+
+// Reads like "Slot 3 / PCIe x16 / Occupied".
+impl Display for SystemSlot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} / {}", self.designation, self.type_name())?;
+        if let Some(width) = self.width_name() {
+            write!(f, " {}", width)?;
+        }
+        write!(f, " / {}", self.usage)
+    }
+}
+
+// A type 41 record, describing a device soldered to the board.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OnboardDevice {
+    pub designation: String,
+    pub device_type: u8,
+    pub enabled: bool,
+    pub instance: u8,
+    pub address: Option<SmbiosAddress>,
+}
+
+impl OnboardDevice {
+    fn parse(structure: &SmbiosStructure) -> Option<OnboardDevice> {
+        let device_type = structure.read_u8(0x05)?;
+        Some(OnboardDevice {
+            designation: structure.string_at(0x04).unwrap_or_default(),
+            device_type: device_type & 0x7F,
+            enabled: device_type & 0x80 != 0,
+            instance: structure.read_u8(0x06)?,
+            address: SmbiosAddress::read(structure, 0x07),
+        })
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self.device_type {
+            0x03 => "Video",
+            0x04 => "SCSI Controller",
+            0x05 => "Ethernet",
+            0x06 => "Token Ring",
+            0x07 => "Sound",
+            0x08 => "PATA Controller",
+            0x09 => "SATA Controller",
+            0x0A => "SAS Controller",
+            0x0B => "Wireless LAN",
+            0x0C => "Bluetooth",
+            0x0D => "WWAN",
+            0x0E => "eMMC",
+            0x0F => "NVMe Controller",
+            0x10 => "UFS Controller",
+            0x02 => "Unknown",
+            _ => "Other",
+        }
+    }
+}
+
+// Reads like "Onboard LAN 1 / Ethernet / Enabled".
+impl Display for OnboardDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = if self.enabled { "Enabled" } else { "Disabled" };
+        write!(f, "{} / {} / {}", self.designation, self.type_name(), state)
+    }
+}
+
+// Where a device physically lives, as far as the firmware says.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SmbiosLocation {
+    Slot(SystemSlot),
+    Onboard(OnboardDevice),
+}
+
+impl Display for SmbiosLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SmbiosLocation::Slot(slot) => write!(f, "{}", slot),
+            SmbiosLocation::Onboard(onboard) => write!(f, "{}", onboard),
+        }
+    }
+}
+
+// The structure table, as found in /sys/firmware/dmi/tables/DMI on Linux.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SmbiosTables {
+    pub structures: Vec<SmbiosStructure>,
+}
+
+impl SmbiosTables {
+    pub fn parse(data: &[u8]) -> Result<SmbiosTables, PciEnumerationError> {
+        let mut structures = Vec::new();
+        let mut offset = 0;
+
+        // Structures run back to back until the end of table record or the end of the data.
+        while offset + 4 <= data.len() {
+            let kind = data[offset];
+            let length = data[offset + 1] as usize;
+            if length < 4 || offset + length > data.len() {
+                return Err(PciEnumerationError::InvalidSmbios);
+            }
+            let handle = read_u16(data, offset + 2).ok_or(PciEnumerationError::InvalidSmbios)?;
+
+            // The string set ends with two NULs, or is just two NULs if there are no strings.
+            let strings_start = offset + length;
+            let strings_end = data[strings_start..]
+                .windows(2)
+                .position(|pair| pair == [0, 0])
+                .map(|position| strings_start + position)
+                .ok_or(PciEnumerationError::InvalidSmbios)?;
+            let strings = data[strings_start..strings_end]
+                .split(|byte| *byte == 0)
+                .filter(|string| !string.is_empty())
+                .map(|string| String::from_utf8_lossy(string).trim().to_string())
+                .collect();
+
+            structures.push(SmbiosStructure {
+                kind,
+                handle,
+                data: data[offset..strings_start].to_vec(),
+                strings,
+            });
+            if kind == SMBIOS_TYPE_END {
+                break;
+            }
+            offset = strings_end + 2;
+        }
+
+        Ok(SmbiosTables { structures })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<SmbiosTables, PciEnumerationError> {
+        SmbiosTables::parse(&read(path)?)
+    }
+
+    pub fn of_type(&self, kind: u8) -> impl Iterator<Item = &SmbiosStructure> {
+        self.structures.iter().filter(move |structure| structure.kind == kind)
+    }
+
+    pub fn system_slots(&self) -> Vec<SystemSlot> {
+        self.of_type(SMBIOS_TYPE_SYSTEM_SLOTS).filter_map(SystemSlot::parse).collect()
+    }
+
+    pub fn onboard_devices(&self) -> Vec<OnboardDevice> {
+        self.of_type(SMBIOS_TYPE_ONBOARD_DEVICES).filter_map(OnboardDevice::parse).collect()
+    }
+
+    // Firmware points slot records at either the card itself or the port above it. A record
+    // for the card covers all of its functions; one for a port only covers what is below it,
+    // not the port or the other functions beside it.
+    pub fn locate(&self, topology: &PciTopology, id: NodeId) -> Option<SmbiosLocation> {
+        let device = &topology.node(id).device;
+        let onboard = self.onboard_devices().into_iter().find(|onboard| onboard.address.is_some_and(|address| address.matches(device)));
+        if let Some(onboard) = onboard {
+            return Some(SmbiosLocation::Onboard(onboard));
+        }
+
+        let names_bridge = |address: &SmbiosAddress| topology.nodes().iter().any(|node| node.is_bridge() && address.matches(&node.device));
+        let slots = self.system_slots();
+        let card = slots.iter().find(|slot| slot.address.is_some_and(|address| address.matches_slot(device) && !names_bridge(&address)));
+        if let Some(slot) = card {
+            return Some(SmbiosLocation::Slot(slot.clone()));
+        }
+
+        for ancestor in topology.ancestors(id) {
+            let port = &topology.node(ancestor).device;
+            if let Some(slot) = slots.iter().find(|slot| slot.address.is_some_and(|address| address.matches(port))) {
+                return Some(SmbiosLocation::Slot(slot.clone()));
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::PciDevice;
+    use crate::pci::smbios::{SlotUsage, SmbiosLocation, SmbiosTables, SystemSlot};
+    use crate::pci::topology::{PciTopology, TopologyDevice};
+
+    fn device(bus: u8, device: u8, class: u8) -> PciDevice {
+        PciDevice {
+            domain: 0,
+            bus,
+            device,
+            function: 0,
+            label: String::new(),
+            vendor_id: 0x8086,
+            device_id: 0x1234,
+            subsys_device_id: 0,
+            subsys_vendor_id: 0,
+            class,
+            subclass: if class == 0x06 { 0x04 } else { 0x00 },
+            programming_interface: 0,
+            revision_id: 0,
+        }
+    }
+
+    // Build a table with one PCIe x16 slot pointing at root port 00:01.0, one onboard NIC
+    // at 00:1f.6, and the end of table marker.
+    fn table() -> Vec<u8> {
+        let mut data = vec![
+            9, 0x11, 0x01, 0x00, // Type 9, length 0x11, handle 1
+            1, 0xAA, 0x0D, 0x04, 0x04, // Designation, PCIe x16, x16, in use, long
+            0x03, 0x00, 0x0C, 0x01, // Slot ID 3, characteristics
+            0x00, 0x00, 0x00, 0x08, // Segment 0, bus 0, device 1 function 0
+        ];
+        data.extend_from_slice(b"Slot 3\0\0");
+        data.extend_from_slice(&[
+            41, 0x0B, 0x02, 0x00, // Type 41, length 0x0B, handle 2
+            1, 0x85, 1, // Designation, enabled Ethernet, instance 1
+            0x00, 0x00, 0x00, 0xFE, // Segment 0, bus 0, device 0x1f function 6
+        ]);
+        data.extend_from_slice(b"Onboard LAN\0\0");
+        data.extend_from_slice(&[127, 4, 0x03, 0x00, 0, 0]);
+        data
+    }
+
+    #[test]
+    fn test_parse_slots() {
+        let tables = SmbiosTables::parse(&table()).unwrap();
+        assert_eq!(tables.structures.len(), 3);
+
+        let slots = tables.system_slots();
+        assert_eq!(slots.len(), 1);
+        assert_eq!(slots[0].slot_id, 3);
+        assert_eq!(slots[0].usage, SlotUsage::InUse);
+        assert_eq!(slots[0].to_string(), "Slot 3 / PCIe x16 / Occupied");
+
+        let onboard = tables.onboard_devices();
+        assert_eq!(onboard[0].address.unwrap().device, 0x1f);
+        assert_eq!(onboard[0].to_string(), "Onboard LAN / Ethernet / Enabled");
+
+        assert!(SmbiosTables::parse(&table()[..20]).is_err());
+    }
+
+    #[test]
+    fn test_slot_type_names() {
+        let slot = |slot_type| SystemSlot {
+            designation: String::new(),
+            slot_type,
+            data_bus_width: 0,
+            usage: SlotUsage::Available,
+            slot_id: 0,
+            address: None,
+        };
+        let names: Vec<&str> = [0x06, 0x0F, 0x10, 0x11, 0x12, 0x13, 0x1F, 0x26, 0xAA, 0x01].into_iter().map(|slot_type| slot(slot_type).type_name()).collect();
+        assert_eq!(names, vec!["PCI", "AGP", "AGP", "AGP", "PCI-X", "AGP", "U.2", "OCP NIC 3.0", "PCIe", "Other"]);
+    }
+
+    #[test]
+    fn test_locate_through_bridge() {
+        let tables = SmbiosTables::parse(&table()).unwrap();
+
+        let mut port = TopologyDevice::new(device(0, 1, 0x06));
+        port.root_complex = Some("pci0000:00".to_string());
+        let mut gpu = TopologyDevice::new(device(1, 0, 0x03));
+        gpu.parent_address = Some("0000:00:01.0".to_string());
+        let mut nic = TopologyDevice::new(device(0, 0x1f, 0x02));
+        nic.device.function = 6;
+        let mut audio = TopologyDevice::new(device(1, 0, 0x04));
+        audio.device.function = 1;
+        audio.parent_address = Some("0000:00:01.0".to_string());
+        let mut second_port = TopologyDevice::new(device(0, 1, 0x06));
+        second_port.device.function = 1;
+        let topology = PciTopology::build(vec![port, gpu, nic, audio, second_port]);
+
+        let gpu = topology.find("0000:01:00.0").unwrap();
+        assert!(matches!(tables.locate(&topology, gpu), Some(SmbiosLocation::Slot(slot)) if slot.slot_id == 3));
+        let audio = topology.find("0000:01:00.1").unwrap();
+        assert!(matches!(tables.locate(&topology, audio), Some(SmbiosLocation::Slot(slot)) if slot.slot_id == 3));
+
+        // The record names the root port above the slot, which is not in the slot itself.
+        assert_eq!(tables.locate(&topology, topology.find("0000:00:01.0").unwrap()), None);
+        assert_eq!(tables.locate(&topology, topology.find("0000:00:01.1").unwrap()), None);
+
+        let nic = topology.find("0000:00:1f.6").unwrap();
+        assert!(matches!(tables.locate(&topology, nic), Some(SmbiosLocation::Onboard(_))));
+    }
+}
@@ -0,0 +1,198 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Saving the writable parts of config space so they can be put back after a reset.
+
+use crate::pci::config::{ConfigSpace, CAP_ID_MSI, CAP_ID_MSIX, CONFIG_COMMAND, ECAP_ID_ACS, ECAP_ID_AER, ECAP_ID_L1SS, ECAP_ID_LTR, HEADER_TYPE_BRIDGE, HEADER_TYPE_NORMAL};
+use crate::pci::express::{PciePortType, EXP_DEVICE_CONTROL, EXP_DEVICE_CONTROL2, EXP_LINK_CONTROL, EXP_LINK_CONTROL2, EXP_ROOT_CONTROL, EXP_SLOT_CONTROL, EXP_SLOT_CONTROL2};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SavedRegister {
+    pub offset: usize,
+    pub width: usize, // 1, 2 or 4 bytes.
+    pub value: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterMismatch {
+    pub register: SavedRegister,
+    pub actual: Option<u32>, // None if the register could not be read back at all.
+}
+
+// Registers in the order they should be restored: capability state first, then the header,
+// with the command register last so decoding is only switched on once the BARs are back.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SavedState {
+    pub registers: Vec<SavedRegister>,
+}
+
+impl SavedState {
+    pub fn capture(config: &ConfigSpace) -> SavedState {
+        let mut state = SavedState::default();
+
+        if let Some(pcie) = config.pcie() {
+            let offset = pcie.offset();
+            state.save(config, offset + EXP_DEVICE_CONTROL, 2);
+            if pcie.has_link() {
+                state.save(config, offset + EXP_LINK_CONTROL, 2);
+            }
+            if pcie.has_slot() {
+                state.save(config, offset + EXP_SLOT_CONTROL, 2);
+            }
+            if pcie.port_type() == Some(PciePortType::RootPort) {
+                state.save(config, offset + EXP_ROOT_CONTROL, 2);
+            }
+            if pcie.version().unwrap_or(0) >= 2 {
+                state.save(config, offset + EXP_DEVICE_CONTROL2, 2);
+                state.save(config, offset + EXP_LINK_CONTROL2, 2);
+                if pcie.has_slot() {
+                    state.save(config, offset + EXP_SLOT_CONTROL2, 2);
+                }
+            }
+        }
+
+        // The same AER registers as the kernel's pci_save_aer_state. Capabilities and Control
+        // is left out, as its First Error Pointer changes whenever an error is logged.
+        let root_port = config.pcie().and_then(|pcie| pcie.port_type()) == Some(PciePortType::RootPort);
+        for capability in config.extended_capabilities() {
+            let offset = capability.offset;
+            match capability.id {
+                ECAP_ID_AER => {
+                    state.save(config, offset + 0x08, 4); // Uncorrectable error mask.
+                    state.save(config, offset + 0x0C, 4); // Uncorrectable error severity.
+                    state.save(config, offset + 0x14, 4); // Correctable error mask.
+                    if root_port {
+                        state.save(config, offset + 0x2C, 4); // Root error command.
+                    }
+                }
+                ECAP_ID_ACS => state.save(config, offset + 0x06, 2),
+                ECAP_ID_LTR => state.save(config, offset + 0x04, 4),
+                ECAP_ID_L1SS => {
+                    state.save(config, offset + 0x08, 4);
+                    state.save(config, offset + 0x0C, 4);
+                }
+                _ => (),
+            }
+        }
+
+        // MSI address and data go back before the enable bit in the control register.
+        if let Some(offset) = config.find_capability(CAP_ID_MSI) {
+            let control = config.read_u16(offset + 2).unwrap_or(0);
+            state.save(config, offset + 0x04, 4);
+            if control & 0x0080 != 0 {
+                state.save(config, offset + 0x08, 4);
+                state.save(config, offset + 0x0C, 2);
+            } else {
+                state.save(config, offset + 0x08, 2);
+            }
+            state.save(config, offset + 2, 2);
+        }
+        if let Some(offset) = config.find_capability(CAP_ID_MSIX) {
+            state.save(config, offset + 2, 2);
+        }
+
+        match config.header_type() {
+            Some(HEADER_TYPE_NORMAL) => {
+                for bar in (0x10..=0x24).step_by(4) {
+                    state.save(config, bar, 4);
+                }
+                state.save(config, 0x30, 4); // Expansion ROM base.
+            }
+            Some(HEADER_TYPE_BRIDGE) => {
+                state.save(config, 0x10, 4);
+                state.save(config, 0x14, 4);
+                state.save(config, 0x18, 4); // Bus numbers and secondary latency.
+                state.save(config, 0x1C, 2); // I/O base and limit, skipping the secondary status.
+                for register in [0x20, 0x24, 0x28, 0x2C, 0x30, 0x38] {
+                    state.save(config, register, 4);
+                }
+                state.save(config, 0x3E, 2); // Bridge control.
+            }
+            _ => (),
+        }
+        state.save(config, 0x0C, 1); // Cache line size.
+        state.save(config, 0x0D, 1); // Latency timer.
+        state.save(config, 0x3C, 1); // Interrupt line.
+        state.save(config, CONFIG_COMMAND, 2);
+
+        state
+    }
+
+    fn save(&mut self, config: &ConfigSpace, offset: usize, width: usize) {
+        if let Some(value) = read_register(config, offset, width) {
+            self.registers.push(SavedRegister { offset, width, value });
+        }
+    }
+
+    // The saved registers that no longer hold their saved value.
+    pub fn differences(&self, config: &ConfigSpace) -> Vec<RegisterMismatch> {
+        self.registers.iter()
+            .filter_map(|register| {
+                let actual = read_register(config, register.offset, register.width);
+                if actual == Some(register.value) {
+                    None
+                } else {
+                    Some(RegisterMismatch { register: *register, actual })
+                }
+            })
+            .collect()
+    }
+}
+
+pub(crate) fn read_register(config: &ConfigSpace, offset: usize, width: usize) -> Option<u32> {
+    match width {
+        1 => config.read_u8(offset).map(u32::from),
+        2 => config.read_u16(offset).map(u32::from),
+        4 => config.read_u32(offset),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pci::config::{ConfigSpace, ECAP_ID_AER};
+    use crate::pci::state::SavedState;
+
+    #[test]
+    fn test_root_port_aer_state() {
+        let mut data = vec![0u8; 4096];
+        data[0x06] = 0x10;
+        data[0x34] = 0x40;
+        data[0x40] = 0x10;
+        data[0x42] = 0x42; // Root port.
+        data[0x100..0x104].copy_from_slice(&(ECAP_ID_AER as u32 | 1 << 16).to_le_bytes());
+        data[0x118] = 0x0D; // First Error Pointer.
+        data[0x12C] = 0x07; // Every root error reporting enable.
+
+        let state = SavedState::capture(&ConfigSpace::new(data));
+        let aer: Vec<(usize, u32)> = state.registers.iter()
+            .filter(|register| register.offset >= 0x100)
+            .map(|register| (register.offset, register.value))
+            .collect();
+        assert_eq!(aer, vec![(0x108, 0), (0x10C, 0), (0x114, 0), (0x12C, 0x07)]);
+    }
+}
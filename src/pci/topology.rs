@@ -0,0 +1,298 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! The tree of bridges, switches and devices behind each root complex.
+
+use std::collections::HashMap;
+
+use crate::backend::PciDevice;
+use crate::pci::config::ConfigSpace;
+use crate::pci::express::PciePortType;
+
+// Nodes are referred to by their index in the topology.
+pub type NodeId = usize;
+
+// Everything known about a device before it is linked into the tree.
+#[derive(Debug, Clone)]
+pub struct TopologyDevice {
+    pub device: PciDevice,
+    pub config: Option<ConfigSpace>,
+    pub parent_address: Option<String>, // The parent bridge's address, when the OS knows it.
+    pub root_complex: Option<String>, // The host bridge name, e.g. pci0000:00 on Linux.
+}
+
+impl TopologyDevice {
+    pub fn new(device: PciDevice) -> Self {
+        TopologyDevice {
+            device,
+            config: None,
+            parent_address: None,
+            root_complex: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TopologyNode {
+    pub device: PciDevice,
+    pub config: Option<ConfigSpace>,
+    pub parent: Option<NodeId>,
+    pub children: Vec<NodeId>,
+    root_complex: Option<String>,
+}
+
+impl TopologyNode {
+    pub fn address(&self) -> String {
+        self.device.address()
+    }
+
+    pub fn is_bridge(&self) -> bool {
+        match &self.config {
+            Some(config) => config.is_bridge(),
+            None => self.device.class == 0x06 && self.device.subclass == 0x04,
+        }
+    }
+
+    pub fn port_type(&self) -> Option<PciePortType> {
+        self.config.as_ref()?.pcie()?.port_type()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PciTopology {
+    nodes: Vec<TopologyNode>,
+    by_address: HashMap<String, NodeId>,
+}
+
+impl PciTopology {
+    // Link devices to their parents. A parent address reported by the OS wins; otherwise the
+    // parent is the bridge in the same domain whose secondary bus is the device's bus, falling
+    // back to the narrowest secondary..subordinate range that contains it.
+    pub fn build(devices: Vec<TopologyDevice>) -> PciTopology {
+        let mut topology = PciTopology::default();
+        let mut parent_addresses = Vec::new();
+
+        let mut devices = devices;
+        devices.sort_by_key(|entry| (entry.device.domain, entry.device.bus, entry.device.device, entry.device.function));
+
+        for entry in devices {
+            topology.by_address.insert(entry.device.address(), topology.nodes.len());
+            parent_addresses.push(entry.parent_address);
+            topology.nodes.push(TopologyNode {
+                device: entry.device,
+                config: entry.config,
+                parent: None,
+                children: Vec::new(),
+                root_complex: entry.root_complex,
+            });
+        }
+
+        for (id, parent_address) in parent_addresses.into_iter().enumerate() {
+            let parent = match parent_address.and_then(|address| topology.by_address.get(&address).copied()) {
+                Some(parent) => Some(parent),
+                None => topology.bridge_for_bus(id),
+            };
+
+            if let Some(parent) = parent.filter(|parent| *parent != id) {
+                topology.nodes[id].parent = Some(parent);
+                topology.nodes[parent].children.push(id);
+            }
+        }
+
+        topology
+    }
+
+    fn bridge_for_bus(&self, id: NodeId) -> Option<NodeId> {
+        let device = &self.nodes[id].device;
+        let mut best: Option<(NodeId, u8)> = None;
+
+        for (candidate, node) in self.nodes.iter().enumerate() {
+            if candidate == id || node.device.domain != device.domain {
+                continue;
+            }
+            let (_, secondary, subordinate) = match node.config.as_ref().and_then(ConfigSpace::bridge_buses) {
+                Some(buses) => buses,
+                None => continue,
+            };
+            if secondary == 0 || device.bus < secondary || device.bus > subordinate {
+                continue;
+            }
+            if secondary == device.bus {
+                return Some(candidate);
+            }
+            let width = subordinate - secondary;
+            if best.is_none_or(|(_, best_width)| width < best_width) {
+                best = Some((candidate, width));
+            }
+        }
+
+        best.map(|(candidate, _)| candidate)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn nodes(&self) -> &[TopologyNode] {
+        &self.nodes
+    }
+
+    pub fn node(&self, id: NodeId) -> &TopologyNode {
+        &self.nodes[id]
+    }
+
+    pub fn find(&self, address: &str) -> Option<NodeId> {
+        self.by_address.get(address).copied()
+    }
+
+    pub fn find_device(&self, device: &PciDevice) -> Option<NodeId> {
+        self.find(&device.address())
+    }
+
+    pub fn parent(&self, id: NodeId) -> Option<NodeId> {
+        self.nodes[id].parent
+    }
+
+    pub fn children(&self, id: NodeId) -> &[NodeId] {
+        &self.nodes[id].children
+    }
+
+    // Devices with no parent: root ports and root complex integrated devices.
+    pub fn roots(&self) -> Vec<NodeId> {
+        (0..self.nodes.len()).filter(|id| self.nodes[*id].parent.is_none()).collect()
+    }
+
+    // The chain of bridges above a device, nearest first.
+    pub fn ancestors(&self, id: NodeId) -> Vec<NodeId> {
+        let mut ancestors = Vec::new();
+        let mut current = self.nodes[id].parent;
+        while let Some(parent) = current {
+            // Guard against a malformed tree linking back on itself.
+            if ancestors.contains(&parent) {
+                break;
+            }
+            ancestors.push(parent);
+            current = self.nodes[parent].parent;
+        }
+        ancestors
+    }
+
+    // Every device below a node, depth first.
+    pub fn descendants(&self, id: NodeId) -> Vec<NodeId> {
+        let mut descendants = Vec::new();
+        let mut stack: Vec<NodeId> = self.nodes[id].children.iter().rev().copied().collect();
+        while let Some(child) = stack.pop() {
+            if descendants.contains(&child) {
+                continue;
+            }
+            descendants.push(child);
+            stack.extend(self.nodes[child].children.iter().rev());
+        }
+        descendants
+    }
+
+    // The top of a device's chain, normally the root port it hangs off.
+    pub fn root_port(&self, id: NodeId) -> Option<NodeId> {
+        self.ancestors(id).last().copied()
+    }
+
+    // The nearest switch upstream port above a device, if it sits behind a switch.
+    pub fn upstream_switch(&self, id: NodeId) -> Option<NodeId> {
+        self.ancestors(id).into_iter().find(|ancestor| self.nodes[*ancestor].port_type() == Some(PciePortType::UpstreamPort))
+    }
+
+    // The host bridge a device sits under, named like Linux names it, e.g. pci0000:00
+    pub fn root_complex(&self, id: NodeId) -> String {
+        let top = self.root_port(id).unwrap_or(id);
+        let node = &self.nodes[top];
+        match &node.root_complex {
+            Some(root_complex) => root_complex.clone(),
+            None => format!("pci{:04x}:{:02x}", node.device.domain, node.device.bus),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::PciDevice;
+    use crate::pci::config::ConfigSpace;
+    use crate::pci::topology::{PciTopology, TopologyDevice};
+
+    fn device(bus: u8, device: u8, class: u8, subclass: u8) -> PciDevice {
+        PciDevice {
+            domain: 0,
+            bus,
+            device,
+            function: 0,
+            label: String::new(),
+            vendor_id: 0x8086,
+            device_id: 0x1234,
+            subsys_device_id: 0,
+            subsys_vendor_id: 0,
+            class,
+            subclass,
+            programming_interface: 0,
+            revision_id: 0,
+        }
+    }
+
+    fn bridge(bus: u8, device_number: u8, secondary: u8, subordinate: u8) -> TopologyDevice {
+        let mut config = vec![0u8; 64];
+        config[0x0E] = 0x01;
+        config[0x18] = bus;
+        config[0x19] = secondary;
+        config[0x1A] = subordinate;
+
+        let mut entry = TopologyDevice::new(device(bus, device_number, 0x06, 0x04));
+        entry.config = Some(ConfigSpace::new(config));
+        entry
+    }
+
+    #[test]
+    fn test_topology_from_bus_numbers() {
+        let topology = PciTopology::build(vec![
+            TopologyDevice::new(device(3, 0, 0x03, 0x00)), // GPU behind the switch.
+            bridge(0, 1, 1, 3), // Root port.
+            bridge(1, 0, 2, 3), // Switch upstream port.
+            bridge(2, 0, 3, 3), // Switch downstream port.
+            TopologyDevice::new(device(0, 0, 0x06, 0x00)), // Host bridge.
+        ]);
+
+        let gpu = topology.find("0000:03:00.0").unwrap();
+        let root_port = topology.find("0000:00:01.0").unwrap();
+        assert_eq!(topology.parent(gpu), topology.find("0000:02:00.0"));
+        assert_eq!(topology.ancestors(gpu).len(), 3);
+        assert_eq!(topology.root_port(gpu), Some(root_port));
+        assert_eq!(topology.descendants(root_port).len(), 3);
+        assert_eq!(topology.root_complex(gpu), "pci0000:00");
+        assert_eq!(topology.roots().len(), 2);
+    }
+}
@@ -0,0 +1,243 @@
+// Copyright (c) 2023 NamedNeon. All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without modification,
+// are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice,
+// this list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+// this list of conditions and the following disclaimer in the documentation
+// and/or other materials provided with the distribution.
+//
+// 3. Neither the name of the copyright holder nor the names of its contributors
+// may be used to endorse or promote products derived from this software without
+// specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE
+// LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+// USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Parsing of PCI Vital Product Data (VPD).
+
+use crate::backend::PciEnumerationError;
+use crate::pci::read_u16;
+
+// Large resource tags have bit 7 set and a 16 bit length, small ones pack the length in the tag byte.
+const LARGE_RESOURCE: u8 = 0x80;
+const TAG_IDENTIFIER: u8 = 0x02;
+const TAG_VPD_R: u8 = 0x10;
+const TAG_VPD_W: u8 = 0x11;
+const TAG_END: u8 = 0x0F;
+
+// A single keyword from the VPD-R or VPD-W list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VpdField {
+    pub keyword: String,
+    pub data: Vec<u8>,
+}
+
+impl VpdField {
+    // Most keywords hold ASCII text padded with spaces or NULs.
+    pub fn as_string(&self) -> String {
+        String::from_utf8_lossy(&self.data)
+            .trim_end_matches(|c: char| c == '\0' || c.is_whitespace())
+            .to_string()
+    }
+
+    // V0 through VZ are vendor specific.
+    pub fn is_vendor_specific(&self) -> bool {
+        let keyword = self.keyword.as_bytes();
+        keyword.len() == 2 && keyword[0] == b'V' && keyword[1].is_ascii_alphanumeric()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vpd {
+    pub identifier: String,
+    pub read_only: Vec<VpdField>,
+    pub read_write: Vec<VpdField>,
+    pub checksum_valid: Option<bool>, // None if there was no RV keyword to check against.
+    pub end_tag: bool, // Whether the data was terminated by an end tag rather than running out.
+}
+
+impl Vpd {
+    pub fn parse(data: &[u8]) -> Result<Vpd, PciEnumerationError> {
+        let mut vpd = Vpd {
+            identifier: String::new(),
+            read_only: Vec::new(),
+            read_write: Vec::new(),
+            checksum_valid: None,
+            end_tag: false,
+        };
+
+        let mut offset = 0;
+        while offset < data.len() {
+            let tag_byte = data[offset];
+
+            if tag_byte & LARGE_RESOURCE == 0 {
+                let tag = (tag_byte >> 3) & 0x0F;
+                if tag == TAG_END {
+                    vpd.end_tag = true;
+                    break;
+                }
+                // Skip any other small resource.
+                offset += 1 + (tag_byte & 0x07) as usize;
+                continue;
+            }
+
+            let length = read_u16(data, offset + 1).ok_or(PciEnumerationError::InvalidVpd)? as usize;
+            let body_start = offset + 3;
+            let body = data.get(body_start..body_start + length).ok_or(PciEnumerationError::InvalidVpd)?;
+
+            match tag_byte & !LARGE_RESOURCE {
+                TAG_IDENTIFIER => vpd.identifier = String::from_utf8_lossy(body).trim_end().to_string(),
+                TAG_VPD_R => {
+                    vpd.read_only = parse_fields(body)?;
+                    vpd.checksum_valid = checksum(data, body_start, &vpd.read_only);
+                }
+                TAG_VPD_W => vpd.read_write = parse_fields(body)?,
+                _ => (), // Unknown large resources are skipped.
+            }
+
+            offset = body_start + length;
+        }
+
+        // A device without VPD usually reads back as all 0xFF or zeroes.
+        if vpd.identifier.is_empty() && vpd.read_only.is_empty() && vpd.read_write.is_empty() {
+            return Err(PciEnumerationError::InvalidVpd);
+        }
+
+        Ok(vpd)
+    }
+
+    // Look a keyword up in the read only section first, then the read/write section.
+    pub fn get(&self, keyword: &str) -> Option<&VpdField> {
+        self.read_only.iter()
+            .chain(self.read_write.iter())
+            .find(|field| field.keyword == keyword)
+    }
+
+    pub fn part_number(&self) -> Option<String> {
+        self.get("PN").map(VpdField::as_string)
+    }
+
+    pub fn engineering_change(&self) -> Option<String> {
+        self.get("EC").map(VpdField::as_string)
+    }
+
+    pub fn serial_number(&self) -> Option<String> {
+        self.get("SN").map(VpdField::as_string)
+    }
+
+    pub fn manufacturer_id(&self) -> Option<String> {
+        self.get("MN").map(VpdField::as_string)
+    }
+
+    pub fn vendor_specific(&self) -> impl Iterator<Item = &VpdField> {
+        self.read_only.iter()
+            .chain(self.read_write.iter())
+            .filter(|field| field.is_vendor_specific())
+    }
+}
+
+fn parse_fields(body: &[u8]) -> Result<Vec<VpdField>, PciEnumerationError> {
+    let mut fields = Vec::new();
+    let mut offset = 0;
+
+    while offset + 3 <= body.len() {
+        let keyword = String::from_utf8_lossy(&body[offset..offset + 2]).to_string();
+        let length = body[offset + 2] as usize;
+        let data = body.get(offset + 3..offset + 3 + length).ok_or(PciEnumerationError::InvalidVpd)?;
+
+        fields.push(VpdField {
+            keyword,
+            data: data.to_vec(),
+        });
+        offset += 3 + length;
+    }
+
+    Ok(fields)
+}
+
+// The first byte of RV makes the sum of every byte from the start of the VPD up to and
+// including itself zero.
+fn checksum(data: &[u8], body_start: usize, fields: &[VpdField]) -> Option<bool> {
+    let mut offset = body_start;
+    for field in fields {
+        if field.keyword == "RV" {
+            // An empty RV has no checksum byte to balance the sum.
+            if field.data.is_empty() {
+                return Some(false);
+            }
+            let checksum_offset = offset + 3;
+            let sum = data.get(..=checksum_offset)?.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+            return Some(sum == 0);
+        }
+        offset += 3 + field.data.len();
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pci::vpd::Vpd;
+
+    fn build_vpd() -> Vec<u8> {
+        let mut data = vec![0x82, 0x08, 0x00];
+        data.extend(b"Test NIC");
+
+        let mut fields: Vec<u8> = Vec::new();
+        fields.extend(b"PN\x06X520DA");
+        fields.extend(b"SN\x0aAB12345678");
+        fields.extend(b"V1\x03abc");
+        fields.extend(b"RV\x01\x00");
+
+        data.push(0x90);
+        data.extend((fields.len() as u16).to_le_bytes());
+        data.extend(fields);
+
+        // Fix up the checksum now that everything before it is in place.
+        let checksum_offset = data.len() - 1;
+        let sum = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        data[checksum_offset] = 0u8.wrapping_sub(sum);
+
+        data.push(0x78);
+        data
+    }
+
+    #[test]
+    fn test_vpd_parsing() {
+        let vpd = Vpd::parse(&build_vpd()).unwrap();
+        assert_eq!(vpd.identifier, "Test NIC");
+        assert_eq!(vpd.part_number().as_deref(), Some("X520DA"));
+        assert_eq!(vpd.serial_number().as_deref(), Some("AB12345678"));
+        assert_eq!(vpd.vendor_specific().count(), 1);
+        assert_eq!(vpd.checksum_valid, Some(true));
+        assert!(vpd.end_tag);
+    }
+
+    #[test]
+    fn test_vpd_bad_checksum() {
+        let mut data = build_vpd();
+        data[5] ^= 0x01;
+        assert_eq!(Vpd::parse(&data).unwrap().checksum_valid, Some(false));
+    }
+
+    #[test]
+    fn test_vpd_empty_rv() {
+        // Cut off right after a zero length RV, with no end tag.
+        let mut data = vec![0x82, 0x01, 0x00, b'X', 0x90, 0x03, 0x00];
+        data.extend(b"RV\x00");
+        let vpd = Vpd::parse(&data).unwrap();
+        assert_eq!(vpd.checksum_valid, Some(false));
+        assert!(!vpd.end_tag);
+    }
+}